@@ -116,6 +116,8 @@ impl<const LEN: usize> Message<LEN> {
 }
 
 impl RxMessage {
+    /// The GA-FC LED states of a status reply. This is the only reply
+    /// decoded so far; the amp's tuner state in particular isn't known.
     pub fn led_status(&self) -> Option<u8> {
         if self.buf[1] != 0x00 || self.buf[2] != 0x00 {
             None
@@ -135,13 +137,60 @@ impl<const LEN: usize> IntoIterator for Message<LEN> {
     }
 }
 
+/// Bits of the LED status byte, see [`RxMessage::led_status`].
+///
+/// The amp sends the state of the GA-FC's LEDs, bit N for LED N. The names
+/// follow the GA-FC panel; they are inferred from which LED lights up and
+/// haven't been checked in every amp mode.
+pub mod led_status {
+    pub const CH1: u8 = 1 << 0;
+    pub const CH2: u8 = 1 << 1;
+    pub const CH3: u8 = 1 << 2;
+    pub const CH4: u8 = 1 << 3;
+    pub const CHANNELS: u8 = CH1 | CH2 | CH3 | CH4;
+    /// Lit when bank B is selected
+    pub const BANK: u8 = 1 << 4;
+    /// Lit in effect mode, where the first five LEDs show the effects
+    pub const EFFECT_MODE: u8 = 1 << 5;
+
+    // Effect mode
+    pub const BOOST: u8 = 1 << 0;
+    pub const MOD: u8 = 1 << 1;
+    pub const FX: u8 = 1 << 2;
+    pub const DELAY: u8 = 1 << 3;
+    pub const REVERB: u8 = 1 << 4;
+}
+
+/// Bits of the footswitch byte in [`status`] and [`footswitch_change`], bit N
+/// for GA-FC switch N + 1.
+pub mod footswitch {
+    /// Switches 1-4 select channels 1-4 of the bank, outside effect mode
+    pub const CHANNELS: u8 = 0x0f;
+    /// Switch 5 toggles the bank, outside effect mode
+    pub const BANK: u8 = 1 << 4;
+    /// Switch 6 toggles effect mode
+    pub const EFFECT_MODE: u8 = 1 << 5;
+    /// In effect mode switches 1-5 toggle the effects, in the order of the
+    /// [`led_status`](super::led_status) effect bits
+    pub const EFFECTS: u8 = 0x1f;
+    /// The GA-FC's six switches. There are no others, and bit 7 wouldn't
+    /// even be a valid data byte.
+    pub const ALL: u8 = 0x3f;
+}
+
+/// Status request, with the GA-FC switches held down. Bits outside
+/// [`footswitch::ALL`] are dropped.
 pub fn status(footswitch: u8) -> Message<9> {
+    let footswitch = footswitch & footswitch::ALL;
     let mut buf = [MSG_BEGIN, 0, 0, 0, 0, footswitch, 0, 0, MSG_END];
     _ = set_checksum(&mut buf[..]);
     Message { buf }
 }
 
+/// New state of the GA-FC switches. Bits outside [`footswitch::ALL`] are
+/// dropped.
 pub fn footswitch_change(footswitch: u8) -> Message<7> {
+    let footswitch = footswitch & footswitch::ALL;
     let mut buf = [MSG_BEGIN, 0, 0x02, footswitch, 0, 0, MSG_END];
     _ = set_checksum(&mut buf[..]);
     Message { buf }
@@ -176,7 +225,7 @@ fn checksum(msg: &[u8]) -> Result<u8, MessageTooShort> {
 
 #[cfg(test)]
 mod test {
-    use super::validate_checksum;
+    use super::{footswitch_change, status, validate_checksum};
 
     #[test]
     fn test_chksum() {
@@ -186,4 +235,15 @@ mod test {
         let msg = [0xf0, 0, 0, 2, 0x7e, 0xf7];
         assert!(validate_checksum(&msg));
     }
+
+    #[test]
+    fn test_footswitch_bits() {
+        // Only GA-FC switches, so every data byte stays below 0x80
+        let msg = footswitch_change(0xc1).as_bytes();
+        assert_eq!(msg[3], 0x01);
+        assert!(validate_checksum(&msg));
+        let msg = status(0xff).as_bytes();
+        assert_eq!(msg[5], 0x3f);
+        assert!(msg[1..msg.len() - 1].iter().all(|&b| b < 0x80));
+    }
 }
@@ -1,6 +1,8 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 16K
+    /* Saved settings, see src/flash_config.rs */
+    CONFIG : ORIGIN = 0x101FC000, LENGTH = 16K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -12,4 +14,4 @@ SECTIONS {
     {
         KEEP(*(.boot2));
     } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+} INSERT BEFORE .text;
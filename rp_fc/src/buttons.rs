@@ -1,90 +1,59 @@
 extern crate alloc;
 
+#[cfg(not(feature = "soft-debounce"))]
 use alloc::boxed::Box;
 use core::cell::{Cell, RefCell};
-use critical_section::Mutex;
+use critical_section::{CriticalSection, Mutex};
 use heapless::Deque;
-use pio::Program;
-use pio_proc::pio_asm;
+pub use rp_fc_core::button_event::ButtonEvent;
+#[cfg(not(feature = "soft-debounce"))]
+use rp_fc_core::debounce;
+#[cfg(feature = "soft-debounce")]
+use rp_fc_core::soft_debounce::SoftDebouncer;
+#[cfg(feature = "soft-debounce")]
+use rp2040_hal::{
+    gpio::{FunctionSioInput, Interrupt},
+    timer::{Alarm, Alarm2},
+};
+#[cfg(not(feature = "soft-debounce"))]
 use rp2040_hal::{
     fugit::HertzU32,
+    pio::{InstallError, PIOBuilder, PinDir, PioIRQ, UninitStateMachine, PIO},
+};
+#[cfg(any(not(feature = "soft-debounce"), feature = "encoder"))]
+use rp2040_hal::pio::{Rx, ValidStateMachine};
+use rp2040_hal::{
     gpio::{DynPinId, Pin, PullUp},
-    pio::{
-        InstallError, PIOBuilder, PinDir, PioIRQ, Rx, UninitStateMachine, ValidStateMachine, PIO,
-    },
+    timer,
 };
 
-struct ButtonDebouncePioProgram {
-    program: Program<32>,
-    debounce_cycle_instructions: u8,
-    debounce_cycles: u8,
-}
-
+#[cfg(not(feature = "soft-debounce"))]
 static BUTTONS_PIO_SM_RX: Mutex<RefCell<Option<Box<dyn PioFifoRead + Send>>>> =
     Mutex::new(RefCell::new(None));
-static BUTTON_CHANGE_QUEUE: Mutex<RefCell<Deque<u8, 5>>> = Mutex::new(RefCell::new(Deque::new()));
+static BUTTON_CHANGE_QUEUE: Mutex<RefCell<Deque<ButtonEvent, 16>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+static BUTTON_QUEUE_OVERFLOWS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
 static CURRENT_BUTTONS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+/// Timer for the event timestamps
+static BUTTONS_TIMER: Mutex<Cell<Option<timer::Timer>>> = Mutex::new(Cell::new(None));
 
-trait PioFifoRead {
+/// Button state bits of the footswitches, driven by the PIO or software debouncer
+pub const FOOTSWITCH_MASK: u8 = 0x3F;
+
+/// Word reads from a PIO RX FIFO, also used by the encoder
+#[cfg(any(not(feature = "soft-debounce"), feature = "encoder"))]
+pub(crate) trait PioFifoRead {
     fn read(&mut self) -> Option<u32>;
 }
 
+#[cfg(any(not(feature = "soft-debounce"), feature = "encoder"))]
 impl<SM: ValidStateMachine> PioFifoRead for Rx<SM> {
     fn read(&mut self) -> Option<u32> {
         Rx::read(self)
     }
 }
 
-fn program() -> ButtonDebouncePioProgram {
-    let p = pio_asm!(
-        "
-.define public debounce_cycle_instructions 68
-.define public debounce_cycles 32
-
-start:
-    // Read the input pins
-    in pins, 6
-
-    // Check if the value changed from previous iteration
-    mov y isr
-    jmp x!=y changed
-
-    // If we're in debounce period, jump to the debounce check
-    jmp !osre debounce
-
-    // No change detected, clear isr and start from beginning
-    jmp reset
-
-changed:
-    mov x y       // Store new button states to x
-    mov osr null  // Clear osr counter
-    jmp reset
-
-debounce:
-    out null, 1   [31]     // Shift one bit from the debounce counter
-    jmp !osre reset [31]   // Counter not empty, so still debouncing
-
-emit:
-    // Debouncing done
-    push noblock
-
-reset:
-    // Clear isr
-    in null, 32
-",
-    );
-
-    ButtonDebouncePioProgram {
-        program: p.program,
-        debounce_cycle_instructions: p
-            .public_defines
-            .debounce_cycle_instructions
-            .try_into()
-            .unwrap(),
-        debounce_cycles: p.public_defines.debounce_cycles.try_into().unwrap(),
-    }
-}
-
+#[cfg(not(feature = "soft-debounce"))]
 fn validate_pins(pin_ids: impl IntoIterator<Item = u8>) -> (u8, u8) {
     let mut base_pin = None;
     let mut prev_pin = None;
@@ -107,6 +76,7 @@ fn validate_pins(pin_ids: impl IntoIterator<Item = u8>) -> (u8, u8) {
     (base_pin, pin_count)
 }
 
+#[cfg(not(feature = "soft-debounce"))]
 pub fn init_buttons<
     P: rp2040_hal::pio::PIOExt + 'static,
     SM: rp2040_hal::pio::StateMachineIndex + 'static,
@@ -116,20 +86,40 @@ pub fn init_buttons<
     pio: &mut PIO<P>,
     sys_freq: HertzU32,
     pins: impl IntoIterator<Item = Pin<DynPinId, P::PinFunction, PullUp>>,
+    debounce_time_ms: u8,
+    timer: timer::Timer,
 ) -> Result<(), InstallError> {
+    critical_section::with(|cs| BUTTONS_TIMER.borrow(cs).set(Some(timer)));
+
+    let rx = install_debouncer::<_, _, IRQ>(sm, pio, sys_freq, pins, debounce_time_ms.into())?;
+
+    critical_section::with(|cs| BUTTONS_PIO_SM_RX.borrow(cs).replace(Some(Box::new(rx))));
+
+    Ok(())
+}
+
+/// Installs and starts the debounce program on a state machine. The program
+/// pushes the state of the given pins to the RX FIFO whenever it has changed
+/// and then stayed stable for `debounce_time_ms`.
+#[cfg(not(feature = "soft-debounce"))]
+fn install_debouncer<
+    P: rp2040_hal::pio::PIOExt + 'static,
+    SM: rp2040_hal::pio::StateMachineIndex + 'static,
+    const IRQ: usize,
+>(
+    sm: UninitStateMachine<(P, SM)>,
+    pio: &mut PIO<P>,
+    sys_freq: HertzU32,
+    pins: impl IntoIterator<Item = Pin<DynPinId, P::PinFunction, PullUp>>,
+    debounce_time_ms: u32,
+) -> Result<Rx<(P, SM)>, InstallError> {
     // By taking the strongly typed pins in, they can be automatically reconfigured
     // and moved to be owned by this button module.
     // We don't actually need to store them though, just get / validate the ids.
     let (base_pin, pin_count) = validate_pins(pins.into_iter().map(|p| p.id().num));
 
-    let prog = program();
-
-    const TARGET_DEBOUNCE_TIME_MS: u32 = 5;
-    let total_debounce_instructions =
-        prog.debounce_cycle_instructions as u32 * prog.debounce_cycles as u32;
-    let target_clk_div = (TARGET_DEBOUNCE_TIME_MS * (sys_freq.to_Hz() / 1000)) // instructions during the target time
-        / total_debounce_instructions;
-    let clk_div = target_clk_div.min(u16::MAX as u32) as u16;
+    let prog = debounce::program(pin_count);
+    let clk_div = prog.clock_divisor(sys_freq.to_Hz(), debounce_time_ms);
 
     let installed = pio.install(&prog.program)?;
 
@@ -150,33 +140,144 @@ pub fn init_buttons<
 
     rx.enable_rx_not_empty_interrupt(pio_irq);
 
-    critical_section::with(|cs| BUTTONS_PIO_SM_RX.borrow(cs).replace(Some(Box::new(rx))));
-
     let _sm = sm.start();
-    
-    Ok(())
+
+    Ok(rx)
 }
 
+#[cfg(feature = "soft-debounce")]
+pub type SoftButtonPin = Pin<DynPinId, FunctionSioInput, PullUp>;
+
+#[cfg(feature = "soft-debounce")]
+struct SoftButtons {
+    debouncer: SoftDebouncer<SoftButtonPin, 6>,
+    alarm: Alarm2,
+}
+
+#[cfg(feature = "soft-debounce")]
+static SOFT_BUTTONS: Mutex<RefCell<Option<SoftButtons>>> = Mutex::new(RefCell::new(None));
+
+/// Starts the buttons with the GPIO interrupt and timer based debouncer
+/// instead of the PIO one, leaving the PIO blocks free for other uses.
+/// `on_gpio_interrupt` and `on_timer_interrupt` must be called from
+/// `IO_IRQ_BANK0` and `TIMER_IRQ_2`.
+#[cfg(feature = "soft-debounce")]
+pub fn init_buttons_soft(
+    pins: [SoftButtonPin; 6],
+    debounce_time_ms: u8,
+    mut alarm: Alarm2,
+    timer: timer::Timer,
+) {
+    for p in &pins {
+        p.set_interrupt_enabled(Interrupt::EdgeLow, true);
+        p.set_interrupt_enabled(Interrupt::EdgeHigh, true);
+    }
+    alarm.enable_interrupt();
+
+    let debouncer = SoftDebouncer::new(pins, u64::from(debounce_time_ms) * 1000);
+    critical_section::with(|cs| {
+        BUTTONS_TIMER.borrow(cs).set(Some(timer));
+        // Report the initial state, like the PIO program does
+        update_state(cs, FOOTSWITCH_MASK, debouncer.state());
+        SOFT_BUTTONS
+            .borrow(cs)
+            .replace(Some(SoftButtons { debouncer, alarm }));
+    });
+}
+
+#[cfg(feature = "soft-debounce")]
+pub fn on_gpio_interrupt() {
+    critical_section::with(|cs| {
+        let mut buttons = SOFT_BUTTONS.borrow_ref_mut(cs);
+        let Some(buttons) = buttons.as_mut() else {
+            return;
+        };
+
+        for p in buttons.debouncer.pins_mut() {
+            p.clear_interrupt(Interrupt::EdgeLow);
+            p.clear_interrupt(Interrupt::EdgeHigh);
+        }
+
+        let deadline = buttons.debouncer.on_edge(now_us(cs));
+        defmt::unwrap!(buttons
+            .alarm
+            .schedule_at(timer::Instant::from_ticks(deadline))
+            .map_err(|_| "Schedule error"));
+    })
+}
+
+#[cfg(feature = "soft-debounce")]
+pub fn on_timer_interrupt() {
+    critical_section::with(|cs| {
+        let mut buttons = SOFT_BUTTONS.borrow_ref_mut(cs);
+        let Some(buttons) = buttons.as_mut() else {
+            return;
+        };
+
+        buttons.alarm.clear_interrupt();
+        if let Some(b) = buttons.debouncer.poll(now_us(cs)) {
+            update_state(cs, FOOTSWITCH_MASK, b);
+        }
+    })
+}
+
+#[cfg(not(feature = "soft-debounce"))]
 pub fn on_interrupt() {
     defmt::trace!("pio interrupt");
     critical_section::with(|cs| {
         if let Some(rx) = BUTTONS_PIO_SM_RX.borrow_ref_mut(cs).as_mut() {
             while let Some(b) = rx.read() {
-                let b = (!b & 0x3F) as u8;
-                defmt::info!("Buttons change: 0x{:02x}", b);
-                CURRENT_BUTTONS.borrow(cs).set(b);
-                if BUTTON_CHANGE_QUEUE.borrow_ref_mut(cs).push_back(b).is_err() {
-                    defmt::warn!("BUTTON_CHANGE_QUEUE full");
-                }
+                let b = (!b & FOOTSWITCH_MASK as u32) as u8;
+                update_state(cs, FOOTSWITCH_MASK, b);
             }
         }
     })
 }
 
+/// Merges a change from an input outside the PIO debouncer (e.g. the external
+/// footswitch jack) into the button state. Only the bits in `mask` are touched.
+#[cfg(feature = "ext-footswitch")]
+pub fn update_external(cs: CriticalSection, mask: u8, state: u8) {
+    debug_assert_eq!(mask & FOOTSWITCH_MASK, 0);
+    update_state(cs, mask, state);
+}
+
+fn update_state(cs: CriticalSection, mask: u8, state: u8) {
+    let current = CURRENT_BUTTONS.borrow(cs);
+    let prev_state = current.get();
+    let b = (prev_state & !mask) | (state & mask);
+    defmt::info!("Buttons change: 0x{:02x}", b);
+    current.set(b);
+
+    let ev = ButtonEvent {
+        time_us: now_us(cs),
+        state: b,
+        prev_state,
+    };
+    if BUTTON_CHANGE_QUEUE.borrow_ref_mut(cs).push_back(ev).is_err() {
+        let overflows = BUTTON_QUEUE_OVERFLOWS.borrow(cs);
+        overflows.set(overflows.get().wrapping_add(1));
+        defmt::warn!("BUTTON_CHANGE_QUEUE full, {} events dropped", overflows.get());
+    }
+}
+
+fn now_us(cs: CriticalSection) -> u64 {
+    BUTTONS_TIMER
+        .borrow(cs)
+        .get()
+        .map(|t| t.get_counter().ticks())
+        .unwrap_or(0)
+}
+
 pub fn current() -> u8 {
     critical_section::with(|cs| CURRENT_BUTTONS.borrow(cs).get())
 }
 
-pub fn pop_change_queue() -> Option<u8> {
+pub fn pop_change_queue() -> Option<ButtonEvent> {
     critical_section::with(|cs| BUTTON_CHANGE_QUEUE.borrow_ref_mut(cs).pop_front())
 }
+
+/// Number of button events dropped because the change queue was full
+pub fn queue_overflows() -> u32 {
+    critical_section::with(|cs| BUTTON_QUEUE_OVERFLOWS.borrow(cs).get())
+}
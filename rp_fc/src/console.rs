@@ -0,0 +1,402 @@
+//! Configuration and diagnostics console on the USB serial port.
+//!
+//! Typed lines are collected and parsed in `USBCTRL_IRQ`, and the commands
+//! run from the main loop, which owns the settings. The output is queued
+//! here and sent by the USB interrupt. Command output waits for room in the
+//! queue, so long output isn't cut short while the host reads it.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use critical_section::{CriticalSection, Mutex};
+use heapless::Deque;
+use rp_fc_core::actions::Action;
+use rp_fc_core::config::Config;
+use rp_fc_core::console::{self, Command, LineBuffer};
+use rp_fc_core::encoder::EncoderMapping;
+use rp_fc_core::midi::{MidiOutMapping, SwitchMidi};
+use rp2040_hal::timer;
+
+use crate::flash_config;
+use crate::kt_uart::LinkStats;
+#[cfg(feature = "ws2812")]
+use crate::rgb_leds;
+use crate::time::{InstantEx, TimerEx};
+use crate::{buttons, leds, usb, version};
+
+const PROMPT: &str = "> ";
+
+/// How long command output waits for the host to read the queued output
+const OUTPUT_TIMEOUT_MS: i64 = 100;
+
+pub(crate) static OUTPUT: Mutex<RefCell<Deque<u8, { console::OUTPUT_LEN }>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+static LINE: Mutex<RefCell<LineBuffer>> = Mutex::new(RefCell::new(LineBuffer::new()));
+static COMMANDS: Mutex<RefCell<Deque<Command, 4>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// Settings and state the commands work on
+pub struct Context<'a> {
+    /// The debounce time is used from the next boot on, the rest right away
+    pub config: &'a mut Config,
+    pub config_store: &'a mut flash_config::Store,
+    pub link_stats: LinkStats,
+    pub timer: &'a timer::Timer,
+}
+
+/// Echo and error output from the USB interrupt, with `\n` sent as CR LF.
+/// Output is dropped if the queue is full.
+struct Echo<'cs>(CriticalSection<'cs>);
+
+impl Write for Echo<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut output = OUTPUT.borrow_ref_mut(self.0);
+        for b in s.bytes() {
+            if b == b'\n' {
+                _ = output.push_back(b'\r');
+            }
+            _ = output.push_back(b);
+        }
+        Ok(())
+    }
+}
+
+/// Command output from the main loop, with `\n` sent as CR LF. When the
+/// queue is full, waits for the USB interrupt to send some of it, and drops
+/// the rest of the output if the host doesn't read it in time.
+struct Output<'a> {
+    timer: &'a timer::Timer,
+    stalled: bool,
+}
+
+impl Output<'_> {
+    /// Waits for room in the queue. Returns false on a timeout.
+    fn wait(&mut self) -> bool {
+        usb::flush_console();
+        let deadline = self.timer.now().offset_ms(OUTPUT_TIMEOUT_MS);
+        while critical_section::with(|cs| OUTPUT.borrow_ref(cs).is_full()) {
+            if self.stalled || self.timer.has_passed(deadline) {
+                self.stalled = true;
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut rest = s.as_bytes();
+        // Whether the CR of a line end at the start of `rest` is queued
+        let mut cr_queued = false;
+        while !rest.is_empty() {
+            critical_section::with(|cs| {
+                let mut output = OUTPUT.borrow_ref_mut(cs);
+                while let Some(&b) = rest.first() {
+                    if b == b'\n' && !cr_queued {
+                        if output.push_back(b'\r').is_err() {
+                            break;
+                        }
+                        cr_queued = true;
+                    }
+                    if output.push_back(b).is_err() {
+                        break;
+                    }
+                    cr_queued = false;
+                    rest = &rest[1..];
+                }
+            });
+            if !rest.is_empty() && !self.wait() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints a line outside of a command, e.g. the diagnostics results. Nobody
+/// may be reading, so it's dropped if the queue is full.
+pub fn print(args: core::fmt::Arguments) {
+    critical_section::with(|cs| {
+        _ = writeln!(Echo(cs), "{}", args);
+    });
+    usb::flush_console();
+}
+
+/// Handles bytes received from the host. Called from the USB interrupt.
+pub(crate) fn on_input(cs: CriticalSection, bytes: &[u8]) {
+    let mut out = Echo(cs);
+    let mut line = LINE.borrow_ref_mut(cs);
+    for &b in bytes {
+        // Echo
+        match b {
+            b'\r' | b'\n' => _ = out.write_str("\n"),
+            0x08 | 0x7f => _ = out.write_str("\x08 \x08"),
+            0x20..=0x7e => _ = out.write_char(b as char),
+            _ => {}
+        }
+
+        match line.push(b) {
+            None => {}
+            Some(Ok(l)) => match console::parse(l) {
+                None => _ = out.write_str(PROMPT),
+                Some(Ok(cmd)) => {
+                    if COMMANDS.borrow_ref_mut(cs).push_back(cmd).is_err() {
+                        _ = write!(out, "Busy\n{}", PROMPT);
+                    }
+                }
+                Some(Err(e)) => _ = write!(out, "{}\n{}", e.message(), PROMPT),
+            },
+            Some(Err(())) => _ = write!(out, "Line too long\n{}", PROMPT),
+        }
+    }
+}
+
+/// Pops the next command typed on the console
+pub fn pop_command() -> Option<Command> {
+    critical_section::with(|cs| COMMANDS.borrow_ref_mut(cs).pop_front())
+}
+
+/// Runs a command and prints the result
+pub fn execute(cmd: Command, ctx: Context) {
+    let mut out = Output {
+        timer: ctx.timer,
+        stalled: false,
+    };
+    _ = run(cmd, ctx, &mut out);
+    _ = out.write_str(PROMPT);
+    usb::flush_console();
+}
+
+fn run(cmd: Command, ctx: Context, out: &mut Output) -> core::fmt::Result {
+    match cmd {
+        Command::Help => out.write_str(console::HELP),
+        Command::Version => writeln!(out, "RP-FC {} ({})", version::VERSION, version::GIT_HASH),
+        Command::Stats => {
+            let s = ctx.link_stats;
+            writeln!(
+                out,
+                "Sent {}, received {}, errors {}, timeouts {}",
+                s.sent, s.received, s.errors, s.timeouts
+            )?;
+            writeln!(out, "Button queue overflows {}", buttons::queue_overflows())
+        }
+        Command::ShowMidi => show_midi_out(out, &ctx.config.midi_out),
+        Command::SetMidi { switch, midi } => {
+            ctx.config.midi_out.switches[usize::from(switch)] = midi;
+            writeln!(out, "OK")
+        }
+        Command::SetMidiTempo(control) => {
+            ctx.config.midi_out.tempo_control = control;
+            writeln!(out, "OK")
+        }
+        Command::ShowDin => {
+            show_midi_out(out, &ctx.config.din_out)?;
+            let din_in = &ctx.config.din_in;
+            match din_in.channel {
+                None => writeln!(out, "In, all channels:")?,
+                Some(c) => writeln!(out, "In, channel {}:", c + 1)?,
+            }
+            for rule in din_in.rules() {
+                let switches = console::format_switches(&[rule.footswitches]);
+                writeln!(out, "{}: {}", console::format_midi_trigger(rule.trigger), switches)?;
+            }
+            let status = &ctx.config.status_midi;
+            writeln!(out, "Status, channel {}:", status.channel + 1)?;
+            writeln!(out, "pc {}", if status.channel_programs { "on" } else { "off" })?;
+            let controls = [status.effect_mode_control].into_iter().chain(status.effect_controls);
+            for (i, control) in controls.enumerate() {
+                let name = i.checked_sub(1).map_or("mode", console::effect_name);
+                match control {
+                    None => writeln!(out, "{}: off", name)?,
+                    Some(c) => writeln!(out, "{}: cc {}", name, c)?,
+                }
+            }
+            Ok(())
+        }
+        Command::SetDinMidi { switch, midi } => {
+            ctx.config.din_out.switches[usize::from(switch)] = midi;
+            writeln!(out, "OK")
+        }
+        Command::SetDinTempo(control) => {
+            ctx.config.din_out.tempo_control = control;
+            writeln!(out, "OK")
+        }
+        Command::SetDinIn {
+            trigger,
+            footswitches,
+        } => {
+            if ctx.config.din_in.set(trigger, footswitches) {
+                writeln!(out, "OK")
+            } else {
+                writeln!(out, "Too many messages mapped")
+            }
+        }
+        Command::SetStatusPrograms(on) => {
+            ctx.config.status_midi.channel_programs = on;
+            writeln!(out, "OK")
+        }
+        Command::SetStatusControl { effect, control } => {
+            let status = &mut ctx.config.status_midi;
+            match effect {
+                None => status.effect_mode_control = control,
+                Some(bit) => status.effect_controls[usize::from(bit)] = control,
+            }
+            writeln!(out, "OK")
+        }
+        Command::ShowLayer => writeln!(out, "Layer {}", ctx.config.layer + 1),
+        Command::SetLayer(layer) => {
+            ctx.config.layer = layer;
+            writeln!(out, "OK")
+        }
+        Command::ShowActions => {
+            for (i, a) in ctx.config.actions().switches.iter().enumerate() {
+                write!(out, "{}: press {}", i + 1, console::format_action(a.press))?;
+                if a.long_press != Action::None {
+                    write!(out, ", long {}", console::format_action(a.long_press))?;
+                }
+                writeln!(out)?;
+            }
+            Ok(())
+        }
+        Command::SetAction {
+            switch,
+            long_press,
+            action,
+        } => {
+            let actions = &mut ctx.config.actions_mut().switches[usize::from(switch)];
+            if long_press {
+                actions.long_press = action;
+            } else {
+                actions.press = action;
+            }
+            writeln!(out, "OK")
+        }
+        Command::ShowMacros => {
+            for (i, m) in ctx.config.macros.iter().enumerate() {
+                writeln!(out, "{}: {}", i + 1, console::format_macro(m))?;
+            }
+            Ok(())
+        }
+        Command::SetMacro { index, steps } => {
+            ctx.config.macros[usize::from(index)] = steps;
+            writeln!(out, "OK")
+        }
+        Command::ShowSetlist => {
+            for (i, step) in ctx.config.setlist.steps().iter().enumerate() {
+                writeln!(out, "{}: {}", i + 1, console::format_setlist_step(step))?;
+            }
+            Ok(())
+        }
+        Command::AddToSetlist(steps) => {
+            for step in steps.steps() {
+                if !ctx.config.setlist.push(*step) {
+                    return writeln!(out, "Setlist full");
+                }
+            }
+            writeln!(out, "OK")
+        }
+        Command::ClearSetlist => {
+            ctx.config.setlist.len = 0;
+            writeln!(out, "OK")
+        }
+        Command::ShowDebounce => writeln!(out, "{} ms", ctx.config.debounce_time_ms),
+        Command::SetDebounce(ms) => {
+            ctx.config.debounce_time_ms = ms;
+            writeln!(out, "OK, used after saving and rebooting")
+        }
+        Command::ShowBrightness => writeln!(out, "{}", ctx.config.led_brightness),
+        Command::SetBrightness(b) => {
+            ctx.config.led_brightness = b;
+            leds::set_global_brightness(b);
+            #[cfg(feature = "ws2812")]
+            rgb_leds::set_brightness(b);
+            writeln!(out, "OK")
+        }
+        Command::ShowLedMapping => {
+            if ctx.config.led_mapping.is_none() {
+                writeln!(out, "Board mapping")?;
+            }
+            let mapping = ctx.config.led_mapping.unwrap_or(leds::BOARD_MAPPING);
+            for (i, map) in mapping.leds.iter().enumerate() {
+                writeln!(out, "{}: {}", i + 1, console::format_led_map(map))?;
+            }
+            Ok(())
+        }
+        Command::SetLedMap { led, map } => {
+            let mut mapping = ctx.config.led_mapping.unwrap_or(leds::BOARD_MAPPING);
+            mapping.leds[usize::from(led)] = map;
+            ctx.config.led_mapping = Some(mapping);
+            set_led_mapping(mapping);
+            writeln!(out, "OK")
+        }
+        Command::ResetLedMapping => {
+            ctx.config.led_mapping = None;
+            set_led_mapping(leds::BOARD_MAPPING);
+            writeln!(out, "OK")
+        }
+        Command::ShowTuner => {
+            let m = ctx.config.tuner_footswitches;
+            write!(out, "Tuner switches: ")?;
+            if m == 0 {
+                write!(out, "off")?;
+            }
+            for n in (0..8).filter(|n| m & (1 << n) != 0) {
+                write!(out, "{}", n + 1)?;
+            }
+            writeln!(out)
+        }
+        Command::SetTuner(m) => {
+            ctx.config.tuner_footswitches = m;
+            writeln!(out, "OK")
+        }
+        Command::ShowEncoder => {
+            let e = &ctx.config.encoder;
+            writeln!(out, "scroll {}", console::format_switches(e.scroll_switches()))?;
+            writeln!(out, "push {}", console::format_switches(&[e.push_switches]))
+        }
+        Command::SetEncoderScroll(scroll) => {
+            let len = scroll.iter().take_while(|&&m| m != 0).count();
+            let push = ctx.config.encoder.push_switches;
+            // At most MAX_SCROLL switches, as parsed
+            ctx.config.encoder = EncoderMapping::new(&scroll[..len], push).unwrap();
+            writeln!(out, "OK")
+        }
+        Command::SetEncoderPush(m) => {
+            ctx.config.encoder.push_switches = m;
+            writeln!(out, "OK")
+        }
+        Command::Save => match ctx.config_store.save(ctx.config) {
+            Ok(()) => writeln!(out, "Saved"),
+            Err(e) => {
+                defmt::error!("Config save failed: {}", e);
+                writeln!(out, "Saving failed")
+            }
+        },
+        Command::Bootloader => {
+            // Doesn't return. The host sees the serial port disappear and the
+            // RPI-RP2 drive appear instead.
+            rp2040_hal::rom_data::reset_to_usb_boot(0, 0);
+            Ok(())
+        }
+    }
+}
+
+fn show_midi_out(out: &mut Output, m: &MidiOutMapping) -> core::fmt::Result {
+    writeln!(out, "MIDI channel {}", m.channel + 1)?;
+    for (i, midi) in m.switches.iter().enumerate() {
+        match midi {
+            SwitchMidi::None => writeln!(out, "{}: off", i + 1)?,
+            SwitchMidi::Cc { control } => writeln!(out, "{}: cc {}", i + 1, control)?,
+            SwitchMidi::Pc { program } => writeln!(out, "{}: pc {}", i + 1, program)?,
+        }
+    }
+    match m.tempo_control {
+        None => writeln!(out, "tempo: off"),
+        Some(control) => writeln!(out, "tempo: cc {} and {}", control, control + 32),
+    }
+}
+
+fn set_led_mapping(mapping: leds::LedMapping<{ leds::LED_COUNT }>) {
+    leds::set_mapping(mapping);
+    #[cfg(feature = "ws2812")]
+    rgb_leds::set_mapping(mapping);
+}
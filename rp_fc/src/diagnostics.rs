@@ -0,0 +1,108 @@
+//! Wiring diagnostics for newly built units.
+//!
+//! Entered by holding footswitch 1 at power-up. Lights each LED in turn,
+//! checks the amp link by reading back a byte sent on the bus, and then
+//! echoes the footswitches on their LEDs. The results are logged over defmt
+//! and printed on the USB console.
+//! Nothing is sent to the amp apart from the test byte; power cycle to leave.
+
+use crate::buttons::{self, FOOTSWITCH_MASK};
+use crate::console;
+use crate::input::{self, InputEvent};
+use crate::kt_uart::KatanaUart;
+use crate::leds::{self, LED_COUNT};
+#[cfg(feature = "ws2812")]
+use crate::rgb_leds::{self, Rgb};
+use rp2040_hal::uart::{UartDevice, ValidUartPinout};
+use rp_fc_core::self_test::SwitchCheck;
+
+/// Buttons held at power-up to enter the diagnostics
+pub const ENTRY_BUTTONS: u8 = 0x01;
+
+const LED_STEP_MS: u32 = 300;
+
+/// Logs over defmt and prints on the USB console, for units without a probe
+macro_rules! report {
+    ($level:ident, $($arg:tt)*) => {{
+        defmt::$level!($($arg)*);
+        console::print(format_args!($($arg)*));
+    }};
+}
+
+pub fn run<UART: UartDevice, Pins: ValidUartPinout<UART>>(
+    ktuart: &mut KatanaUart<UART, Pins>,
+    delay: &mut cortex_m::delay::Delay,
+) -> ! {
+    report!(info, "Diagnostics mode, release the footswitch to start");
+    while buttons::current() & ENTRY_BUTTONS != 0 {
+        delay.delay_ms(10);
+    }
+    while input::pop_event().is_some() {}
+
+    report!(info, "LED test: each LED lights up in turn");
+    for led in 0..LED_COUNT {
+        show(1 << led);
+        delay.delay_ms(LED_STEP_MS);
+    }
+    show(0);
+
+    // All LEDs on for OK, blinking for a failure
+    let all_leds = (1 << LED_COUNT) - 1;
+    if ktuart.loopback_test() {
+        report!(info, "Amp link loopback OK");
+        show(all_leds);
+        delay.delay_ms(3 * LED_STEP_MS);
+    } else {
+        report!(warn, "Amp link loopback failed, no echo (is the amp connected?)");
+        for _ in 0..3 {
+            show(all_leds);
+            delay.delay_ms(LED_STEP_MS / 2);
+            show(0);
+            delay.delay_ms(LED_STEP_MS / 2);
+        }
+    }
+    show(0);
+
+    report!(info, "Switch test: press each footswitch, its LED lights up");
+    let mut check = SwitchCheck::new(FOOTSWITCH_MASK);
+    loop {
+        cortex_m::asm::wfi();
+
+        while let Some(ev) = input::pop_event() {
+            match ev {
+                InputEvent::Buttons(ev) => {
+                    show(ev.state);
+                    let new = check.update(&ev);
+                    for button in (0..8).filter(|b| new & (1 << b) != 0) {
+                        report!(info, "Footswitch {} OK", button + 1);
+                    }
+                    if new != 0 && check.is_complete() {
+                        report!(info, "All footswitches OK");
+                    }
+                    if ev.pressed() & !FOOTSWITCH_MASK != 0 {
+                        report!(info, "External footswitch: 0x{:02x}", ev.state >> 6);
+                    }
+                }
+                #[cfg(feature = "encoder")]
+                InputEvent::Encoder(ev) => report!(info, "Encoder: {:?}", ev),
+                InputEvent::UsbMidi(msg) => report!(info, "USB MIDI: {:?}", msg),
+                #[cfg(feature = "din-midi")]
+                InputEvent::DinMidi(msg) => report!(info, "DIN MIDI: {:?}", msg),
+            }
+        }
+    }
+}
+
+fn show(bits: u8) {
+    leds::set_lit(bits);
+    #[cfg(feature = "ws2812")]
+    {
+        let mut colours = [Rgb::OFF; LED_COUNT];
+        for (i, c) in colours.iter_mut().enumerate() {
+            if bits & (1 << i) != 0 {
+                *c = Rgb::new(255, 255, 255);
+            }
+        }
+        rgb_leds::set_colours(colours);
+    }
+}
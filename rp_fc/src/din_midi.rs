@@ -0,0 +1,108 @@
+//! 5-pin DIN MIDI port on UART0 (TX GPIO0, RX GPIO1), for controlling
+//! other MIDI gear such as loopers and multi-effects.
+//!
+//! Received bytes are parsed in `UART0_IRQ` and the messages queued for the
+//! main loop. The standard MIDI in/out circuits (optocoupler on the input,
+//! 220 ohm resistors on the output) are needed between the pins and the
+//! jacks.
+
+use core::cell::RefCell;
+use critical_section::Mutex;
+use heapless::Deque;
+use rp2040_hal::{
+    clocks::ClocksManager,
+    fugit::RateExtU32,
+    gpio::{bank0, FunctionUart, Pin, PullNone},
+    pac,
+    uart::{self, UartPeripheral},
+    Clock,
+};
+use rp_fc_core::midi::{MidiMessage, MidiParser};
+
+const BAUD_RATE: u32 = 31250;
+
+type DinUart = UartPeripheral<
+    uart::Enabled,
+    pac::UART0,
+    (
+        Pin<bank0::Gpio0, FunctionUart, PullNone>,
+        Pin<bank0::Gpio1, FunctionUart, PullNone>,
+    ),
+>;
+
+struct DinMidi {
+    uart: DinUart,
+    parser: MidiParser,
+}
+
+static DIN_MIDI: Mutex<RefCell<Option<DinMidi>>> = Mutex::new(RefCell::new(None));
+static MIDI_IN_QUEUE: Mutex<RefCell<Deque<MidiMessage, 16>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Starts the port. `on_interrupt` must be called from `UART0_IRQ`.
+pub fn init(
+    u: pac::UART0,
+    pins: (
+        Pin<bank0::Gpio0, FunctionUart, PullNone>,
+        Pin<bank0::Gpio1, FunctionUart, PullNone>,
+    ),
+    resets: &mut pac::RESETS,
+    clocks: &ClocksManager,
+) -> Result<(), uart::Error> {
+    let mut uart = UartPeripheral::new(u, pins, resets).enable(
+        uart::UartConfig::new(
+            BAUD_RATE.Hz(),
+            uart::DataBits::Eight,
+            None,
+            uart::StopBits::One,
+        ),
+        clocks.peripheral_clock.freq(),
+    )?;
+    uart.enable_rx_interrupt();
+
+    critical_section::with(|cs| {
+        DIN_MIDI.borrow(cs).replace(Some(DinMidi {
+            uart,
+            parser: MidiParser::new(),
+        }))
+    });
+    Ok(())
+}
+
+pub fn on_interrupt() {
+    critical_section::with(|cs| {
+        let mut din = DIN_MIDI.borrow_ref_mut(cs);
+        let Some(din) = din.as_mut() else {
+            return;
+        };
+        let mut queue = MIDI_IN_QUEUE.borrow_ref_mut(cs);
+        let mut b = [0u8; 1];
+        // Reading the FIFO empty also clears the interrupts
+        while din.uart.uart_is_readable() {
+            if din.uart.read_full_blocking(&mut b).is_err() {
+                continue;
+            }
+            if let Some(msg) = din.parser.push(b[0]) {
+                if queue.push_back(msg).is_err() {
+                    defmt::warn!("DIN MIDI_IN_QUEUE full, message dropped");
+                }
+            }
+        }
+    })
+}
+
+/// Pops the next MIDI message received on the DIN input
+pub fn pop_midi() -> Option<MidiMessage> {
+    critical_section::with(|cs| MIDI_IN_QUEUE.borrow_ref_mut(cs).pop_front())
+}
+
+/// Sends a MIDI message on the DIN output. The message fits in the UART
+/// FIFO, so this only blocks when messages are sent faster than the MIDI
+/// baud rate allows.
+pub fn send_midi(msg: MidiMessage) {
+    critical_section::with(|cs| {
+        if let Some(din) = DIN_MIDI.borrow_ref_mut(cs).as_mut() {
+            din.uart.write_full_blocking(&msg.to_bytes());
+        }
+    })
+}
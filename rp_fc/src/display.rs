@@ -0,0 +1,74 @@
+//! SSD1306 128x64 I2C OLED status display.
+//!
+//! Shows the amp's bank and channel, the link state and transient messages
+//! in the display's text mode. Only changed characters are written, at most
+//! `CHARS_PER_POLL` per main loop round, so the I2C writes never hold up the
+//! amp communication for long.
+
+use embedded_hal::i2c::I2c;
+use rp_fc_core::display::{StatusView, TextScreen, COLUMNS};
+use ssd1306::{
+    mode::{TerminalMode, TerminalModeError},
+    prelude::*,
+    I2CDisplayInterface, Ssd1306,
+};
+
+/// About 5 ms of I2C traffic at 400 kHz, less than the UART RX FIFO holds
+const CHARS_PER_POLL: usize = 16;
+
+pub struct Display<I2C> {
+    oled: Ssd1306<I2CInterface<I2C>, DisplaySize128x64, TerminalMode>,
+    screen: TextScreen,
+    pub view: StatusView,
+    /// Where the display's cursor is, to skip needless position commands
+    cursor: Option<(u8, u8)>,
+}
+
+impl<I2C: I2c> Display<I2C> {
+    pub fn new(i2c: I2C) -> Result<Self, TerminalModeError> {
+        let mut oled = Ssd1306::new(
+            I2CDisplayInterface::new(i2c),
+            DisplaySize128x64,
+            DisplayRotation::Rotate0,
+        )
+        .into_terminal_mode();
+        oled.init()?;
+        oled.clear()?;
+
+        Ok(Display {
+            oled,
+            screen: TextScreen::new(),
+            view: StatusView::new(),
+            cursor: None,
+        })
+    }
+
+    /// Draws the next few changed characters
+    pub fn poll(&mut self, now_ms: u64) {
+        self.screen.set(&self.view.lines(now_ms));
+
+        for _ in 0..CHARS_PER_POLL {
+            let Some((col, row, c)) = self.screen.next_change() else {
+                break;
+            };
+            if self.draw(col, row, c).is_err() {
+                defmt::warn!("Display write error");
+                // Redraw from scratch once the display works again
+                self.screen = TextScreen::new();
+                self.cursor = None;
+                _ = self.oled.clear();
+                return;
+            }
+        }
+    }
+
+    fn draw(&mut self, col: u8, row: u8, c: u8) -> Result<(), TerminalModeError> {
+        if self.cursor != Some((col, row)) {
+            self.oled.set_position(col, row)?;
+        }
+        self.oled.print_char(c as char)?;
+        // The cursor wraps to the next line at the end of a line
+        self.cursor = Some((col + 1, row)).filter(|(col, _)| (*col as usize) < COLUMNS);
+        Ok(())
+    }
+}
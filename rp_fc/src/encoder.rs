@@ -0,0 +1,142 @@
+//! Rotary encoder with a push switch.
+//!
+//! The encoder has PIO1 to itself: one state machine decodes the A and B
+//! pins with the quadrature program, which needs the start of the
+//! instruction memory, and another debounces the push switch.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use critical_section::Mutex;
+use heapless::Deque;
+use rp2040_hal::{
+    fugit::HertzU32,
+    gpio::{DynPinId, Pin, PullUp},
+    pio::{
+        Buffers, InstallError, PIOBuilder, PIOExt, PinDir, PioIRQ, ShiftDirection,
+        StateMachineIndex, UninitStateMachine, PIO,
+    },
+};
+use rp_fc_core::encoder::{self, EncoderEvent, Quadrature};
+
+use crate::buttons::PioFifoRead;
+
+/// The push switch is ignored for this long after each change
+const SWITCH_LOCKOUT_MS: u32 = 5;
+
+struct EncoderState {
+    counter_rx: Box<dyn PioFifoRead + Send>,
+    switch_rx: Box<dyn PioFifoRead + Send>,
+    quadrature: Quadrature,
+}
+
+static ENCODER: Mutex<RefCell<Option<EncoderState>>> = Mutex::new(RefCell::new(None));
+static ENCODER_EVENT_QUEUE: Mutex<RefCell<Deque<EncoderEvent, 8>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+/// Starts the encoder state machines. `ab_pins` are the consecutive A and B
+/// pins. The quadrature program is loaded at address 0, so this must be
+/// the first program installed on the PIO.
+pub fn init_encoder<
+    P: PIOExt + 'static,
+    SM0: StateMachineIndex + 'static,
+    SM1: StateMachineIndex + 'static,
+    const IRQ: usize,
+>(
+    counter_sm: UninitStateMachine<(P, SM0)>,
+    switch_sm: UninitStateMachine<(P, SM1)>,
+    pio: &mut PIO<P>,
+    sys_freq: HertzU32,
+    ab_pins: [Pin<DynPinId, P::PinFunction, PullUp>; 2],
+    switch_pin: Pin<DynPinId, P::PinFunction, PullUp>,
+) -> Result<(), InstallError> {
+    let pio_irq = match IRQ {
+        0 => PioIRQ::Irq0,
+        1 => PioIRQ::Irq1,
+        _ => unreachable!(),
+    };
+
+    let a_pin = ab_pins[0].id().num;
+    assert_eq!(a_pin + 1, ab_pins[1].id().num);
+    let installed = pio.install(&encoder::quadrature_program())?;
+    let clk_div = (sys_freq.to_Hz() / encoder::QUADRATURE_CLOCK_HZ) as u16;
+    let (mut sm, counter_rx, _tx) = PIOBuilder::from_installed_program(installed)
+        .in_pin_base(a_pin)
+        .in_shift_direction(ShiftDirection::Left)
+        .out_shift_direction(ShiftDirection::Right)
+        .buffers(Buffers::OnlyRx)
+        .clock_divisor_fixed_point(clk_div, 0)
+        .build(counter_sm);
+    sm.set_pindirs([(a_pin, PinDir::Input), (a_pin + 1, PinDir::Input)]);
+    for instr in encoder::counter_reset() {
+        sm.exec_instruction(instr);
+    }
+    counter_rx.enable_rx_not_empty_interrupt(pio_irq);
+    let _sm = sm.start();
+
+    let switch_pin = switch_pin.id().num;
+    let prog = encoder::switch_program();
+    let clk_div = prog.clock_divisor(sys_freq.to_Hz(), SWITCH_LOCKOUT_MS);
+    let installed = pio.install(&prog.program)?;
+    let (mut sm, switch_rx, _tx) = PIOBuilder::from_installed_program(installed)
+        .in_pin_base(switch_pin)
+        .in_shift_direction(ShiftDirection::Left)
+        .autopush(true)
+        .push_threshold(1)
+        .clock_divisor_fixed_point(clk_div, 0)
+        .build(switch_sm);
+    sm.set_pindirs([(switch_pin, PinDir::Input)]);
+    switch_rx.enable_rx_not_empty_interrupt(pio_irq);
+    let _sm = sm.start();
+
+    critical_section::with(|cs| {
+        ENCODER.borrow(cs).replace(Some(EncoderState {
+            counter_rx: Box::new(counter_rx),
+            switch_rx: Box::new(switch_rx),
+            quadrature: Quadrature::new(),
+        }))
+    });
+
+    Ok(())
+}
+
+pub fn on_interrupt() {
+    critical_section::with(|cs| {
+        let mut encoder = ENCODER.borrow_ref_mut(cs);
+        let Some(encoder) = encoder.as_mut() else {
+            return;
+        };
+        let mut queue = ENCODER_EVENT_QUEUE.borrow_ref_mut(cs);
+        let mut push = |ev| {
+            defmt::debug!("Encoder event: {}", ev);
+            if queue.push_back(ev).is_err() {
+                defmt::warn!("ENCODER_EVENT_QUEUE full");
+            }
+        };
+
+        while let Some(w) = encoder.counter_rx.read() {
+            let detents = encoder.quadrature.update(w);
+            let ev = if detents > 0 {
+                EncoderEvent::Increment
+            } else {
+                EncoderEvent::Decrement
+            };
+            for _ in 0..detents.unsigned_abs() {
+                push(ev);
+            }
+        }
+        while let Some(w) = encoder.switch_rx.read() {
+            // Active low
+            push(if w == 0 {
+                EncoderEvent::Pressed
+            } else {
+                EncoderEvent::Released
+            });
+        }
+    })
+}
+
+pub fn pop_event() -> Option<EncoderEvent> {
+    critical_section::with(|cs| ENCODER_EVENT_QUEUE.borrow_ref_mut(cs).pop_front())
+}
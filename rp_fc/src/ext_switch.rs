@@ -0,0 +1,267 @@
+//! External dual footswitch (Boss FS-6 / FS-7 style) on a TRS jack.
+//!
+//! The tip and ring contacts are read as two extra buttons, which appear as
+//! bits 6 and 7 of the button state. The contacts are sampled from a timer
+//! alarm every millisecond and debounced in software.
+//!
+//! The switch type is detected on the fly, from how long the contact stays
+//! in each position. An open contact reads high, which is taken as released.
+//! A momentary switch is pressed briefly and rests released, so once the
+//! short stays are seen at one level, that level is the pressed one; this
+//! also catches a normally closed switch. A latching switch rests in both
+//! positions, so a contact that stays away from the released level for a
+//! long time, twice in a row with no short press in between, belongs to a
+//! latching switch. Every toggle of a latching switch is turned into a short
+//! press-release pulse, so the rest of the firmware sees the same events for
+//! both switch types.
+//!
+//! A contact that is closed at startup may be a switch held down while
+//! powering up, a normally closed switch, or a latching switch left on. Its
+//! changes are ignored until the short presses or the latching toggles tell
+//! which one it is.
+
+use core::cell::RefCell;
+use critical_section::Mutex;
+use embedded_hal::digital::InputPin;
+use rp2040_hal::{
+    fugit::ExtU32,
+    gpio::{DynPinId, FunctionSioInput, Pin, PullUp},
+    timer::{Alarm, Alarm1},
+};
+
+use crate::buttons;
+
+pub type ExtSwitchPin = Pin<DynPinId, FunctionSioInput, PullUp>;
+
+/// Button bit of the tip contact, the ring contact is the next bit
+pub const EXT_BUTTON_BASE_BIT: u8 = 6;
+
+const SAMPLE_INTERVAL_MS: u32 = 1;
+/// Consecutive samples needed to accept a level change
+const DEBOUNCE_SAMPLES: u8 = 5;
+/// A press shorter than this can only come from a momentary switch
+const MOMENTARY_MAX_MS: u32 = 600;
+/// Short stays at the same level, with none at the other level in between,
+/// needed to take that level as the pressed one
+const PRESS_DETECT_COUNT: u8 = 2;
+/// A contact held this long without a short press seen may be latching
+const LATCH_DETECT_MS: u32 = 3000;
+/// Such long holds needed in a row to take the switch as latching
+const LATCH_DETECT_COUNT: u8 = 2;
+/// Length of the press generated for each toggle of a latching switch
+const LATCH_PULSE_MS: u32 = 50;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum SwitchKind {
+    Unknown,
+    Momentary,
+    Latching,
+}
+
+struct Contact {
+    idle_level: bool,
+    /// Whether `idle_level` is known to be the released level, changes are
+    /// only reported then
+    polarity_known: bool,
+    stable_level: bool,
+    bounce_count: u8,
+    held_ms: u32,
+    kind: SwitchKind,
+    pressed: bool,
+    pulse_remaining_ms: u32,
+    /// Short stays in a row at the low and the high level
+    short_stays: [u8; 2],
+    /// Long stays away from `idle_level` in a row
+    long_stays: u8,
+    /// Whether the current stay was already counted in `long_stays`
+    long_stay_counted: bool,
+}
+
+impl Contact {
+    fn new(level: bool) -> Self {
+        Contact {
+            idle_level: true,
+            polarity_known: level,
+            stable_level: level,
+            bounce_count: 0,
+            held_ms: 0,
+            kind: SwitchKind::Unknown,
+            pressed: false,
+            pulse_remaining_ms: 0,
+            short_stays: [0; 2],
+            long_stays: 0,
+            // How long the contact was closed before startup is unknown
+            long_stay_counted: !level,
+        }
+    }
+
+    /// Feeds one sample of the contact level. Returns the new pressed state
+    /// if it changed.
+    fn sample(&mut self, level: bool) -> Option<bool> {
+        self.held_ms = self.held_ms.saturating_add(SAMPLE_INTERVAL_MS);
+
+        if level == self.stable_level {
+            self.bounce_count = 0;
+        } else {
+            self.bounce_count += 1;
+            if self.bounce_count >= DEBOUNCE_SAMPLES {
+                let prev_held_ms = self.held_ms;
+                self.bounce_count = 0;
+                self.held_ms = 0;
+                self.stable_level = level;
+                self.long_stay_counted = false;
+                return self.on_level_change(prev_held_ms);
+            }
+        }
+
+        self.on_hold()
+    }
+
+    fn on_level_change(&mut self, prev_held_ms: u32) -> Option<bool> {
+        let away = self.stable_level != self.idle_level;
+        let short = prev_held_ms <= MOMENTARY_MAX_MS;
+        if short {
+            let prev = !self.stable_level as usize;
+            self.short_stays[prev] = self.short_stays[prev].saturating_add(1);
+            self.short_stays[1 - prev] = 0;
+            self.long_stays = 0;
+        }
+        let pressed_level = if self.short_stays[self.idle_level as usize] >= PRESS_DETECT_COUNT {
+            Some(self.idle_level)
+        } else if self.short_stays[!self.idle_level as usize] >= PRESS_DETECT_COUNT {
+            Some(!self.idle_level)
+        } else {
+            None
+        };
+
+        match self.kind {
+            SwitchKind::Latching if short && !away => {
+                // Pressed and released right away, this is a momentary switch after all
+                defmt::info!("Ext footswitch: momentary switch detected");
+                self.kind = SwitchKind::Momentary;
+                self.pulse_remaining_ms = 0;
+                self.set_pressed(false)
+            }
+            SwitchKind::Latching => {
+                // Every toggle is a press, regardless of the direction
+                self.pulse_remaining_ms = LATCH_PULSE_MS;
+                self.set_pressed(true)
+            }
+            _ if !self.polarity_known && pressed_level == Some(self.idle_level) => {
+                // The short stays are at the level taken as released
+                defmt::info!("Ext footswitch: inverted momentary switch detected");
+                self.idle_level = !self.idle_level;
+                self.kind = SwitchKind::Momentary;
+                self.polarity_known = true;
+                self.set_pressed(false)
+            }
+            SwitchKind::Unknown if pressed_level.is_some() => {
+                defmt::info!("Ext footswitch: momentary switch detected");
+                self.kind = SwitchKind::Momentary;
+                self.polarity_known = true;
+                self.set_pressed(away)
+            }
+            _ if !self.polarity_known => None,
+            SwitchKind::Unknown | SwitchKind::Momentary => self.set_pressed(away),
+        }
+    }
+
+    fn on_hold(&mut self) -> Option<bool> {
+        match self.kind {
+            SwitchKind::Latching if self.pulse_remaining_ms > 0 => {
+                self.pulse_remaining_ms =
+                    self.pulse_remaining_ms.saturating_sub(SAMPLE_INTERVAL_MS);
+                if self.pulse_remaining_ms == 0 {
+                    self.set_pressed(false)
+                } else {
+                    None
+                }
+            }
+            SwitchKind::Unknown
+                if self.stable_level != self.idle_level
+                    && self.held_ms >= LATCH_DETECT_MS
+                    && !self.long_stay_counted =>
+            {
+                self.long_stay_counted = true;
+                self.long_stays += 1;
+                if self.long_stays < LATCH_DETECT_COUNT {
+                    // Could still be a momentary switch held down
+                    return None;
+                }
+                defmt::info!("Ext footswitch: latching switch detected");
+                self.kind = SwitchKind::Latching;
+                self.polarity_known = true;
+                // The current position is just another resting position
+                self.idle_level = self.stable_level;
+                self.set_pressed(false)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_pressed(&mut self, pressed: bool) -> Option<bool> {
+        if self.pressed == pressed {
+            None
+        } else {
+            self.pressed = pressed;
+            Some(pressed)
+        }
+    }
+}
+
+struct ExtSwitch {
+    alarm: Alarm1,
+    pins: [ExtSwitchPin; 2],
+    contacts: [Contact; 2],
+}
+
+static EXT_SWITCH: Mutex<RefCell<Option<ExtSwitch>>> = Mutex::new(RefCell::new(None));
+
+/// Starts sampling the tip and ring contacts
+pub fn init(mut alarm: Alarm1, tip: ExtSwitchPin, ring: ExtSwitchPin) {
+    let mut pins = [tip, ring];
+    let contacts = [
+        Contact::new(read_level(&mut pins[0])),
+        Contact::new(read_level(&mut pins[1])),
+    ];
+
+    alarm.enable_interrupt();
+    defmt::unwrap!(alarm
+        .schedule(SAMPLE_INTERVAL_MS.millis())
+        .map_err(|_| "Schedule error for ext switch alarm"));
+
+    critical_section::with(|cs| {
+        EXT_SWITCH.borrow(cs).replace(Some(ExtSwitch {
+            alarm,
+            pins,
+            contacts,
+        }))
+    });
+}
+
+pub fn on_timer_interrupt() {
+    critical_section::with(|cs| {
+        let mut ext = EXT_SWITCH.borrow_ref_mut(cs);
+        let Some(ext) = ext.as_mut() else {
+            return;
+        };
+
+        defmt::unwrap!(ext
+            .alarm
+            .schedule(SAMPLE_INTERVAL_MS.millis())
+            .map_err(|_| "Schedule error"));
+        ext.alarm.clear_interrupt();
+
+        for (i, (pin, contact)) in ext.pins.iter_mut().zip(ext.contacts.iter_mut()).enumerate() {
+            if let Some(pressed) = contact.sample(read_level(pin)) {
+                let mask = 1 << (EXT_BUTTON_BASE_BIT + i as u8);
+                buttons::update_external(cs, mask, if pressed { mask } else { 0 });
+            }
+        }
+    })
+}
+
+fn read_level(pin: &mut ExtSwitchPin) -> bool {
+    let Ok(level) = pin.is_high();
+    level
+}
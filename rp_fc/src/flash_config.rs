@@ -0,0 +1,44 @@
+//! Settings storage in the last 16 KiB of the QSPI flash, reserved as the
+//! CONFIG region in `memory.x`.
+//!
+//! The program runs from the same flash (XIP), so erasing and programming
+//! run from RAM with the interrupts disabled. A sector erase stops
+//! everything for tens of milliseconds, so saves only happen on request,
+//! or for the layer in use after a pause in playing.
+//! Core 1 isn't used; it would have to be paused too.
+
+use rp_fc_core::config_store::{ConfigStore, Flash, PAGE_SIZE, SECTOR_SIZE};
+
+const XIP_BASE: u32 = 0x1000_0000;
+/// Offset of the CONFIG region from the start of the flash
+const CONFIG_OFFSET: u32 = 0x1f_c000;
+const CONFIG_SECTORS: u32 = 4;
+
+pub type Store = ConfigStore<XipFlash>;
+
+pub struct XipFlash;
+
+impl Flash for XipFlash {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) {
+        let src = (XIP_BASE + CONFIG_OFFSET + offset) as *const u8;
+        // Safety: the CONFIG region is mapped and not used by the program
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+    }
+
+    fn erase_sector(&mut self, offset: u32) {
+        cortex_m::interrupt::free(|_| unsafe {
+            rp2040_flash::flash::flash_range_erase(CONFIG_OFFSET + offset, SECTOR_SIZE, true)
+        });
+    }
+
+    fn program_page(&mut self, offset: u32, data: &[u8; PAGE_SIZE]) {
+        cortex_m::interrupt::free(|_| unsafe {
+            rp2040_flash::flash::flash_range_program(CONFIG_OFFSET + offset, data, true)
+        });
+    }
+}
+
+/// Opens the store, finding the latest saved settings
+pub fn open() -> Store {
+    ConfigStore::new(XipFlash, CONFIG_SECTORS)
+}
@@ -0,0 +1,42 @@
+//! Common event path for all the input devices.
+
+use crate::buttons::{self, ButtonEvent};
+#[cfg(feature = "din-midi")]
+use crate::din_midi;
+#[cfg(feature = "encoder")]
+use crate::encoder;
+use crate::usb;
+#[cfg(feature = "encoder")]
+use rp_fc_core::encoder::EncoderEvent;
+use rp_fc_core::midi::MidiMessage;
+
+pub enum InputEvent {
+    /// Change in the footswitch button state
+    Buttons(ButtonEvent),
+    #[cfg(feature = "encoder")]
+    Encoder(EncoderEvent),
+    /// MIDI message from the USB host
+    UsbMidi(MidiMessage),
+    /// MIDI message from the DIN input
+    #[cfg(feature = "din-midi")]
+    DinMidi(MidiMessage),
+}
+
+/// Pops the next queued input event. Footswitch changes are returned first.
+pub fn pop_event() -> Option<InputEvent> {
+    if let Some(b) = buttons::pop_change_queue() {
+        return Some(InputEvent::Buttons(b));
+    }
+    #[cfg(feature = "encoder")]
+    if let Some(ev) = encoder::pop_event() {
+        return Some(InputEvent::Encoder(ev));
+    }
+    if let Some(msg) = usb::pop_midi() {
+        return Some(InputEvent::UsbMidi(msg));
+    }
+    #[cfg(feature = "din-midi")]
+    if let Some(msg) = din_midi::pop_midi() {
+        return Some(InputEvent::DinMidi(msg));
+    }
+    None
+}
@@ -13,12 +13,29 @@ use crate::time::{InstantEx, TimerEx};
 
 pub type MsgBuf = heapless::Vec<u8, 16>;
 
+/// The link is considered down after this long without messages from the
+/// amp. It replies to the status messages we send every 300 ms.
+const LINK_TIMEOUT_MS: i64 = 1000;
+
+/// Counters of the amp link traffic since power-up
+#[derive(Clone, Copy, Default, defmt::Format)]
+pub struct LinkStats {
+    pub sent: u32,
+    pub received: u32,
+    /// Invalid messages, read errors and wrong echoes
+    pub errors: u32,
+    /// Echo and reply timeouts
+    pub timeouts: u32,
+}
+
 pub struct KatanaUart<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> {
     uart: uart::UartPeripheral<uart::Enabled, UART, Pins>,
     timer: &'t timer::Timer,
     state: State,
-    tx_queue: Deque<MsgBuf, 5>,
+    tx_queue: Deque<MsgBuf, 16>,
     rx_queue: Deque<RxMessage, 2>,
+    last_rx: Option<Instant>,
+    stats: LinkStats,
 }
 
 impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UART, Pins> {
@@ -42,6 +59,8 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
             state: State::Idle,
             tx_queue: Default::default(),
             rx_queue: Default::default(),
+            last_rx: None,
+            stats: LinkStats::default(),
         })
     }
 
@@ -51,10 +70,47 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
         }
     }
 
+    /// Whether nothing is queued or on the bus, i.e. the last message has
+    /// been echoed and the amp has replied or timed out
+    pub fn is_idle(&self) -> bool {
+        self.tx_queue.is_empty() && matches!(self.state, State::Idle)
+    }
+
     pub fn pop_rx(&mut self) -> Option<RxMessage> {
         self.rx_queue.pop_front()
     }
 
+    /// Whether the amp has sent a valid message recently
+    pub fn link_up(&self) -> bool {
+        self.last_rx
+            .is_some_and(|t| !self.timer.has_passed(t.offset_ms(LINK_TIMEOUT_MS)))
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Checks that a byte sent on the bus is read back, like all the bytes we
+    /// send are. This needs the amp connected, as the bus is powered by it.
+    /// Only for diagnostics: the byte isn't a valid message.
+    pub fn loopback_test(&mut self) -> bool {
+        const TEST_BYTE: u8 = 0x55;
+
+        let mut b = [0u8; 1];
+        while self.uart.uart_is_readable() {
+            _ = self.uart.read_full_blocking(&mut b);
+        }
+
+        self.uart.write_full_blocking(&[TEST_BYTE]);
+        let wait_started = self.timer.now();
+        while !self.timer.has_passed(wait_started.offset_ms(20)) {
+            if self.uart.uart_is_readable() {
+                return matches!(self.uart.read_full_blocking(&mut b), Ok(()) if b[0] == TEST_BYTE);
+            }
+        }
+        false
+    }
+
     pub fn tick(&mut self, delay: &mut cortex_m::delay::Delay) {
         let mut wait_done = false;
         loop {
@@ -107,6 +163,7 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
             Some(State::Receiving(IncompleteRxMessage::start_rx()))
         } else if self.timer.has_passed(wait_start.offset_ms(100)) {
             defmt::error!("Reply wait timed out");
+            self.stats.timeouts += 1;
             Some(State::Idle)
         } else {
             None
@@ -125,6 +182,7 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
                 Ok(_) => b[0],
                 Err(e) => {
                     defmt::error!("Uart read error: {}", e);
+                    self.stats.errors += 1;
                     return Some(State::Idle);
                 }
             };
@@ -133,6 +191,8 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
                 Incomplete(im) => im,
                 Complete(m) => {
                     defmt::debug!("Received: {}", &m);
+                    self.last_rx = Some(self.timer.now());
+                    self.stats.received += 1;
                     if self.rx_queue.push_back(m).is_err() {
                         defmt::error!("Rx queue full!")
                     }
@@ -140,6 +200,7 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
                 }
                 Invalid(reason) => {
                     defmt::error!("Rx msg invalid: {}", reason);
+                    self.stats.errors += 1;
                     // TODO: drop first byte and try again?
                     return Some(State::Idle);
                 }
@@ -172,6 +233,7 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
                                 if pos + 1 == buf.len() {
                                     // Complete
                                     defmt::debug!("Sent msg {}", buf);
+                                    self.stats.sent += 1;
                                     Some(State::WaitReply(self.timer.now()))
                                 } else {
                                     Some(State::Sending(SendState::Send(buf, pos + 1)))
@@ -179,16 +241,19 @@ impl<'t, UART: UartDevice, Pins: uart::ValidUartPinout<UART>> KatanaUart<'t, UAR
                             } else {
                                 // Something went wrong
                                 defmt::error!("Send byte was read back differently");
+                                self.stats.errors += 1;
                                 Some(State::Idle)
                             }
                         }
                         Err(e) => {
                             defmt::error!("Read error while waiting for echo: {}", e);
+                            self.stats.errors += 1;
                             Some(State::Idle)
                         }
                     }
                 } else if self.timer.has_passed(wait_started.offset_ms(20)) {
                     defmt::error!("Echo wait timed out");
+                    self.stats.timeouts += 1;
                     Some(State::Idle)
                 } else {
                     None
@@ -0,0 +1,256 @@
+//! PWM-dimmed status LEDs.
+//!
+//! The six LEDs on GPIO10-15 are driven by PWM slices 5-7. Static patterns
+//! are applied right away; while any LED blinks or breathes, a timer alarm
+//! refreshes the duty cycles in the background.
+//!
+//! The amp's LED status is shown through an [`LedMapping`], which decides
+//! which status bit lights which LED and the polarity of each output.
+
+use core::cell::RefCell;
+use critical_section::Mutex;
+use embedded_hal::pwm::SetDutyCycle;
+pub use rp_fc_core::led::LedPattern;
+pub use rp_fc_core::led_map::LedMapping;
+use rp_fc_core::led::LedBank;
+use rp2040_hal::{
+    fugit::ExtU32,
+    gpio::{bank0, FunctionNull, Pin, PullDown},
+    pwm::{FreeRunning, Pwm5, Pwm6, Pwm7, Slice},
+    timer::{self, Alarm, Alarm3},
+};
+
+pub use rp_fc_core::led_map::LED_COUNT;
+
+/// Which amp LED status bit lights which LED, per board, unless the settings
+/// have a mapping of their own. Both default to the GA-FC order on GPIO10-15
+/// (and on the WS2812 chain); change these to match the enclosure wiring.
+#[cfg(feature = "rp-pico")]
+pub const BOARD_MAPPING: LedMapping<LED_COUNT> = LedMapping::identity();
+#[cfg(feature = "vcc-gnd-yd-rp2040")]
+pub const BOARD_MAPPING: LedMapping<LED_COUNT> = LedMapping::identity();
+
+const REFRESH_INTERVAL_MS: u32 = 10;
+
+type LedPin<I> = Pin<I, FunctionNull, PullDown>;
+
+/// The LED pins, GPIO10-15
+pub type LedPins = (
+    LedPin<bank0::Gpio10>,
+    LedPin<bank0::Gpio11>,
+    LedPin<bank0::Gpio12>,
+    LedPin<bank0::Gpio13>,
+    LedPin<bank0::Gpio14>,
+    LedPin<bank0::Gpio15>,
+);
+
+pub struct LedPwmSlices {
+    pub pwm5: Slice<Pwm5, FreeRunning>,
+    pub pwm6: Slice<Pwm6, FreeRunning>,
+    pub pwm7: Slice<Pwm7, FreeRunning>,
+}
+
+impl LedPwmSlices {
+    fn max_duty_cycle(&self) -> u16 {
+        self.pwm5.channel_a.max_duty_cycle()
+    }
+
+    fn set_duty_cycle(&mut self, led: usize, duty: u16) {
+        let res = match led {
+            0 => self.pwm5.channel_a.set_duty_cycle(duty),
+            1 => self.pwm5.channel_b.set_duty_cycle(duty),
+            2 => self.pwm6.channel_a.set_duty_cycle(duty),
+            3 => self.pwm6.channel_b.set_duty_cycle(duty),
+            4 => self.pwm7.channel_a.set_duty_cycle(duty),
+            5 => self.pwm7.channel_b.set_duty_cycle(duty),
+            _ => Ok(()),
+        };
+        let Ok(()) = res;
+    }
+}
+
+struct Leds {
+    pwm: LedPwmSlices,
+    bank: LedBank<LED_COUNT>,
+    mapping: LedMapping<LED_COUNT>,
+    status: u8,
+    /// Patterns shown instead of the status
+    overlay: bool,
+    /// Status bit of the tap tempo switch, whose LED shows the tempo
+    tempo: Option<(u8, LedPattern)>,
+    alarm: Alarm3,
+    timer: timer::Timer,
+    refresh_scheduled: bool,
+}
+
+static LEDS: Mutex<RefCell<Option<Leds>>> = Mutex::new(RefCell::new(None));
+
+/// Takes the PWM slices and the LED pins into use, with all LEDs off.
+/// `on_timer_interrupt` must be called from `TIMER_IRQ_3`.
+pub fn init(
+    mut pwm: LedPwmSlices,
+    pins: LedPins,
+    mut alarm: Alarm3,
+    timer: timer::Timer,
+) {
+    pwm.pwm5.channel_a.output_to(pins.0);
+    pwm.pwm5.channel_b.output_to(pins.1);
+    pwm.pwm6.channel_a.output_to(pins.2);
+    pwm.pwm6.channel_b.output_to(pins.3);
+    pwm.pwm7.channel_a.output_to(pins.4);
+    pwm.pwm7.channel_b.output_to(pins.5);
+
+    for led in 0..LED_COUNT {
+        pwm.set_duty_cycle(led, 0);
+    }
+    pwm.pwm5.enable();
+    pwm.pwm6.enable();
+    pwm.pwm7.enable();
+
+    alarm.enable_interrupt();
+
+    critical_section::with(|cs| {
+        LEDS.borrow(cs).replace(Some(Leds {
+            pwm,
+            bank: LedBank::default(),
+            mapping: LedMapping::identity(),
+            status: 0,
+            overlay: false,
+            tempo: None,
+            alarm,
+            timer,
+            refresh_scheduled: false,
+        }))
+    });
+}
+
+/// Turns the LEDs on or off from the amp's LED status byte
+pub fn set_status(status: u8) {
+    critical_section::with(|cs| {
+        if let Some(leds) = LEDS.borrow_ref_mut(cs).as_mut() {
+            leds.status = status;
+            if !leds.overlay {
+                leds.show_status();
+            }
+        }
+    })
+}
+
+/// Turns the LEDs on or off directly, bit N for LED N, ignoring the mapping
+pub fn set_lit(bits: u8) {
+    update(|bank| bank.set_bits(bits));
+}
+
+/// Replaces the status bit to LED mapping, and shows the last status with it
+pub fn set_mapping(mapping: LedMapping<LED_COUNT>) {
+    critical_section::with(|cs| {
+        if let Some(leds) = LEDS.borrow_ref_mut(cs).as_mut() {
+            leds.mapping = mapping;
+            if !leds.overlay {
+                leds.show_status();
+            }
+        }
+    })
+}
+
+/// Shows the patterns, ignoring the mapping, until `clear_overlay`. Status
+/// updates meanwhile are shown after that.
+pub fn show_overlay(patterns: [LedPattern; LED_COUNT]) {
+    critical_section::with(|cs| {
+        if let Some(leds) = LEDS.borrow_ref_mut(cs).as_mut() {
+            leds.overlay = true;
+            for (l, p) in leds.bank.leds.iter_mut().zip(patterns) {
+                l.pattern = p;
+            }
+            leds.refresh();
+        }
+    })
+}
+
+/// Goes back to showing the status
+pub fn clear_overlay() {
+    critical_section::with(|cs| {
+        if let Some(leds) = LEDS.borrow_ref_mut(cs).as_mut() {
+            leds.overlay = false;
+            leds.show_status();
+        }
+    })
+}
+
+/// Shows a tap tempo pattern on a footswitch's LED, in place of the status.
+/// The ext switches have no LED, so nothing shows their tempo.
+pub fn show_tempo(switch: u8, pattern: LedPattern) {
+    set_tempo(Some((switch, pattern)));
+}
+
+/// Goes back to showing the status on the tap tempo LED
+pub fn clear_tempo() {
+    set_tempo(None);
+}
+
+fn set_tempo(tempo: Option<(u8, LedPattern)>) {
+    critical_section::with(|cs| {
+        if let Some(leds) = LEDS.borrow_ref_mut(cs).as_mut() {
+            leds.tempo = tempo;
+            if !leds.overlay {
+                leds.show_status();
+            }
+        }
+    })
+}
+
+/// Sets the brightness scaling (0-255) for all the LEDs
+pub fn set_global_brightness(brightness: u8) {
+    update(|bank| bank.global_brightness = brightness);
+}
+
+pub fn on_timer_interrupt() {
+    critical_section::with(|cs| {
+        let mut leds = LEDS.borrow_ref_mut(cs);
+        let Some(leds) = leds.as_mut() else {
+            return;
+        };
+        leds.alarm.clear_interrupt();
+        leds.refresh_scheduled = false;
+        leds.refresh();
+    })
+}
+
+fn update(f: impl FnOnce(&mut LedBank<LED_COUNT>)) {
+    critical_section::with(|cs| {
+        if let Some(leds) = LEDS.borrow_ref_mut(cs).as_mut() {
+            f(&mut leds.bank);
+            leds.refresh();
+        }
+    })
+}
+
+impl Leds {
+    fn show_status(&mut self) {
+        self.bank.set_bits(self.mapping.lit(self.status));
+        if let Some((switch, pattern)) = self.tempo {
+            if let Some(led) = self.bank.leds.get_mut(switch as usize) {
+                led.pattern = pattern;
+            }
+        }
+        self.refresh();
+    }
+
+    fn refresh(&mut self) {
+        let time_ms = (self.timer.get_counter().ticks() / 1000) as u32;
+        let max_duty = self.pwm.max_duty_cycle();
+        let duties = self.bank.duty_cycles(time_ms, max_duty);
+        for (led, (duty, map)) in duties.into_iter().zip(&self.mapping.leds).enumerate() {
+            let duty = if map.active_low { max_duty - duty } else { duty };
+            self.pwm.set_duty_cycle(led, duty);
+        }
+
+        if self.bank.is_animated() && !self.refresh_scheduled {
+            defmt::unwrap!(self
+                .alarm
+                .schedule(REFRESH_INTERVAL_MS.millis())
+                .map_err(|_| "Schedule error for LED alarm"));
+            self.refresh_scheduled = true;
+        }
+    }
+}
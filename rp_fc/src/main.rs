@@ -28,32 +28,118 @@ use bsp::hal::{
     sio::Sio,
     timer,
     fugit::ExtU32,
-    gpio::{PinGroup, PullNone, PinState},
+    gpio::PullNone,
+    pwm,
     timer::{Alarm, Alarm0},
+    uart::{UartDevice, ValidUartPinout},
     watchdog::Watchdog,
 };
+use input::InputEvent;
+use rp_fc_core::{
+    actions::{self, ActionMapper, LocalAction},
+    amp_state::AmpTracker,
+    button_event::{Combo, PressTimer},
+    hid::{KeyMapping, KeyReport},
+    config::Config,
+    held::{HoldSource, Holds},
+    led::{layer_patterns, tuner_patterns, LedPattern},
+    macros::MacroPlayer,
+    setlist::{Progress, SetlistRunner},
+    tap_tempo::TapTempo,
+    tuner::Tuner,
+    midi::MidiInMapping,
+};
+#[cfg(feature = "encoder")]
+use rp_fc_core::encoder::EncoderScroll;
 use static_cell::StaticCell;
 use time::{InstantEx, *};
 
 extern crate alloc;
 
 mod buttons;
+mod console;
+mod diagnostics;
+#[cfg(feature = "din-midi")]
+mod din_midi;
+#[cfg(feature = "oled")]
+mod display;
+#[cfg(feature = "encoder")]
+mod encoder;
+#[cfg(feature = "ext-footswitch")]
+mod ext_switch;
+mod flash_config;
+mod input;
 mod kt_uart;
+mod leds;
+#[cfg(feature = "ws2812")]
+mod rgb_leds;
+#[cfg(feature = "seven-segment")]
+mod segment_display;
 mod time;
+mod usb;
+mod version;
 
 #[global_allocator]
 static HEAP: LlffHeap = LlffHeap::empty();
 
+
+/// Footswitches held at power-up to reboot to the USB bootloader (1 and 6)
+const BOOTLOADER_BUTTONS: u8 = 0x21;
+/// Wait for the power-up button state on top of the debounce time
+const BOOT_BUTTONS_MARGIN_MS: u32 = 10;
+/// Footswitch held at power-up to start in keyboard mode (2)
+const KEYBOARD_MODE_BUTTONS: u8 = 0x02;
+/// Footswitches pressed together to switch between amp and keyboard mode
+/// (5 and 6)
+const MODE_COMBO: Combo = Combo::new(0x30);
+/// Amp status request interval in keyboard mode while no amp replies, to
+/// keep the link mostly idle
+const IDLE_STATUS_INTERVAL_MS: i64 = 2000;
+/// How long the LEDs show the layer after switching to it
+const LAYER_DISPLAY_MS: i64 = 1500;
+/// The layer is saved once no switch was pressed for this long after
+/// switching to it, so stepping through the layers or playing on writes the
+/// flash only in a pause
+const LAYER_SAVE_DELAY_MS: i64 = 10_000;
+/// How long the OLED shows the tapped tempo
+#[cfg(feature = "oled")]
+const TEMPO_MESSAGE_MS: u64 = 2000;
+/// Colour of the WS2812 LEDs showing the layer
+#[cfg(feature = "ws2812")]
+const LAYER_COLOUR: rgb_leds::Rgb = rgb_leds::Rgb::new(255, 255, 255);
+/// Colour of the WS2812 LEDs in tuner mode
+#[cfg(feature = "ws2812")]
+const TUNER_COLOUR: rgb_leds::Rgb = rgb_leds::Rgb::new(0, 128, 255);
+
 static STATUS_MSG_ALARM: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
 
 static mut TIMER_REF: Option<&timer::Timer> = None;
 static TIMER: StaticCell<timer::Timer> = StaticCell::new();
 
+#[cfg(feature = "soft-debounce")]
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    buttons::on_gpio_interrupt();
+}
+
+#[cfg(feature = "soft-debounce")]
+#[interrupt]
+fn TIMER_IRQ_2() {
+    buttons::on_timer_interrupt();
+}
+
+#[cfg(not(feature = "soft-debounce"))]
 #[interrupt]
 fn PIO0_IRQ_0() {
     buttons::on_interrupt();
 }
 
+#[cfg(feature = "encoder")]
+#[interrupt]
+fn PIO1_IRQ_0() {
+    encoder::on_interrupt();
+}
+
 #[interrupt]
 fn TIMER_IRQ_0() {
     // Reschedule
@@ -65,6 +151,28 @@ fn TIMER_IRQ_0() {
     });
 }
 
+#[cfg(feature = "ext-footswitch")]
+#[interrupt]
+fn TIMER_IRQ_1() {
+    ext_switch::on_timer_interrupt();
+}
+
+#[interrupt]
+fn TIMER_IRQ_3() {
+    leds::on_timer_interrupt();
+}
+
+#[interrupt]
+fn USBCTRL_IRQ() {
+    usb::on_interrupt();
+}
+
+#[cfg(feature = "din-midi")]
+#[interrupt]
+fn UART0_IRQ() {
+    din_midi::on_interrupt();
+}
+
 #[interrupt]
 fn UART1_IRQ() {
     // Just clear the flag, this is only used for wfi wake
@@ -75,6 +183,46 @@ fn UART1_IRQ() {
         .write(|f| f.rxic().clear_bit_by_one().rtic().clear_bit_by_one());
 }
 
+/// Shows the layer on the LEDs instead of the amp status, until
+/// `leds::clear_overlay`
+fn show_layer(layer: u8) {
+    let patterns = layer_patterns(usize::from(layer));
+    leds::show_overlay(patterns);
+    #[cfg(feature = "ws2812")]
+    rgb_leds::show_overlay(patterns.map(|p| {
+        if p == LedPattern::Off {
+            rgb_leds::Rgb::OFF
+        } else {
+            LAYER_COLOUR
+        }
+    }));
+}
+
+/// Shows tuner mode on the LEDs instead of the amp status, or goes back to
+/// the status
+fn show_tuner(on: bool) {
+    if on {
+        leds::show_overlay(tuner_patterns());
+        #[cfg(feature = "ws2812")]
+        rgb_leds::show_overlay([TUNER_COLOUR; leds::LED_COUNT]);
+    } else {
+        leds::clear_overlay();
+        #[cfg(feature = "ws2812")]
+        rgb_leds::clear_overlay();
+    }
+}
+
+/// Sends a GA-FC footswitch state to the amp, and tracks what it does to
+/// the amp's state until the next LED status
+fn send_footswitch<UART: UartDevice, Pins: ValidUartPinout<UART>>(
+    ktuart: &mut kt_uart::KatanaUart<UART, Pins>,
+    amp_tracker: &mut AmpTracker,
+    state: u8,
+) {
+    amp_tracker.on_footswitch(state);
+    ktuart.enqueue_send(katana_sysex::footswitch_change(state).into_iter().collect());
+}
+
 defmt::timestamp!("{=u64:us}", unsafe {
     TIMER_REF.map(|t| t.get_counter().ticks()).unwrap_or(0)
 });
@@ -89,6 +237,7 @@ fn init_allocator() {
 #[entry]
 fn main() -> ! {
     init_allocator();
+    info!("RP-FC {} ({})", version::VERSION, version::GIT_HASH);
 
     let mut pac = pac::Peripherals::take().unwrap();
     let core = pac::CorePeripherals::take().unwrap();
@@ -120,6 +269,12 @@ fn main() -> ! {
         .map_err(|_| "Schedule error for status alarm"));
     critical_section::with(|cs| STATUS_MSG_ALARM.borrow_ref_mut(cs).replace(status_alarm));
 
+    #[cfg(feature = "ext-footswitch")]
+    let ext_switch_alarm = defmt::unwrap!(timer.alarm_1());
+    #[cfg(feature = "soft-debounce")]
+    let buttons_alarm = defmt::unwrap!(timer.alarm_2());
+    let leds_alarm = defmt::unwrap!(timer.alarm_3());
+
     let timer = TIMER.init(timer);
     unsafe { TIMER_REF = Some(timer) };
 
@@ -142,6 +297,12 @@ fn main() -> ! {
         timer
     ));
 
+    let mut config_store = flash_config::open();
+    let mut config = config_store.load().unwrap_or_else(|| {
+        info!("No saved config, using the defaults");
+        Config::default()
+    });
+
     let button_pins = [
         pins.gpio16.reconfigure().into_dyn_pin(),
         pins.gpio17.reconfigure().into_dyn_pin(),
@@ -151,48 +312,441 @@ fn main() -> ! {
         pins.gpio21.reconfigure().into_dyn_pin(),
     ];
 
-    let mut led_group = PinGroup::new()
-        .add_pin(pins.gpio10.into_push_pull_output_in_state(PinState::Low))
-        .add_pin(pins.gpio11.into_push_pull_output_in_state(PinState::Low))
-        .add_pin(pins.gpio12.into_push_pull_output_in_state(PinState::Low))
-        .add_pin(pins.gpio13.into_push_pull_output_in_state(PinState::Low))
-        .add_pin(pins.gpio14.into_push_pull_output_in_state(PinState::Low))
-        .add_pin(pins.gpio15.into_push_pull_output_in_state(PinState::Low));
+    let pwm_slices = pwm::Slices::new(pac.PWM, &mut pac.RESETS);
+    leds::init(
+        leds::LedPwmSlices {
+            pwm5: pwm_slices.pwm5,
+            pwm6: pwm_slices.pwm6,
+            pwm7: pwm_slices.pwm7,
+        },
+        (
+            pins.gpio10,
+            pins.gpio11,
+            pins.gpio12,
+            pins.gpio13,
+            pins.gpio14,
+            pins.gpio15,
+        ),
+        leds_alarm,
+        *timer,
+    );
+    let led_mapping = config.led_mapping.unwrap_or(leds::BOARD_MAPPING);
+    leds::set_mapping(led_mapping);
+    leds::set_global_brightness(config.led_brightness);
+
+    #[cfg(feature = "ext-footswitch")]
+    ext_switch::init(
+        ext_switch_alarm,
+        pins.gpio26.reconfigure().into_dyn_pin(),
+        pins.gpio27.reconfigure().into_dyn_pin(),
+    );
 
-    let (mut pio0, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    #[cfg_attr(feature = "soft-debounce", allow(unused_mut, unused_variables))]
+    let (mut pio0, _sm0, _, _sm2, _) = pac.PIO0.split(&mut pac.RESETS);
 
+    #[cfg(not(feature = "soft-debounce"))]
     unwrap!(buttons::init_buttons::<_, _, 0>(
-        sm0,
+        _sm0,
+        &mut pio0,
+        clocks.system_clock.freq(),
+        button_pins,
+        config.debounce_time_ms,
+        *timer
+    )
+    .map_err(|_| "PIO install error"));
+    #[cfg(feature = "soft-debounce")]
+    buttons::init_buttons_soft(button_pins, config.debounce_time_ms, buttons_alarm, *timer);
+
+    #[cfg(feature = "encoder")]
+    let mut encoder_scroll = {
+        let (mut pio1, sm0, sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+        unwrap!(encoder::init_encoder::<_, _, _, 0>(
+            sm0,
+            sm1,
+            &mut pio1,
+            clocks.system_clock.freq(),
+            [pins.gpio6.reconfigure().into_dyn_pin(), pins.gpio7.reconfigure().into_dyn_pin()],
+            pins.gpio8.reconfigure().into_dyn_pin(),
+        )
+        .map_err(|_| "PIO install error"));
+
+        EncoderScroll::new()
+    };
+
+    #[cfg(feature = "ws2812")]
+    unwrap!(rgb_leds::init(
+        _sm2,
         &mut pio0,
         clocks.system_clock.freq(),
-        button_pins
+        pins.gpio28.reconfigure().into_dyn_pin(),
+        *timer,
     )
     .map_err(|_| "PIO install error"));
+    #[cfg(feature = "ws2812")]
+    rgb_leds::set_mapping(led_mapping);
+    #[cfg(feature = "ws2812")]
+    rgb_leds::set_brightness(config.led_brightness);
+
+    #[cfg(feature = "oled")]
+    let mut display = {
+        use bsp::hal::fugit::RateExtU32;
+        let i2c = bsp::hal::I2C::i2c1(
+            pac.I2C1,
+            pins.gpio2.reconfigure(),
+            pins.gpio3.reconfigure(),
+            400.kHz(),
+            &mut pac.RESETS,
+            clocks.system_clock.freq(),
+        );
+        unwrap!(display::Display::new(i2c).map_err(|_| "Display init error"))
+    };
+
+    #[cfg(feature = "seven-segment")]
+    let mut segment_display = segment_display::SegmentDisplay::new(
+        pins.gpio9.into_push_pull_output_in_state(bsp::hal::gpio::PinState::High).into_pull_type(),
+        pins.gpio22.into_pull_type(),
+        *timer,
+    );
+
+    #[cfg(feature = "din-midi")]
+    unwrap!(din_midi::init(
+        pac.UART0,
+        (
+            pins.gpio0.into_function().into_pull_type::<PullNone>(),
+            pins.gpio1.into_function().into_pull_type::<PullNone>(),
+        ),
+        &mut pac.RESETS,
+        &clocks
+    )
+    .map_err(|_| "DIN MIDI UART init error"));
 
-    let mut next_status_send = timer.now().offset_ms(300);
+    // Takes the USB clock, after all the other uses of `clocks`
+    usb::init(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        &mut pac.RESETS,
+    );
+    let midi_in_mapping = MidiInMapping::default();
+    #[cfg(feature = "din-midi")]
+    let mut last_amp_state = None;
+
+    let mut amp_tracker = AmpTracker::new();
+    let mut press_timer = PressTimer::new();
+    let mut action_mapper = ActionMapper::new();
+    let mut macro_player = MacroPlayer::new();
+    let mut setlist_runner = SetlistRunner::new();
+    let mut tap_tempo = TapTempo::new();
+    let mut tuner = Tuner::new();
+    let mut holds = Holds::new();
+    let mut layer_display_end = None;
+    let mut layer_save_at = None;
+    if config.layer != 0 {
+        show_layer(config.layer);
+        layer_display_end = Some(timer.now().offset_ms(LAYER_DISPLAY_MS));
+    }
+    let mut next_status_send = timer.now().offset_ms(config.status_interval_ms.into());
 
     unsafe {
         pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0);
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_3);
+        #[cfg(not(feature = "soft-debounce"))]
         pac::NVIC::unmask(pac::Interrupt::PIO0_IRQ_0);
+        #[cfg(feature = "encoder")]
+        pac::NVIC::unmask(pac::Interrupt::PIO1_IRQ_0);
         pac::NVIC::unmask(pac::Interrupt::UART1_IRQ);
+        pac::NVIC::unmask(pac::Interrupt::USBCTRL_IRQ);
+        #[cfg(feature = "din-midi")]
+        pac::NVIC::unmask(pac::Interrupt::UART0_IRQ);
+        #[cfg(feature = "ext-footswitch")]
+        pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_1);
+        #[cfg(feature = "soft-debounce")]
+        {
+            pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0);
+            pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_2);
+        }
     }   
 
+    // Let the debouncers report the initial button state, which takes the
+    // debounce time, so the power-up combos work with any debounce setting
+    delay.delay_ms(u32::from(config.debounce_time_ms) + BOOT_BUTTONS_MARGIN_MS);
+    if buttons::current() & buttons::FOOTSWITCH_MASK == BOOTLOADER_BUTTONS {
+        info!("Rebooting to the USB bootloader");
+        bsp::hal::rom_data::reset_to_usb_boot(0, 0);
+    }
+    // In keyboard mode the footswitches send USB key presses instead of
+    // switching the amp
+    let mut keyboard_mode = buttons::current() & buttons::FOOTSWITCH_MASK == KEYBOARD_MODE_BUTTONS;
+    let key_mapping = KeyMapping::default();
+    if keyboard_mode {
+        info!("Keyboard mode");
+    } else if buttons::current() & diagnostics::ENTRY_BUTTONS == diagnostics::ENTRY_BUTTONS {
+        diagnostics::run(&mut ktuart, &mut delay);
+    }
+
     loop {
         // Wait until woken by an interrupt
         // - UART data receive
+        // - USB (MIDI, console)
         // - Button pressed / PIO interrupt
         // - Timer trigger
         cortex_m::asm::wfi();
         trace!("Main loop woke (interrupt)");
+        #[cfg(feature = "ws2812")]
+        rgb_leds::poll();
+
+        let mut action_outputs: heapless::Vec<actions::Output, 32> =
+            action_mapper.poll(config.actions(), timer.now().ticks()).into_iter().collect();
+
+        while let Some(ev) = input::pop_event() {
+            match ev {
+                InputEvent::Buttons(ev) => {
+                    trace!(
+                        "Button event latency: {} us",
+                        timer.now().ticks().saturating_sub(ev.time_us)
+                    );
+                    for (button, duration_us) in press_timer.update(&ev) {
+                        debug!("Button {} released after {} ms", button, duration_us / 1000);
+                    }
+                    // Playing on puts off saving the layer
+                    if layer_save_at.is_some() {
+                        layer_save_at = Some(timer.now().offset_ms(LAYER_SAVE_DELAY_MS));
+                    }
+                    // Another switch stops a running macro or setlist step
+                    if ev.pressed() != 0 && macro_player.is_running() {
+                        debug!("Macro aborted");
+                        macro_player.abort();
+                    }
+                    if ev.pressed() != 0 && setlist_runner.is_running() {
+                        debug!("Setlist step aborted");
+                        setlist_runner.abort();
+                    }
+                    // The actions run in keyboard mode too, for the local
+                    // actions and to keep track of the held switches
+                    _ = action_outputs.extend_from_slice(&action_mapper.update(config.actions(), &ev));
+                    if MODE_COMBO.update(&ev) {
+                        _ = action_outputs.push(actions::Output::Local(LocalAction::KeyboardMode));
+                    } else if keyboard_mode {
+                        usb::set_keys(key_mapping.report(ev.state));
+                    }
+                    for msg in config.midi_out.messages(&ev) {
+                        if !usb::send_midi(msg) {
+                            trace!("USB MIDI message dropped: {}", msg);
+                        }
+                    }
+                    #[cfg(feature = "din-midi")]
+                    for msg in config.din_out.messages(&ev) {
+                        din_midi::send_midi(msg);
+                    }
+                }
+                #[cfg(feature = "encoder")]
+                InputEvent::Encoder(ev) => {
+                    if let Some(input) = encoder_scroll.input(&config.encoder, ev) {
+                        let others = action_mapper.state() | macro_player.state();
+                        for state in holds.apply(HoldSource::Encoder, input, others) {
+                            send_footswitch(&mut ktuart, &mut amp_tracker, state);
+                        }
+                    }
+                }
+                InputEvent::UsbMidi(msg) => {
+                    debug!("USB MIDI in: {}", msg);
+                    if let Some(input) = midi_in_mapping.input(msg) {
+                        let others = action_mapper.state() | macro_player.state();
+                        for state in holds.apply(HoldSource::UsbMidi, input, others) {
+                            send_footswitch(&mut ktuart, &mut amp_tracker, state);
+                        }
+                    }
+                }
+                #[cfg(feature = "din-midi")]
+                InputEvent::DinMidi(msg) => {
+                    debug!("DIN MIDI in: {}", msg);
+                    if let Some(input) = config.din_in.input(msg) {
+                        let others = action_mapper.state() | macro_player.state();
+                        for state in holds.apply(HoldSource::DinMidi, input, others) {
+                            send_footswitch(&mut ktuart, &mut amp_tracker, state);
+                        }
+                    }
+                }
+            }
+        }
 
-        while let Some(ch) = buttons::pop_change_queue() {
-            ktuart.enqueue_send(katana_sysex::footswitch_change(ch).into_iter().collect());
+        for out in action_outputs {
+            match out {
+                actions::Output::Footswitch(state) => {
+                    if !keyboard_mode {
+                        send_footswitch(&mut ktuart, &mut amp_tracker, state | holds.state());
+                    }
+                }
+                actions::Output::Local(LocalAction::KeyboardMode) => {
+                    keyboard_mode = !keyboard_mode;
+                    info!("Keyboard mode: {}", keyboard_mode);
+                    macro_player.abort();
+                    usb::set_keys(KeyReport::default());
+                    // Release the amp's footswitches when leaving amp mode
+                    // and pick up the held ones when returning
+                    let state = if keyboard_mode {
+                        0
+                    } else {
+                        action_mapper.state() | holds.state()
+                    };
+                    send_footswitch(&mut ktuart, &mut amp_tracker, state);
+                }
+                actions::Output::Local(LocalAction::NextLayer) => {
+                    config.layer = (config.layer + 1) % actions::LAYER_COUNT as u8;
+                    info!("Layer {}", config.layer + 1);
+                    // The tap switch may do something else on the new layer
+                    leds::clear_tempo();
+                    #[cfg(feature = "ws2812")]
+                    rgb_leds::clear_tempo();
+                    show_layer(config.layer);
+                    layer_display_end = Some(timer.now().offset_ms(LAYER_DISPLAY_MS));
+                    layer_save_at = Some(timer.now().offset_ms(LAYER_SAVE_DELAY_MS));
+                }
+                actions::Output::Local(LocalAction::Tuner) => {
+                    if !keyboard_mode {
+                        let held = action_mapper.state() | holds.state();
+                        let states = tuner.toggle(config.tuner_footswitches, held);
+                        for state in states {
+                            send_footswitch(&mut ktuart, &mut amp_tracker, state);
+                        }
+                        info!("Tuner mode: {}", tuner.is_on());
+                        layer_display_end = None;
+                        show_tuner(tuner.is_on());
+                    }
+                }
+                actions::Output::Local(l @ (LocalAction::SetlistNext | LocalAction::SetlistPrev)) => {
+                    if !keyboard_mode {
+                        if l == LocalAction::SetlistNext {
+                            setlist_runner.next(&config.setlist);
+                        } else {
+                            setlist_runner.prev(&config.setlist);
+                        }
+                        if let Some(p) = setlist_runner.position() {
+                            info!("Setlist step {}", p + 1);
+                        }
+                    }
+                }
+                // Comes as `Output::TapTempo`, with the switch and the time
+                actions::Output::Local(LocalAction::TapTempo) => {}
+                // The GA-FC link only carries footswitch states, so the
+                // amp's delay time can't be set from here. The tempo is
+                // shown on the tap switch's LED and sent over MIDI.
+                actions::Output::TapTempo { switch, time_us } => {
+                    match tap_tempo.tap(time_us) {
+                        Some(interval_us) => {
+                            let bpm = tap_tempo.bpm().unwrap_or(0);
+                            info!("Tap tempo: {} BPM ({} ms)", bpm, interval_us / 1000);
+                            let pattern = LedPattern::Beat {
+                                period_ms: (interval_us / 1000) as u16,
+                                start_ms: (time_us / 1000) as u32,
+                            };
+                            leds::show_tempo(switch, pattern);
+                            #[cfg(feature = "ws2812")]
+                            rgb_leds::show_tempo(switch, pattern);
+                            for msg in config.midi_out.tempo_messages(bpm) {
+                                if !usb::send_midi(msg) {
+                                    trace!("USB MIDI message dropped: {}", msg);
+                                }
+                            }
+                            #[cfg(feature = "din-midi")]
+                            for msg in config.din_out.tempo_messages(bpm) {
+                                din_midi::send_midi(msg);
+                            }
+                            #[cfg(feature = "oled")]
+                            {
+                                use core::fmt::Write;
+                                let mut text: heapless::String<16> = heapless::String::new();
+                                _ = core::write!(text, "TEMPO {} BPM", bpm);
+                                let until_ms = timer.now().ticks() / 1000 + TEMPO_MESSAGE_MS;
+                                display.view.show_message(&text, until_ms);
+                            }
+                        }
+                        // The first tap after a long pause starts over
+                        None if tap_tempo.interval_us().is_none() => {
+                            leds::clear_tempo();
+                            #[cfg(feature = "ws2812")]
+                            rgb_leds::clear_tempo();
+                        }
+                        None => {}
+                    }
+                }
+                actions::Output::Macro(i) => {
+                    if !keyboard_mode {
+                        debug!("Macro {}", i + 1);
+                        let held = action_mapper.state() | holds.state();
+                        macro_player.start(&config.macros[usize::from(i)], held);
+                    }
+                }
+            }
+        }
+
+        while let Some(cmd) = console::pop_command() {
+            console::execute(
+                cmd,
+                console::Context {
+                    config: &mut config,
+                    config_store: &mut config_store,
+                    link_stats: ktuart.stats(),
+                    timer,
+                },
+            );
+        }
+
+        if layer_display_end.is_some_and(|t| timer.has_passed(t)) {
+            layer_display_end = None;
+            // Back to the tuner display if the layer changed in tuner mode
+            show_tuner(tuner.is_on());
+        }
+
+        // Only with no switch held and nothing going on with the amp, as the
+        // flash write stops everything for tens of milliseconds
+        if layer_save_at.is_some_and(|t| timer.has_passed(t))
+            && buttons::current() == 0
+            && ktuart.is_idle()
+            && !macro_player.is_running()
+            && !setlist_runner.is_running()
+        {
+            layer_save_at = None;
+            // Only the layer, the other settings are saved with the `save`
+            // console command
+            let mut saved = config_store.load().unwrap_or_default();
+            saved.layer = config.layer;
+            if let Err(e) = config_store.save(&saved) {
+                error!("Saving the layer failed: {}", e);
+            }
         }
 
         if timer.has_passed(next_status_send) {
-            let btn = buttons::current();
+            let btn = if keyboard_mode {
+                0
+            } else {
+                action_mapper.state() | macro_player.state() | holds.state()
+            };
             ktuart.enqueue_send(katana_sysex::status(btn).into_iter().collect());
-            next_status_send = next_status_send.offset_ms(300);
+            let interval = if keyboard_mode && !ktuart.link_up() {
+                IDLE_STATUS_INTERVAL_MS
+            } else {
+                config.status_interval_ms.into()
+            };
+            next_status_send = next_status_send.offset_ms(interval);
+        }
+
+        // One macro step per echo/reply cycle, so the amp sees each state.
+        // The step delays are checked on the 100 ms status alarm wake-ups at
+        // the latest.
+        if let Some(state) = macro_player.poll(timer.now().ticks() / 1000, ktuart.is_idle()) {
+            send_footswitch(&mut ktuart, &mut amp_tracker, state | holds.state());
+        }
+
+        match setlist_runner.poll(timer.now().ticks() / 1000) {
+            Some(Progress::Tap(m)) => {
+                let state = action_mapper.state() | holds.state();
+                send_footswitch(&mut ktuart, &mut amp_tracker, state | m);
+                send_footswitch(&mut ktuart, &mut amp_tracker, state);
+            }
+            Some(Progress::Done) => info!("Setlist step reached"),
+            Some(Progress::Failed) => warn!("The amp didn't get to the setlist step"),
+            None => {}
         }
 
         ktuart.tick(&mut delay);
@@ -201,11 +755,42 @@ fn main() -> ! {
             match rx.led_status() {
                 Some(led_status) => {
                     defmt::info!("New LED status: {:02x}", led_status);
-                    led_group.set_u32((led_status as u32) << 10);
+                    amp_tracker.on_led_status(led_status);
+                    leds::set_status(led_status);
+                    #[cfg(feature = "ws2812")]
+                    rgb_leds::set_status(led_status);
+                    if let Some(&amp) = amp_tracker.state() {
+                        setlist_runner.on_status(amp, timer.now().ticks() / 1000);
+                        #[cfg(feature = "encoder")]
+                        encoder_scroll.follow(&config.encoder, &amp);
+                        #[cfg(feature = "din-midi")]
+                        {
+                            let msgs = config.status_midi.messages(last_amp_state.as_ref(), &amp);
+                            for msg in msgs {
+                                din_midi::send_midi(msg);
+                            }
+                            last_amp_state = Some(amp);
+                        }
+                    }
                 },
-                None => defmt::warn!("Got an unknown msg: {}", rx)
+                None => defmt::warn!("Got an unknown msg: {}", rx),
             }
         }
+
+        // Also shows the predicted effect of the footswitch changes sent,
+        // which the amp confirms in its next LED status
+        #[cfg(feature = "oled")]
+        {
+            display.view.amp = amp_tracker.state().copied();
+        }
+        #[cfg(feature = "seven-segment")]
+        segment_display.show_status(amp_tracker.state());
+
+        #[cfg(feature = "oled")]
+        {
+            display.view.link_up = ktuart.link_up();
+            display.poll(timer.now().ticks() / 1000);
+        }
     }
 }
 
@@ -0,0 +1,252 @@
+//! WS2812 RGB LEDs, one per footswitch, chained on a single data pin.
+//!
+//! The LEDs are driven by a PIO state machine, so writing the colours only
+//! takes a few FIFO writes, after waiting for the previous frame to latch if
+//! it was just written. The amp's LED status is shown with the colours
+//! of a [`Palette`], through the same kind of [`LedMapping`] as the PWM LEDs.
+//! The tap tempo flashes are timed by [`poll`], from the main loop.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use critical_section::Mutex;
+use rp_fc_core::led::LedPattern;
+pub use rp_fc_core::led_map::LedMapping;
+pub use rp_fc_core::ws2812::Rgb;
+use rp_fc_core::ws2812::Palette;
+use rp_fc_core::ws2812;
+use rp2040_hal::{
+    fugit::HertzU32,
+    gpio::{DynPinId, Pin, PullNone},
+    pio::{
+        Buffers, InstallError, PIOBuilder, PIOExt, PinDir, ShiftDirection, StateMachineIndex, Tx,
+        UninitStateMachine, ValidStateMachine, PIO,
+    },
+    timer,
+};
+
+pub use rp_fc_core::led_map::LED_COUNT;
+
+/// Brightness at the full brightness setting, as driving the LEDs fully is
+/// blinding right under the switches
+const MAX_BRIGHTNESS: u8 = 64;
+/// Colour of the tap tempo flashes, between which the LED shows the status
+const TEMPO_COLOUR: Rgb = Rgb::new(255, 255, 255);
+/// A frame takes 30 µs per LED on the wire, and the LEDs latch it once the
+/// line has been idle for 50 µs. A new frame written before that would run
+/// on past the last LED and be lost.
+const FRAME_US: u64 = 30 * LED_COUNT as u64 + 50 + 20;
+
+trait PioFifoWrite {
+    fn write(&mut self, value: u32) -> bool;
+}
+
+impl<SM: ValidStateMachine> PioFifoWrite for Tx<SM> {
+    fn write(&mut self, value: u32) -> bool {
+        Tx::write(self, value)
+    }
+}
+
+struct RgbLeds {
+    tx: Box<dyn PioFifoWrite + Send>,
+    palette: Palette,
+    mapping: LedMapping<LED_COUNT>,
+    /// The LED brightness setting, 0-255
+    brightness: u8,
+    status: u8,
+    /// Colours shown instead of the status
+    overlay: Option<[Rgb; LED_COUNT]>,
+    /// The tap switch and its tempo pattern
+    tempo: Option<(u8, LedPattern)>,
+    /// Whether the last frame showed a tempo flash
+    tempo_lit: bool,
+    timer: timer::Timer,
+    /// When the last frame was written to the FIFO
+    written_at_us: u64,
+}
+
+static RGB_LEDS: Mutex<RefCell<Option<RgbLeds>>> = Mutex::new(RefCell::new(None));
+
+/// Installs and starts the WS2812 program on a state machine, with all the
+/// LEDs off.
+pub fn init<P: PIOExt + 'static, SM: StateMachineIndex + 'static>(
+    sm: UninitStateMachine<(P, SM)>,
+    pio: &mut PIO<P>,
+    sys_freq: HertzU32,
+    pin: Pin<DynPinId, P::PinFunction, PullNone>,
+    timer: timer::Timer,
+) -> Result<(), InstallError> {
+    let pin_id = pin.id().num;
+    let prog = ws2812::program();
+    let (clk_div_int, clk_div_frac) = prog.clock_divisor(sys_freq.to_Hz(), ws2812::BIT_RATE_HZ);
+
+    let installed = pio.install(&prog.program)?;
+
+    let (mut sm, _rx, tx) = PIOBuilder::from_installed_program(installed)
+        .side_set_pin_base(pin_id)
+        .out_shift_direction(ShiftDirection::Left)
+        .autopull(true)
+        .pull_threshold(24)
+        // The colours for all the LEDs fit in the joined FIFO
+        .buffers(Buffers::OnlyTx)
+        .clock_divisor_fixed_point(clk_div_int, clk_div_frac)
+        .build(sm);
+
+    sm.set_pindirs([(pin_id, PinDir::Output)]);
+    let _sm = sm.start();
+
+    critical_section::with(|cs| {
+        let mut leds = RgbLeds {
+            tx: Box::new(tx),
+            palette: Palette::default(),
+            mapping: LedMapping::identity(),
+            brightness: 255,
+            status: 0,
+            overlay: None,
+            tempo: None,
+            tempo_lit: false,
+            timer,
+            written_at_us: 0,
+        };
+        let frame = leds.frame();
+        leds.push(frame);
+        RGB_LEDS.borrow(cs).replace(Some(leds));
+    });
+
+    Ok(())
+}
+
+/// Shows an LED status byte from the amp.
+///
+/// A frame takes about 0.25 ms to send and latch, and an update right after
+/// another waits for that, with interrupts enabled.
+pub fn set_status(status: u8) {
+    update(|leds| leds.status = status);
+}
+
+/// Shows the given colours until the next update
+pub fn set_colours(colours: [Rgb; LED_COUNT]) {
+    let frame = critical_section::with(|cs| {
+        RGB_LEDS.borrow_ref(cs).as_ref().map(|leds| leds.words(colours))
+    });
+    if let Some(frame) = frame {
+        write(frame);
+    }
+}
+
+/// Shows the colours until `clear_overlay`. Status updates meanwhile are
+/// shown after that.
+pub fn show_overlay(colours: [Rgb; LED_COUNT]) {
+    update(|leds| leds.overlay = Some(colours));
+}
+
+/// Goes back to showing the status
+pub fn clear_overlay() {
+    update(|leds| leds.overlay = None);
+}
+
+pub fn set_mapping(mapping: LedMapping<LED_COUNT>) {
+    update(|leds| leds.mapping = mapping);
+}
+
+/// Sets the brightness scaling (0-255), like for the PWM LEDs
+pub fn set_brightness(brightness: u8) {
+    update(|leds| leds.brightness = brightness);
+}
+
+/// Flashes a footswitch's LED with a tap tempo pattern. The ext switches have
+/// no LED, so nothing shows their tempo.
+pub fn show_tempo(switch: u8, pattern: LedPattern) {
+    update(|leds| leds.tempo = Some((switch, pattern)));
+}
+
+/// Goes back to showing just the status on the tap tempo LED
+pub fn clear_tempo() {
+    update(|leds| leds.tempo = None);
+}
+
+/// Starts and ends the tap tempo flashes. Called from the main loop, which
+/// the PWM LED alarm wakes up every few milliseconds while the tempo shows.
+pub fn poll() {
+    let frame = critical_section::with(|cs| {
+        let mut leds = RGB_LEDS.borrow_ref_mut(cs);
+        let leds = leds.as_mut()?;
+        (leds.tempo_flash() != leds.tempo_lit).then(|| leds.frame())
+    });
+    if let Some(frame) = frame {
+        write(frame);
+    }
+}
+
+fn update(f: impl FnOnce(&mut RgbLeds)) {
+    let frame = critical_section::with(|cs| {
+        let mut leds = RGB_LEDS.borrow_ref_mut(cs);
+        let leds = leds.as_mut()?;
+        f(leds);
+        Some(leds.frame())
+    });
+    if let Some(frame) = frame {
+        write(frame);
+    }
+}
+
+/// Writes a frame once the previous one has gone out and latched, after
+/// which the FIFO is empty and takes the whole frame. The wait is outside
+/// the critical section, so it doesn't hold up the interrupts.
+fn write(frame: [u32; LED_COUNT]) {
+    loop {
+        let written = critical_section::with(|cs| {
+            let mut leds = RGB_LEDS.borrow_ref_mut(cs);
+            let Some(leds) = leds.as_mut() else {
+                return true;
+            };
+            if leds.timer.get_counter().ticks() < leds.written_at_us + FRAME_US {
+                return false;
+            }
+            leds.push(frame);
+            true
+        });
+        if written {
+            return;
+        }
+    }
+}
+
+impl RgbLeds {
+    /// The FIFO words for what's shown now
+    fn frame(&mut self) -> [u32; LED_COUNT] {
+        let mut colours = self
+            .overlay
+            .unwrap_or_else(|| self.mapping.colours(self.status, &self.palette));
+        self.tempo_lit = self.tempo_flash();
+        if let (true, Some((switch, _))) = (self.tempo_lit, self.tempo) {
+            colours[switch as usize] = TEMPO_COLOUR;
+        }
+        self.words(colours)
+    }
+
+    fn words(&self, colours: [Rgb; LED_COUNT]) -> [u32; LED_COUNT] {
+        colours.map(|c| c.dimmed(MAX_BRIGHTNESS).dimmed(self.brightness).grb_word())
+    }
+
+    /// Whether the tap switch's LED should show a tempo flash now
+    fn tempo_flash(&self) -> bool {
+        match self.tempo {
+            Some((switch, pattern)) if self.overlay.is_none() && (switch as usize) < LED_COUNT => {
+                let time_ms = (self.timer.get_counter().ticks() / 1000) as u32;
+                pattern.level(time_ms) > 0
+            }
+            _ => false,
+        }
+    }
+
+    fn push(&mut self, frame: [u32; LED_COUNT]) {
+        for word in frame {
+            if !self.tx.write(word) {
+                defmt::warn!("WS2812 FIFO full, colours dropped");
+            }
+        }
+        self.written_at_us = self.timer.get_counter().ticks();
+    }
+}
@@ -0,0 +1,46 @@
+//! Two-digit seven-segment display on a TM1637 module, showing the amp's
+//! bank and channel.
+//!
+//! CLK is on GPIO9 and DIO on GPIO22. A write takes about half a
+//! millisecond of bit-banging, and only happens when the digits change.
+
+use rp2040_hal::{
+    gpio::{bank0, FunctionNull, FunctionSioOutput, InOutPin, Pin, PullUp},
+    timer,
+};
+use rp_fc_core::{amp_state::AmpState, seven_segment, tm1637::Tm1637};
+
+type ClkPin = Pin<bank0::Gpio9, FunctionSioOutput, PullUp>;
+type DioPin = InOutPin<Pin<bank0::Gpio22, FunctionNull, PullUp>>;
+
+pub struct SegmentDisplay {
+    tm: Tm1637<ClkPin, DioPin, timer::Timer>,
+    shown: Option<[u8; 2]>,
+}
+
+impl SegmentDisplay {
+    pub fn new(
+        clk: ClkPin,
+        dio: Pin<bank0::Gpio22, FunctionNull, PullUp>,
+        timer: timer::Timer,
+    ) -> Self {
+        let mut display = SegmentDisplay {
+            tm: Tm1637::new(clk, InOutPin::new(dio), timer),
+            shown: None,
+        };
+        display.show_status(None);
+        display
+    }
+
+    /// Shows the amp's bank and channel, or dashes before the first status
+    pub fn show_status(&mut self, state: Option<&AmpState>) {
+        let digits = seven_segment::status_digits(state);
+        if self.shown == Some(digits) {
+            return;
+        }
+        match self.tm.write(&digits) {
+            Ok(()) => self.shown = Some(digits),
+            Err(e) => defmt::warn!("Seven-segment display write error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! USB device with a MIDI interface, for using the footswitches as a MIDI
+//! controller when connected to a computer, and for switching the amp from
+//! a DAW or sequencer. A CDC-ACM serial port carries the configuration
+//! console, and HID keyboard and media key interfaces are used in keyboard
+//! mode.
+//!
+//! The device is polled from `USBCTRL_IRQ`, which also queues the received
+//! MIDI messages and console input. Without a USB host (e.g. when powered by
+//! the amp) nothing is sent.
+
+use core::cell::RefCell;
+use critical_section::{CriticalSection, Mutex};
+use heapless::Deque;
+use rp2040_hal::{clocks::UsbClock, pac, usb::UsbBus};
+use rp_fc_core::{hid::KeyReport, midi::MidiMessage};
+use crate::console;
+use static_cell::StaticCell;
+use usb_device::{class_prelude::UsbBusAllocator, prelude::*};
+use usbd_midi::midi_device::MidiClass;
+use usbd_hid::{
+    descriptor::{KeyboardReport, MediaKeyboardReport, SerializedDescriptor},
+    hid_class::HIDClass,
+};
+use usbd_serial::SerialPort;
+
+// The shared V-USB VID/PID for MIDI devices
+const USB_VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x05e4);
+
+static USB_BUS: StaticCell<UsbBusAllocator<UsbBus>> = StaticCell::new();
+static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
+static MIDI_IN_QUEUE: Mutex<RefCell<Deque<MidiMessage, 16>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+struct Usb {
+    device: UsbDevice<'static, UsbBus>,
+    midi: MidiClass<'static, UsbBus>,
+    serial: SerialPort<'static, UsbBus>,
+    keyboard: HIDClass<'static, UsbBus>,
+    media: HIDClass<'static, UsbBus>,
+    keys: KeyReport,
+    /// Keyboard and media reports not sent yet
+    keys_pending: (bool, bool),
+}
+
+/// Starts the USB device. `on_interrupt` must be called from `USBCTRL_IRQ`.
+pub fn init(
+    regs: pac::USBCTRL_REGS,
+    dpram: pac::USBCTRL_DPRAM,
+    usb_clock: UsbClock,
+    resets: &mut pac::RESETS,
+) {
+    let bus = USB_BUS.init(UsbBusAllocator::new(UsbBus::new(
+        regs, dpram, usb_clock, true, resets,
+    )));
+
+    let midi = defmt::unwrap!(MidiClass::new(bus, 1, 1).map_err(|_| "USB MIDI init error"));
+    let serial = SerialPort::new(bus);
+    let keyboard = HIDClass::new(bus, KeyboardReport::desc(), 10);
+    let media = HIDClass::new(bus, MediaKeyboardReport::desc(), 10);
+    let device = defmt::unwrap!(UsbDeviceBuilder::new(bus, USB_VID_PID)
+        .strings(&[StringDescriptors::default()
+            .manufacturer("RP-FC")
+            .product("RP-FC Foot Controller")])
+        .map_err(|_| "USB descriptor error"))
+    .composite_with_iads()
+    .build();
+
+    critical_section::with(|cs| {
+        USB.borrow(cs).replace(Some(Usb {
+            device,
+            midi,
+            serial,
+            keyboard,
+            media,
+            keys: KeyReport::default(),
+            keys_pending: (false, false),
+        }))
+    });
+}
+
+pub fn on_interrupt() {
+    critical_section::with(|cs| {
+        if let Some(usb) = USB.borrow_ref_mut(cs).as_mut() {
+            if usb.device.poll(&mut [
+                &mut usb.midi,
+                &mut usb.serial,
+                &mut usb.keyboard,
+                &mut usb.media,
+            ]) {
+                read_midi(cs, &mut usb.midi);
+                read_console(cs, &mut usb.serial);
+            }
+            write_console(cs, &mut usb.serial);
+            send_keys(usb);
+        }
+    })
+}
+
+fn read_console(cs: CriticalSection, serial: &mut SerialPort<'static, UsbBus>) {
+    let mut buf = [0u8; 64];
+    if let Ok(len) = serial.read(&mut buf) {
+        console::on_input(cs, &buf[..len]);
+    }
+}
+
+fn write_console(cs: CriticalSection, serial: &mut SerialPort<'static, UsbBus>) {
+    let mut output = console::OUTPUT.borrow_ref_mut(cs);
+    while !output.is_empty() {
+        // Fails with WouldBlock when the serial port's buffer is full
+        let Ok(len) = serial.write(output.as_slices().0) else {
+            break;
+        };
+        for _ in 0..len {
+            output.pop_front();
+        }
+    }
+    _ = serial.flush();
+}
+
+/// Starts sending the queued console output
+pub fn flush_console() {
+    critical_section::with(|cs| {
+        if let Some(usb) = USB.borrow_ref_mut(cs).as_mut() {
+            write_console(cs, &mut usb.serial);
+        }
+    })
+}
+
+fn read_midi(cs: CriticalSection, midi: &mut MidiClass<'static, UsbBus>) {
+    let mut buf = [0u8; 64];
+    let Ok(len) = midi.read(&mut buf) else {
+        return;
+    };
+    let mut queue = MIDI_IN_QUEUE.borrow_ref_mut(cs);
+    for packet in buf[..len].chunks_exact(4) {
+        let Some(msg) = MidiMessage::from_usb_packet([packet[0], packet[1], packet[2], packet[3]])
+        else {
+            continue;
+        };
+        if queue.push_back(msg).is_err() {
+            defmt::warn!("MIDI_IN_QUEUE full, message dropped");
+        }
+    }
+}
+
+/// Pops the next MIDI message received from the host
+pub fn pop_midi() -> Option<MidiMessage> {
+    critical_section::with(|cs| MIDI_IN_QUEUE.borrow_ref_mut(cs).pop_front())
+}
+
+/// Sends a MIDI message to the host. Returns false if it was dropped
+/// because no host is connected or the endpoint is busy.
+pub fn send_midi(msg: MidiMessage) -> bool {
+    critical_section::with(|cs| {
+        let mut usb = USB.borrow_ref_mut(cs);
+        let Some(usb) = usb.as_mut() else {
+            return false;
+        };
+        if usb.device.state() != UsbDeviceState::Configured {
+            return false;
+        }
+        usb.midi.send_bytes(msg.to_usb_packet()).is_ok()
+    })
+}
+
+/// Sets the held keys. The reports are resent from the interrupt until the
+/// host takes them, so key releases aren't lost.
+pub fn set_keys(keys: KeyReport) {
+    critical_section::with(|cs| {
+        if let Some(usb) = USB.borrow_ref_mut(cs).as_mut() {
+            usb.keys_pending.0 |= keys.modifiers != usb.keys.modifiers || keys.keys != usb.keys.keys;
+            usb.keys_pending.1 |= keys.media != usb.keys.media;
+            usb.keys = keys;
+            send_keys(usb);
+        }
+    })
+}
+
+fn send_keys(usb: &mut Usb) {
+    if usb.device.state() != UsbDeviceState::Configured {
+        return;
+    }
+    if usb.keys_pending.0 {
+        let report = KeyboardReport {
+            modifier: usb.keys.modifiers,
+            reserved: 0,
+            leds: 0,
+            keycodes: usb.keys.keys,
+        };
+        usb.keys_pending.0 = usb.keyboard.push_input(&report).is_err();
+    }
+    if usb.keys_pending.1 {
+        let report = MediaKeyboardReport {
+            usage_id: usb.keys.media,
+        };
+        usb.keys_pending.1 = usb.media.push_input(&report).is_err();
+    }
+}
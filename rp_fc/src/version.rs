@@ -0,0 +1,5 @@
+//! Firmware version and build information.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the firmware was built from, set by `build.rs`
+pub const GIT_HASH: &str = env!("RP_FC_GIT_HASH");
@@ -0,0 +1,311 @@
+use crate::button_event::ButtonEvent;
+use defmt::Format;
+use katana_sysex::footswitch;
+
+/// How long a switch is held for its long press action
+pub const LONG_PRESS_US: u64 = 600_000;
+
+/// Number of layers, each with its own action table
+pub const LAYER_COUNT: usize = 3;
+
+/// Something done on the device instead of on the amp
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum LocalAction {
+    /// Switches between amp and keyboard mode
+    KeyboardMode,
+    /// Switches to the next layer, from the last back to the first
+    NextLayer,
+    /// Goes to the next setlist step
+    SetlistNext,
+    /// Goes to the previous setlist step
+    SetlistPrev,
+    /// Taps a tempo
+    TapTempo,
+    /// Turns tuner mode on or off
+    Tuner,
+}
+
+impl LocalAction {
+    /// Stored ID, these must not change
+    pub fn id(&self) -> u8 {
+        match self {
+            LocalAction::KeyboardMode => 0,
+            LocalAction::NextLayer => 1,
+            LocalAction::SetlistNext => 2,
+            LocalAction::SetlistPrev => 3,
+            LocalAction::TapTempo => 4,
+            LocalAction::Tuner => 5,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(LocalAction::KeyboardMode),
+            1 => Some(LocalAction::NextLayer),
+            2 => Some(LocalAction::SetlistNext),
+            3 => Some(LocalAction::SetlistPrev),
+            4 => Some(LocalAction::TapTempo),
+            5 => Some(LocalAction::Tuner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Action {
+    None,
+    /// GA-FC footswitches (bit N is switch N + 1). As a press action they
+    /// are held down as long as the switch is, as a long press action they
+    /// are tapped.
+    Footswitch(u8),
+    /// GA-FC footswitch combinations tapped one after another, unused
+    /// entries are 0
+    Sequence([u8; 4]),
+    Local(LocalAction),
+    /// Plays the macro with this index
+    Macro(u8),
+}
+
+impl Action {
+    /// Whether the GA-FC switches of the action exist on a GA-FC
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Action::Footswitch(m) => m & !footswitch::ALL == 0,
+            Action::Sequence(steps) => steps.iter().all(|m| m & !footswitch::ALL == 0),
+            _ => true,
+        }
+    }
+}
+
+/// Actions of a physical switch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct SwitchActions {
+    pub press: Action,
+    /// If set, the press action waits for the release and is only done if
+    /// the switch was released before `LONG_PRESS_US`
+    pub long_press: Action,
+}
+
+/// Actions of the physical switches, switch N is button state bit N
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct ActionTable {
+    pub switches: [SwitchActions; 8],
+}
+
+impl Default for ActionTable {
+    /// Each switch holds the same GA-FC switch. The GA-FC has six, so the
+    /// other switches (the external footswitch) do nothing on the amp.
+    fn default() -> Self {
+        let mut switches = [SwitchActions {
+            press: Action::None,
+            long_press: Action::None,
+        }; 8];
+        for (i, s) in switches.iter_mut().enumerate() {
+            if 1 << i & footswitch::ALL != 0 {
+                s.press = Action::Footswitch(1 << i);
+            }
+        }
+        ActionTable { switches }
+    }
+}
+
+/// What to do for the actions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Output {
+    /// New GA-FC footswitch state to send to the amp
+    Footswitch(u8),
+    Local(LocalAction),
+    Macro(u8),
+    /// A tap tempo tap on a physical switch, at the time of the button
+    /// event or when the long press time was reached
+    TapTempo { switch: u8, time_us: u64 },
+}
+
+pub type Outputs = heapless::Vec<Output, 16>;
+
+/// Turns button events into actions from an action table
+#[derive(Default)]
+pub struct ActionMapper {
+    /// GA-FC footswitches held down by each physical switch
+    held: [u8; 8],
+    /// Press times of the switches waiting for a release or a long press
+    pending: [Option<u64>; 8],
+}
+
+impl ActionMapper {
+    pub const fn new() -> Self {
+        ActionMapper {
+            held: [0; 8],
+            pending: [None; 8],
+        }
+    }
+
+    /// GA-FC footswitches currently held down
+    pub fn state(&self) -> u8 {
+        self.held.iter().fold(0, |s, h| s | h)
+    }
+
+    pub fn update(&mut self, table: &ActionTable, ev: &ButtonEvent) -> Outputs {
+        let mut out = Outputs::new();
+        let prev_state = self.state();
+
+        for (i, actions) in table.switches.iter().enumerate() {
+            let bit = 1 << i;
+            if ev.pressed() & bit != 0 {
+                if actions.long_press != Action::None {
+                    self.pending[i] = Some(ev.time_us);
+                } else if let Action::Footswitch(m) = actions.press {
+                    self.held[i] = m;
+                } else {
+                    self.run(actions.press, i, ev.time_us, &mut out);
+                }
+            } else if ev.released() & bit != 0 {
+                if self.pending[i].take().is_some() {
+                    self.run(actions.press, i, ev.time_us, &mut out);
+                }
+                self.held[i] = 0;
+            }
+        }
+
+        if self.state() != prev_state {
+            _ = out.push(Output::Footswitch(self.state()));
+        }
+        out
+    }
+
+    /// Does the long press actions of the switches held long enough
+    pub fn poll(&mut self, table: &ActionTable, now_us: u64) -> Outputs {
+        let mut out = Outputs::new();
+        for (i, actions) in table.switches.iter().enumerate() {
+            if let Some(start) = self.pending[i] {
+                if now_us.saturating_sub(start) >= LONG_PRESS_US {
+                    self.pending[i] = None;
+                    self.run(actions.long_press, i, start + LONG_PRESS_US, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    /// Does an action of a switch once: footswitches are tapped
+    fn run(&self, action: Action, switch: usize, time_us: u64, out: &mut Outputs) {
+        let state = self.state();
+        let mut tap = |m: u8| {
+            _ = out.push(Output::Footswitch(state | m));
+            _ = out.push(Output::Footswitch(state));
+        };
+        match action {
+            Action::None => {}
+            Action::Footswitch(m) => tap(m),
+            Action::Sequence(steps) => steps.into_iter().filter(|&m| m != 0).for_each(tap),
+            Action::Local(LocalAction::TapTempo) => {
+                _ = out.push(Output::TapTempo {
+                    switch: switch as u8,
+                    time_us,
+                })
+            }
+            Action::Local(l) => _ = out.push(Output::Local(l)),
+            Action::Macro(i) => _ = out.push(Output::Macro(i)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, ActionMapper, ActionTable, LocalAction, Output, LONG_PRESS_US};
+    use crate::button_event::ButtonEvent;
+
+    fn ev(time_us: u64, prev_state: u8, state: u8) -> ButtonEvent {
+        ButtonEvent {
+            time_us,
+            state,
+            prev_state,
+        }
+    }
+
+    #[test]
+    fn test_default_table() {
+        let t = ActionTable::default();
+        let mut m = ActionMapper::new();
+        assert_eq!(&m.update(&t, &ev(0, 0, 0b01))[..], &[Output::Footswitch(0b01)]);
+        assert_eq!(&m.update(&t, &ev(0, 0b01, 0b11))[..], &[Output::Footswitch(0b11)]);
+        assert_eq!(&m.update(&t, &ev(0, 0b11, 0b10))[..], &[Output::Footswitch(0b10)]);
+        assert_eq!(&m.update(&t, &ev(0, 0b10, 0))[..], &[Output::Footswitch(0)]);
+        // No GA-FC switches for the external footswitch
+        assert!(m.update(&t, &ev(0, 0, 0xc0)).is_empty());
+        assert!(t.switches.iter().all(|s| s.press.is_valid()));
+        assert!(!Action::Sequence([0x01, 0x40, 0, 0]).is_valid());
+    }
+
+    #[test]
+    fn test_remap_and_sequence() {
+        let mut t = ActionTable::default();
+        // Switch 1 holds GA-FC switch 6, switch 2 switches the bank and
+        // selects channel 1
+        t.switches[0].press = Action::Footswitch(0x20);
+        t.switches[1].press = Action::Sequence([0x10, 0x01, 0, 0]);
+        let mut m = ActionMapper::new();
+
+        assert_eq!(&m.update(&t, &ev(0, 0, 0b01))[..], &[Output::Footswitch(0x20)]);
+        assert_eq!(
+            &m.update(&t, &ev(0, 0b01, 0b11))[..],
+            &[
+                Output::Footswitch(0x30),
+                Output::Footswitch(0x20),
+                Output::Footswitch(0x21),
+                Output::Footswitch(0x20),
+            ]
+        );
+        assert!(m.update(&t, &ev(0, 0b11, 0b01)).is_empty());
+    }
+
+    #[test]
+    fn test_long_press() {
+        let mut t = ActionTable::default();
+        t.switches[2].long_press = Action::Local(LocalAction::KeyboardMode);
+        let mut m = ActionMapper::new();
+
+        // Short press taps the press action on release
+        assert!(m.update(&t, &ev(0, 0, 0b100)).is_empty());
+        assert!(m.poll(&t, LONG_PRESS_US - 1).is_empty());
+        assert_eq!(
+            &m.update(&t, &ev(LONG_PRESS_US - 1, 0b100, 0))[..],
+            &[Output::Footswitch(0b100), Output::Footswitch(0)]
+        );
+
+        // Long press
+        assert!(m.update(&t, &ev(0, 0, 0b100)).is_empty());
+        assert_eq!(
+            &m.poll(&t, LONG_PRESS_US)[..],
+            &[Output::Local(LocalAction::KeyboardMode)]
+        );
+        assert!(m.poll(&t, 2 * LONG_PRESS_US).is_empty());
+        assert!(m.update(&t, &ev(2 * LONG_PRESS_US, 0b100, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_tap_tempo() {
+        let mut t = ActionTable::default();
+        t.switches[4].press = Action::Local(LocalAction::TapTempo);
+        t.switches[5].long_press = Action::Local(LocalAction::TapTempo);
+        let mut m = ActionMapper::new();
+
+        // The taps carry the button event time
+        assert_eq!(
+            &m.update(&t, &ev(1234, 0, 0b10000))[..],
+            &[Output::TapTempo {
+                switch: 4,
+                time_us: 1234
+            }]
+        );
+        // And a long press the time it was reached, not the poll time
+        assert!(m.update(&t, &ev(1000, 0, 0b100000)).is_empty());
+        assert_eq!(
+            &m.poll(&t, LONG_PRESS_US + 50_000)[..],
+            &[Output::TapTempo {
+                switch: 5,
+                time_us: 1000 + LONG_PRESS_US
+            }]
+        );
+    }
+}
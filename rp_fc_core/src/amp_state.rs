@@ -0,0 +1,222 @@
+use defmt::Format;
+use katana_sysex::{footswitch, led_status};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Bank {
+    A,
+    B,
+}
+
+/// What sets the amp's sound
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Selection {
+    /// The panel setting, i.e. the amp's knobs
+    Panel,
+    /// Channel 0-3 of the bank
+    Channel(u8),
+}
+
+/// The amp's state as far as the GA-FC LEDs tell.
+///
+/// The LEDs show either the bank and channel, or in effect mode the
+/// effects, so the other half is what was seen last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct AmpState {
+    pub bank: Option<Bank>,
+    pub selection: Option<Selection>,
+    /// The LEDs and switches 1-5 are for the effects
+    pub effect_mode: bool,
+    /// Effects switched on, as `led_status` effect mode bits. Unknown until
+    /// seen in effect mode, and again after selecting a channel, as that
+    /// loads its effect settings.
+    pub effects: Option<u8>,
+}
+
+impl AmpState {
+    /// The selected channel, bank A channels 1-4 as 0-3 and bank B as 4-7.
+    /// `None` for the panel setting.
+    pub fn channel(&self) -> Option<u8> {
+        match (self.bank?, self.selection?) {
+            (Bank::A, Selection::Channel(ch)) => Some(ch),
+            (Bank::B, Selection::Channel(ch)) => Some(4 + ch),
+            (_, Selection::Panel) => None,
+        }
+    }
+
+    /// Whether an effect (`led_status::BOOST` etc.) is on, if known
+    pub fn effect_on(&self, effect: u8) -> Option<bool> {
+        self.effects.map(|e| e & effect != 0)
+    }
+}
+
+/// Keeps track of the amp's state from its LED status and the footswitch
+/// changes sent to it
+#[derive(Default)]
+pub struct AmpTracker {
+    state: Option<AmpState>,
+    /// GA-FC switches held down in the last footswitch change
+    footswitch: u8,
+}
+
+impl AmpTracker {
+    pub const fn new() -> Self {
+        AmpTracker {
+            state: None,
+            footswitch: 0,
+        }
+    }
+
+    /// `None` until the first LED status has been received
+    pub fn state(&self) -> Option<&AmpState> {
+        self.state.as_ref()
+    }
+
+    pub fn on_led_status(&mut self, status: u8) {
+        let mut s = self.state.unwrap_or(AmpState {
+            bank: None,
+            selection: None,
+            effect_mode: false,
+            effects: None,
+        });
+        s.effect_mode = status & led_status::EFFECT_MODE != 0;
+        if s.effect_mode {
+            s.effects = Some(status & EFFECTS);
+        } else {
+            let bank = Some(if status & led_status::BANK != 0 {
+                Bank::B
+            } else {
+                Bank::A
+            });
+            let selection = Some(match status & led_status::CHANNELS {
+                0 => Selection::Panel,
+                ch => Selection::Channel(ch.trailing_zeros() as u8),
+            });
+            if (bank, selection) != (s.bank, s.selection) {
+                s.effects = None;
+            }
+            s.bank = bank;
+            s.selection = selection;
+        }
+        self.state = Some(s);
+    }
+
+    /// Updates the state with what a footswitch change sent to the amp
+    /// does, until the amp's next LED status tells for sure
+    pub fn on_footswitch(&mut self, state: u8) {
+        let pressed = state & !core::mem::replace(&mut self.footswitch, state);
+        let Some(s) = self.state.as_mut() else {
+            return;
+        };
+        if s.effect_mode {
+            if let Some(effects) = s.effects.as_mut() {
+                *effects ^= pressed & footswitch::EFFECTS;
+            }
+        } else {
+            if pressed & footswitch::BANK != 0 {
+                s.bank = s.bank.map(|b| if b == Bank::A { Bank::B } else { Bank::A });
+                s.effects = None;
+            }
+            if pressed & footswitch::CHANNELS != 0 {
+                s.selection = Some(Selection::Channel(pressed.trailing_zeros() as u8));
+                s.effects = None;
+            }
+        }
+        if pressed & footswitch::EFFECT_MODE != 0 {
+            s.effect_mode = !s.effect_mode;
+        }
+    }
+}
+
+const EFFECTS: u8 = led_status::BOOST
+    | led_status::MOD
+    | led_status::FX
+    | led_status::DELAY
+    | led_status::REVERB;
+
+#[cfg(test)]
+mod test {
+    use super::{AmpState, AmpTracker, Bank, Selection};
+    use katana_sysex::led_status::*;
+
+    fn track(frames: &[u8]) -> AmpState {
+        let mut t = AmpTracker::new();
+        for &f in frames {
+            t.on_led_status(f);
+        }
+        *t.state().unwrap()
+    }
+
+    #[test]
+    fn test_channels() {
+        assert_eq!(AmpTracker::new().state(), None);
+
+        let s = track(&[CH1, CH3 | BANK]);
+        assert_eq!(s.bank, Some(Bank::B));
+        assert_eq!(s.selection, Some(Selection::Channel(2)));
+        assert_eq!(s.channel(), Some(6));
+        assert!(!s.effect_mode);
+        assert_eq!(s.effects, None);
+
+        let s = track(&[CH3 | BANK, 0]);
+        assert_eq!(s.selection, Some(Selection::Panel));
+        assert_eq!(s.channel(), None);
+    }
+
+    #[test]
+    fn test_effects() {
+        // Effect mode keeps the channel seen before it
+        let s = track(&[CH2, EFFECT_MODE | BOOST | REVERB]);
+        assert!(s.effect_mode);
+        assert_eq!(s.channel(), Some(1));
+        assert_eq!(s.effect_on(BOOST), Some(true));
+        assert_eq!(s.effect_on(DELAY), Some(false));
+
+        // And the effects are known after leaving it
+        let s = track(&[CH2, EFFECT_MODE | BOOST, CH2]);
+        assert!(!s.effect_mode);
+        assert_eq!(s.effects, Some(BOOST));
+
+        // Until another channel is selected
+        let s = track(&[CH2, EFFECT_MODE | BOOST, CH2, CH4]);
+        assert_eq!(s.effects, None);
+
+        // Effect mode before any channel has been seen
+        let s = track(&[EFFECT_MODE]);
+        assert_eq!(s.channel(), None);
+        assert_eq!(s.effects, Some(0));
+    }
+
+    #[test]
+    fn test_footswitch() {
+        let mut t = AmpTracker::new();
+        // Nothing known before the first status
+        t.on_footswitch(0x01);
+        t.on_footswitch(0);
+        assert_eq!(t.state(), None);
+
+        t.on_led_status(CH1);
+        // Bank, then channel 4
+        t.on_footswitch(0x10);
+        t.on_footswitch(0);
+        t.on_footswitch(0x08);
+        t.on_footswitch(0);
+        assert_eq!(t.state().unwrap().channel(), Some(7));
+
+        // Effect mode, then the delay, then back with switch 6 while
+        // holding the delay switch
+        t.on_led_status(CH4 | BANK);
+        t.on_footswitch(0x20);
+        t.on_footswitch(0);
+        t.on_led_status(EFFECT_MODE);
+        t.on_footswitch(0x08);
+        assert_eq!(t.state().unwrap().effects, Some(DELAY));
+        t.on_footswitch(0x28);
+        assert!(!t.state().unwrap().effect_mode);
+        assert_eq!(t.state().unwrap().effects, Some(DELAY));
+
+        // The amp's status wins
+        t.on_led_status(EFFECT_MODE | REVERB);
+        assert_eq!(t.state().unwrap().effects, Some(REVERB));
+        assert_eq!(t.state().unwrap().channel(), Some(7));
+    }
+}
@@ -0,0 +1,133 @@
+use defmt::Format;
+
+/// A change in the debounced button state
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct ButtonEvent {
+    /// Timer value when the change was detected, in microseconds
+    pub time_us: u64,
+    pub state: u8,
+    pub prev_state: u8,
+}
+
+impl ButtonEvent {
+    /// Buttons pressed in this change
+    pub fn pressed(&self) -> u8 {
+        self.state & !self.prev_state
+    }
+
+    /// Buttons released in this change
+    pub fn released(&self) -> u8 {
+        !self.state & self.prev_state
+    }
+}
+
+/// Measures how long each button is held down.
+pub struct PressTimer {
+    press_start_us: [Option<u64>; 8],
+}
+
+impl Default for PressTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PressTimer {
+    pub const fn new() -> Self {
+        PressTimer {
+            press_start_us: [None; 8],
+        }
+    }
+
+    /// Updates the press times from a button event. Returns the buttons
+    /// released in the event, with their press durations in microseconds.
+    pub fn update(&mut self, ev: &ButtonEvent) -> heapless::Vec<(u8, u64), 8> {
+        let mut durations = heapless::Vec::new();
+        for button in 0..8u8 {
+            let bit = 1 << button;
+            if ev.pressed() & bit != 0 {
+                self.press_start_us[button as usize] = Some(ev.time_us);
+            } else if ev.released() & bit != 0 {
+                if let Some(start) = self.press_start_us[button as usize].take() {
+                    _ = durations.push((button, ev.time_us.saturating_sub(start)));
+                }
+            }
+        }
+        durations
+    }
+
+    /// How long a button has been held down, if it is pressed
+    pub fn held_for(&self, button: u8, now_us: u64) -> Option<u64> {
+        self.press_start_us
+            .get(button as usize)
+            .copied()
+            .flatten()
+            .map(|start| now_us.saturating_sub(start))
+    }
+}
+
+/// Detects a combination of buttons being pressed together
+pub struct Combo {
+    mask: u8,
+}
+
+impl Combo {
+    pub const fn new(mask: u8) -> Self {
+        Combo { mask }
+    }
+
+    /// True when the event completes the combination. Other buttons held at
+    /// the same time don't matter.
+    pub fn update(&self, ev: &ButtonEvent) -> bool {
+        ev.state & self.mask == self.mask && ev.prev_state & self.mask != self.mask
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ButtonEvent, Combo, PressTimer};
+
+    fn ev(time_us: u64, prev_state: u8, state: u8) -> ButtonEvent {
+        ButtonEvent {
+            time_us,
+            state,
+            prev_state,
+        }
+    }
+
+    #[test]
+    fn test_pressed_released() {
+        let e = ev(0, 0b0110, 0b0011);
+        assert_eq!(e.pressed(), 0b0001);
+        assert_eq!(e.released(), 0b0100);
+    }
+
+    #[test]
+    fn test_press_durations() {
+        let mut t = PressTimer::new();
+        assert!(t.update(&ev(1_000, 0b00, 0b01)).is_empty());
+        assert!(t.update(&ev(2_000, 0b01, 0b11)).is_empty());
+        assert_eq!(t.held_for(0, 5_000), Some(4_000));
+        assert_eq!(t.held_for(2, 5_000), None);
+
+        assert_eq!(&t.update(&ev(9_000, 0b11, 0b10))[..], &[(0, 8_000)]);
+        assert_eq!(&t.update(&ev(9_500, 0b10, 0b00))[..], &[(1, 7_500)]);
+        assert_eq!(t.held_for(1, 10_000), None);
+    }
+
+    #[test]
+    fn test_release_without_press() {
+        let mut t = PressTimer::new();
+        assert!(t.update(&ev(1_000, 0b01, 0b00)).is_empty());
+    }
+
+    #[test]
+    fn test_combo() {
+        let c = Combo::new(0b110);
+        assert!(!c.update(&ev(0, 0b000, 0b010)));
+        assert!(c.update(&ev(0, 0b010, 0b111)));
+        assert!(!c.update(&ev(0, 0b111, 0b110)));
+        assert!(!c.update(&ev(0, 0b110, 0b010)));
+        assert!(c.update(&ev(0, 0b010, 0b110)));
+    }
+}
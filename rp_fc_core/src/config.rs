@@ -0,0 +1,591 @@
+use crate::actions::{Action, ActionTable, LocalAction, LAYER_COUNT};
+use crate::encoder::{EncoderMapping, MAX_SCROLL};
+use crate::led_map::{LedMap, LedMapping, LedSource, LED_COUNT};
+use crate::macros::{Macro, MacroStep, MACRO_COUNT};
+use crate::midi::{
+    MidiInMapping, MidiInRule, MidiOutMapping, MidiTrigger, StatusMidiMapping, SwitchMidi,
+};
+use crate::setlist::{Setlist, SetlistStep, MAX_SETLIST};
+use crate::ws2812::Rgb;
+use defmt::Format;
+use katana_sysex::footswitch;
+
+/// Version of the stored format. Fields are only ever appended, so older
+/// records load with defaults for the new fields; the version changes when
+/// the meaning of a stored field changes.
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// Longest encoded config
+pub const MAX_ENCODED_LEN: usize = 1000;
+
+/// Settings stored in flash
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct Config {
+    pub debounce_time_ms: u8,
+    /// Brightness scaling of the PWM and WS2812 LEDs, 0-255
+    pub led_brightness: u8,
+    /// How often the amp is asked for its status
+    pub status_interval_ms: u16,
+    pub midi_out: MidiOutMapping,
+    /// Action table of each layer
+    pub layers: [ActionTable; LAYER_COUNT],
+    pub macros: [Macro; MACRO_COUNT],
+    /// The layer in use
+    pub layer: u8,
+    pub setlist: Setlist,
+    /// Replaces the board's compiled-in LED mapping, for enclosures wired
+    /// differently
+    pub led_mapping: Option<LedMapping<LED_COUNT>>,
+    /// GA-FC switches tapped on entering and leaving tuner mode, 0 for none
+    pub tuner_footswitches: u8,
+    pub encoder: EncoderMapping,
+    /// MIDI messages sent for the switches on the DIN MIDI port
+    pub din_out: MidiOutMapping,
+    /// What the MIDI messages received on the DIN MIDI port do
+    pub din_in: MidiInMapping,
+    /// MIDI messages sent on the DIN MIDI port when the amp's state changes
+    pub status_midi: StatusMidiMapping,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            debounce_time_ms: 5,
+            led_brightness: 255,
+            status_interval_ms: 300,
+            midi_out: MidiOutMapping::default(),
+            layers: [ActionTable::default(); LAYER_COUNT],
+            macros: [Macro::default(); MACRO_COUNT],
+            layer: 0,
+            setlist: Setlist::default(),
+            led_mapping: None,
+            tuner_footswitches: 0,
+            encoder: EncoderMapping::default(),
+            din_out: MidiOutMapping::default(),
+            din_in: MidiInMapping::default(),
+            status_midi: StatusMidiMapping::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Action table of the layer in use
+    pub fn actions(&self) -> &ActionTable {
+        &self.layers[usize::from(self.layer)]
+    }
+
+    pub fn actions_mut(&mut self) -> &mut ActionTable {
+        &mut self.layers[usize::from(self.layer)]
+    }
+
+    pub fn encode(&self) -> heapless::Vec<u8, MAX_ENCODED_LEN> {
+        let mut w = Writer(heapless::Vec::new());
+        w.u8(self.debounce_time_ms);
+        w.u8(self.led_brightness);
+        w.u16(self.status_interval_ms);
+        w.u8(self.midi_out.channel);
+        self.midi_out.switches.iter().for_each(|s| w.switch_midi(s));
+        w.actions(&self.layers[0]);
+        for m in &self.macros {
+            for step in &m.steps {
+                w.u8(step.footswitches);
+                w.u16(step.delay_ms);
+            }
+        }
+        w.u8(self.layer);
+        for t in &self.layers[1..] {
+            w.actions(t);
+        }
+        w.u8(self.setlist.len);
+        for step in self.setlist.steps() {
+            w.bytes(&[step.channel, step.effects, step.effects_on]);
+        }
+        match &self.led_mapping {
+            None => w.u8(0),
+            Some(m) => {
+                w.u8(1);
+                m.leds.iter().for_each(|l| w.led_map(l));
+            }
+        }
+        w.option(self.midi_out.tempo_control);
+        w.u8(self.tuner_footswitches);
+        let scroll = self.encoder.scroll_switches();
+        w.u8(scroll.len() as u8);
+        w.bytes(scroll);
+        w.u8(self.encoder.push_switches);
+        w.u8(self.din_out.channel);
+        self.din_out.switches.iter().for_each(|s| w.switch_midi(s));
+        w.option(self.din_out.tempo_control);
+        w.option(self.din_in.channel);
+        w.u8(self.din_in.len);
+        self.din_in.rules().iter().for_each(|rule| w.midi_in_rule(rule));
+        let status = &self.status_midi;
+        w.bytes(&[status.channel, u8::from(status.channel_programs)]);
+        w.option(status.effect_mode_control);
+        status.effect_controls.iter().for_each(|&c| w.option(c));
+        w.0
+    }
+
+    /// Decodes a stored config. Returns `None` for an unknown schema
+    /// version or invalid values.
+    pub fn decode(version: u8, data: &[u8]) -> Option<Self> {
+        if version != SCHEMA_VERSION {
+            return None;
+        }
+
+        let default = Config::default();
+        let mut r = Reader(data);
+        let mut config = Config {
+            debounce_time_ms: r.u8().unwrap_or(default.debounce_time_ms),
+            led_brightness: r.u8().unwrap_or(default.led_brightness),
+            status_interval_ms: r.u16().unwrap_or(default.status_interval_ms),
+            midi_out: default.midi_out,
+            layers: default.layers,
+            macros: default.macros,
+            layer: default.layer,
+            setlist: default.setlist,
+            led_mapping: default.led_mapping,
+            tuner_footswitches: default.tuner_footswitches,
+            encoder: default.encoder,
+            din_out: default.din_out,
+            din_in: default.din_in,
+            status_midi: default.status_midi,
+        };
+        if let Some(channel) = r.u8() {
+            config.midi_out.channel = channel;
+            for s in config.midi_out.switches.iter_mut() {
+                *s = r.switch_midi()?;
+            }
+        }
+        if !r.is_empty() {
+            r.actions(&mut config.layers[0])?;
+        }
+        if !r.is_empty() {
+            for step in config.macros.iter_mut().flat_map(|m| m.steps.iter_mut()) {
+                *step = MacroStep {
+                    footswitches: r.u8()?,
+                    delay_ms: r.u16()?,
+                };
+            }
+        }
+        if let Some(layer) = r.u8() {
+            config.layer = layer;
+            for t in config.layers[1..].iter_mut() {
+                r.actions(t)?;
+            }
+        }
+        if let Some(len) = r.u8() {
+            if usize::from(len) > MAX_SETLIST {
+                return None;
+            }
+            for _ in 0..len {
+                config.setlist.push(SetlistStep {
+                    channel: r.u8()?,
+                    effects: r.u8()?,
+                    effects_on: r.u8()?,
+                });
+            }
+        }
+        match r.u8() {
+            None | Some(0) => {}
+            Some(1) => {
+                let mut m = LedMapping::identity();
+                for l in m.leds.iter_mut() {
+                    *l = r.led_map()?;
+                }
+                config.led_mapping = Some(m);
+            }
+            Some(_) => return None,
+        }
+        if !r.is_empty() {
+            config.midi_out.tempo_control = r.option()?;
+        }
+        if let Some(m) = r.u8() {
+            config.tuner_footswitches = m;
+        }
+        if let Some(len) = r.u8() {
+            let mut scroll = [0; MAX_SCROLL];
+            for m in scroll.get_mut(..usize::from(len))? {
+                *m = r.u8()?;
+            }
+            config.encoder = EncoderMapping::new(&scroll[..usize::from(len)], r.u8()?)?;
+        }
+        if let Some(channel) = r.u8() {
+            config.din_out.channel = channel;
+            for s in config.din_out.switches.iter_mut() {
+                *s = r.switch_midi()?;
+            }
+            config.din_out.tempo_control = r.option()?;
+        }
+        if !r.is_empty() {
+            let mut m = MidiInMapping {
+                channel: r.option()?,
+                ..MidiInMapping::new()
+            };
+            m.len = r.u8()?;
+            for rule in m.rules.get_mut(..usize::from(m.len))? {
+                *rule = r.midi_in_rule()?;
+            }
+            config.din_in = m;
+        }
+        if let Some(channel) = r.u8() {
+            let status = &mut config.status_midi;
+            status.channel = channel;
+            status.channel_programs = match r.u8()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            status.effect_mode_control = r.option()?;
+            for c in status.effect_controls.iter_mut() {
+                *c = r.option()?;
+            }
+        }
+
+        config.is_valid().then_some(config)
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=50).contains(&self.debounce_time_ms)
+            && self.status_interval_ms >= 100
+            && midi_out_is_valid(&self.midi_out)
+            && usize::from(self.layer) < LAYER_COUNT
+            && self
+                .layers
+                .iter()
+                .flat_map(|t| t.switches.iter())
+                .all(|s| s.press.is_valid() && s.long_press.is_valid())
+            && self
+                .macros
+                .iter()
+                .flat_map(|m| m.steps.iter())
+                .all(|s| s.footswitches & !footswitch::ALL == 0)
+            && self
+                .setlist
+                .steps()
+                .iter()
+                .all(|s| s.channel < 8 && s.effects < 0x20 && s.effects_on & !s.effects == 0)
+            && self.led_mapping.is_none_or(|m| m.leds.iter().all(|l| l.is_valid()))
+            && self.tuner_footswitches & !footswitch::ALL == 0
+            && self.encoder.is_valid()
+            && midi_out_is_valid(&self.din_out)
+            && self.din_in.channel.is_none_or(|c| c < 16)
+            && self.din_in.rules().iter().all(|rule| {
+                let (MidiTrigger::Program(n) | MidiTrigger::Cc(n)) = rule.trigger;
+                n < 128 && rule.footswitches != 0 && rule.footswitches & !footswitch::ALL == 0
+            })
+            && self.status_midi.channel < 16
+            && [self.status_midi.effect_mode_control]
+                .iter()
+                .chain(&self.status_midi.effect_controls)
+                .all(|c| c.is_none_or(|c| c < 128))
+    }
+}
+
+fn midi_out_is_valid(m: &MidiOutMapping) -> bool {
+    m.channel < 16 && m.tempo_control.is_none_or(|c| c < 32)
+}
+
+struct Writer(heapless::Vec<u8, MAX_ENCODED_LEN>);
+
+impl Writer {
+    fn bytes(&mut self, b: &[u8]) {
+        // The config is known to fit
+        self.0.extend_from_slice(b).unwrap();
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    /// 0 for none, or 1 and the value
+    fn option(&mut self, v: Option<u8>) {
+        match v {
+            None => self.u8(0),
+            Some(v) => self.bytes(&[1, v]),
+        }
+    }
+
+    fn switch_midi(&mut self, s: &SwitchMidi) {
+        match *s {
+            SwitchMidi::None => self.bytes(&[0, 0]),
+            SwitchMidi::Cc { control } => self.bytes(&[1, control]),
+            SwitchMidi::Pc { program } => self.bytes(&[2, program]),
+        }
+    }
+
+    /// Trigger kind and number, and the footswitches
+    fn midi_in_rule(&mut self, rule: &MidiInRule) {
+        match rule.trigger {
+            MidiTrigger::Program(p) => self.bytes(&[0, p]),
+            MidiTrigger::Cc(c) => self.bytes(&[1, c]),
+        }
+        self.u8(rule.footswitches);
+    }
+
+    /// Kind and four bytes of data
+    fn action(&mut self, a: Action) {
+        match a {
+            Action::None => self.bytes(&[0; 5]),
+            Action::Footswitch(m) => self.bytes(&[1, m, 0, 0, 0]),
+            Action::Sequence(s) => {
+                self.u8(2);
+                self.bytes(&s);
+            }
+            Action::Local(l) => self.bytes(&[3, l.id(), 0, 0, 0]),
+            Action::Macro(i) => self.bytes(&[4, i, 0, 0, 0]),
+        }
+    }
+
+    fn actions(&mut self, table: &ActionTable) {
+        for s in &table.switches {
+            self.action(s.press);
+            self.action(s.long_press);
+        }
+    }
+
+    /// Source kind and bit, flags (bit 0 active low, bit 1 colour set) and
+    /// the colour
+    fn led_map(&mut self, l: &LedMap) {
+        match l.source {
+            LedSource::None => self.bytes(&[0, 0]),
+            LedSource::Bit(b) => self.bytes(&[1, b]),
+            LedSource::NotBit(b) => self.bytes(&[2, b]),
+        }
+        let c = l.colour.unwrap_or(Rgb::OFF);
+        let flags = u8::from(l.active_low) | u8::from(l.colour.is_some()) << 1;
+        self.bytes(&[flags, c.r, c.g, c.b]);
+    }
+}
+
+/// Reads fields until the data ends, for older records without the newer
+/// fields
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&v, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(v)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn option(&mut self) -> Option<Option<u8>> {
+        match self.u8()? {
+            0 => Some(None),
+            1 => Some(Some(self.u8()?)),
+            _ => None,
+        }
+    }
+
+    fn switch_midi(&mut self) -> Option<SwitchMidi> {
+        match (self.u8()?, self.u8()?) {
+            (0, _) => Some(SwitchMidi::None),
+            (1, control) => Some(SwitchMidi::Cc { control }),
+            (2, program) => Some(SwitchMidi::Pc { program }),
+            _ => None,
+        }
+    }
+
+    fn midi_in_rule(&mut self) -> Option<MidiInRule> {
+        let trigger = match (self.u8()?, self.u8()?) {
+            (0, p) => MidiTrigger::Program(p),
+            (1, c) => MidiTrigger::Cc(c),
+            _ => return None,
+        };
+        Some(MidiInRule {
+            trigger,
+            footswitches: self.u8()?,
+        })
+    }
+
+    fn action(&mut self) -> Option<Action> {
+        let kind = self.u8()?;
+        let data = [self.u8()?, self.u8()?, self.u8()?, self.u8()?];
+        match kind {
+            0 => Some(Action::None),
+            1 => Some(Action::Footswitch(data[0])),
+            2 => Some(Action::Sequence(data)),
+            3 => Some(Action::Local(LocalAction::from_id(data[0])?)),
+            4 if usize::from(data[0]) < MACRO_COUNT => Some(Action::Macro(data[0])),
+            _ => None,
+        }
+    }
+
+    fn actions(&mut self, table: &mut ActionTable) -> Option<()> {
+        for s in table.switches.iter_mut() {
+            s.press = self.action()?;
+            s.long_press = self.action()?;
+        }
+        Some(())
+    }
+
+    fn led_map(&mut self) -> Option<LedMap> {
+        let source = match (self.u8()?, self.u8()?) {
+            (0, _) => LedSource::None,
+            (1, b) => LedSource::Bit(b),
+            (2, b) => LedSource::NotBit(b),
+            _ => return None,
+        };
+        let flags = self.u8()?;
+        let colour = Rgb::new(self.u8()?, self.u8()?, self.u8()?);
+        Some(LedMap {
+            source,
+            active_low: flags & 1 != 0,
+            colour: (flags & 2 != 0).then_some(colour),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, SCHEMA_VERSION};
+    use crate::actions::{Action, LocalAction};
+    use crate::encoder::EncoderMapping;
+    use crate::led_map::{LedMap, LedMapping, LedSource};
+    use crate::macros::MacroStep;
+    use crate::midi::{MidiTrigger, SwitchMidi, MAX_IN_RULES};
+    use crate::setlist::SetlistStep;
+    use crate::ws2812::Rgb;
+
+    #[test]
+    fn test_round_trip() {
+        let mut c = Config {
+            debounce_time_ms: 12,
+            led_brightness: 40,
+            ..Default::default()
+        };
+        c.midi_out.channel = 3;
+        c.midi_out.switches[1] = SwitchMidi::Pc { program: 7 };
+        c.midi_out.switches[7] = SwitchMidi::None;
+        c.midi_out.tempo_control = Some(20);
+        c.layers[0].switches[0].press = Action::Sequence([1, 2, 0, 0]);
+        c.layers[0].switches[5].long_press = Action::Local(LocalAction::KeyboardMode);
+        c.layers[0].switches[6].press = Action::Macro(2);
+        c.layers[2].switches[5].long_press = Action::Local(LocalAction::NextLayer);
+        c.layer = 2;
+        c.setlist.push(SetlistStep {
+            channel: 6,
+            effects: 0x11,
+            effects_on: 0x01,
+        });
+        c.setlist.push(SetlistStep::default());
+        c.macros[2].steps[1] = MacroStep {
+            footswitches: 0x10,
+            delay_ms: 500,
+        };
+        let mut m = LedMapping::identity();
+        m.leds.swap(0, 3);
+        m.leds[5] = LedMap {
+            source: LedSource::NotBit(4),
+            active_low: true,
+            colour: Some(Rgb::new(0, 255, 0)),
+        };
+        c.led_mapping = Some(m);
+        c.tuner_footswitches = 0x18;
+        c.encoder = EncoderMapping::new(&[0x10, 0x03], 0).unwrap();
+        c.din_out.channel = 9;
+        c.din_out.switches[0] = SwitchMidi::Pc { program: 1 };
+        c.din_out.tempo_control = Some(3);
+        c.din_in.channel = Some(4);
+        c.din_in.set(MidiTrigger::Program(1), 0);
+        c.din_in.set(MidiTrigger::Cc(20), 0x30);
+        c.status_midi.channel_programs = false;
+        c.status_midi.effect_controls[4] = Some(70);
+
+        let data = c.encode();
+        assert_eq!(Config::decode(SCHEMA_VERSION, &data), Some(c));
+        assert_eq!(Config::decode(SCHEMA_VERSION + 1, &data), None);
+    }
+
+    #[test]
+    fn test_largest() {
+        let mut c = Config {
+            led_mapping: Some(LedMapping::identity()),
+            encoder: EncoderMapping::new(&[0x3f; 8], 0x3f).unwrap(),
+            ..Default::default()
+        };
+        c.midi_out.tempo_control = Some(0);
+        while c.setlist.push(SetlistStep::default()) {}
+        c.din_out.tempo_control = Some(0);
+        c.din_in.channel = Some(0);
+        for i in 0..MAX_IN_RULES {
+            c.din_in.set(MidiTrigger::Cc(i as u8), 0x3f);
+        }
+        c.status_midi.effect_mode_control = Some(0);
+        c.status_midi.effect_controls = [Some(0); 5];
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), Some(c));
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        // Only the first fields, as written by an older version
+        let c = Config::decode(SCHEMA_VERSION, &[10, 128]).unwrap();
+        assert_eq!(c.debounce_time_ms, 10);
+        assert_eq!(c.led_brightness, 128);
+        assert_eq!(c.status_interval_ms, Config::default().status_interval_ms);
+        assert_eq!(c.midi_out, Config::default().midi_out);
+        assert_eq!(c.layer, 0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut data = Config::default().encode();
+        data[0] = 0;
+        assert_eq!(Config::decode(SCHEMA_VERSION, &data), None);
+
+        let c = Config {
+            layer: 3,
+            ..Default::default()
+        };
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+
+        // GA-FC switches 7 and 8 don't exist
+        let mut c = Config::default();
+        c.layers[1].switches[6].press = Action::Footswitch(0x40);
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+        let mut c = Config::default();
+        c.macros[0].steps[0].footswitches = 0x81;
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+
+        let mut c = Config::default();
+        c.midi_out.tempo_control = Some(32);
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+        let c = Config {
+            tuner_footswitches: 0x40,
+            ..Default::default()
+        };
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+        let c = Config {
+            encoder: EncoderMapping::new(&[0x01, 0x80], 0x10).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+
+        let mut c = Config::default();
+        c.din_in.set(MidiTrigger::Program(9), 0x40);
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+        let mut c = Config::default();
+        c.status_midi.effect_controls[0] = Some(128);
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+
+        let mut m = LedMapping::identity();
+        m.leds[0].source = LedSource::Bit(8);
+        let c = Config {
+            led_mapping: Some(m),
+            ..Default::default()
+        };
+        assert_eq!(Config::decode(SCHEMA_VERSION, &c.encode()), None);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::config::{Config, MAX_ENCODED_LEN};
+
+/// Flash erase unit
+pub const SECTOR_SIZE: u32 = 4096;
+/// Flash program unit
+pub const PAGE_SIZE: usize = 256;
+/// Each record takes four pages
+pub const RECORD_SIZE: usize = 4 * PAGE_SIZE;
+/// Records used to take two pages, and are still loaded from any two-page
+/// boundary
+const LEGACY_RECORD_SIZE: usize = 2 * PAGE_SIZE;
+
+const RECORDS_PER_SECTOR: u32 = SECTOR_SIZE / RECORD_SIZE as u32;
+/// Sequence number, schema version and payload length
+const HEADER_LEN: usize = 7;
+const CRC_LEN: usize = 4;
+const _: () = assert!(HEADER_LEN + MAX_ENCODED_LEN + CRC_LEN <= RECORD_SIZE);
+
+/// NOR flash area used for the config, addressed from its start
+pub trait Flash {
+    fn read(&mut self, offset: u32, buf: &mut [u8]);
+    /// Erases the sector starting at `offset` to all ones
+    fn erase_sector(&mut self, offset: u32);
+    /// Programs the page starting at `offset`, which must be erased
+    fn program_page(&mut self, offset: u32, data: &[u8; PAGE_SIZE]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The record didn't read back correctly after writing
+    Verify,
+}
+
+/// Where the latest record is
+#[derive(Clone, Copy)]
+struct Latest {
+    offset: u32,
+    seq: u32,
+}
+
+/// Wear-levelled config storage in a few flash sectors.
+///
+/// Every save writes a new record to the next four pages, each with a
+/// sequence number and a CRC. The area is used as a ring: a sector is
+/// erased only when the records move into it, so the previous record stays
+/// intact until the new one is written, and each sector is erased once per
+/// `sectors * 4` saves. Loading uses the valid record with the highest
+/// sequence number.
+pub struct ConfigStore<F> {
+    flash: F,
+    size: u32,
+    records: u32,
+    latest: Option<Latest>,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    /// Finds the latest record. At least two sectors are needed.
+    pub fn new(mut flash: F, sectors: u32) -> Self {
+        assert!(sectors >= 2);
+        let size = sectors * SECTOR_SIZE;
+        let mut latest: Option<Latest> = None;
+        let mut buf = [0u8; RECORD_SIZE];
+        // Looking at every two-page boundary finds the records written
+        // before they grew, as well as the current ones
+        for offset in (0..size).step_by(LEGACY_RECORD_SIZE) {
+            let buf = &mut buf[..RECORD_SIZE.min((size - offset) as usize)];
+            flash.read(offset, buf);
+            if let Some(rec) = record(buf) {
+                if latest.is_none_or(|l| rec.seq > l.seq) {
+                    latest = Some(Latest {
+                        offset,
+                        seq: rec.seq,
+                    });
+                }
+            }
+        }
+        ConfigStore {
+            flash,
+            size,
+            records: sectors * RECORDS_PER_SECTOR,
+            latest,
+        }
+    }
+
+    /// Loads the saved config. `None` if nothing valid has been saved or the
+    /// record is from an unknown schema version.
+    pub fn load(&mut self) -> Option<Config> {
+        let latest = self.latest?;
+        let mut buf = [0u8; RECORD_SIZE];
+        let buf = &mut buf[..RECORD_SIZE.min((self.size - latest.offset) as usize)];
+        self.flash.read(latest.offset, buf);
+        let r = record(buf)?;
+        Config::decode(r.version, r.payload)
+    }
+
+    pub fn save(&mut self, config: &Config) -> Result<(), Error> {
+        if self.load().as_ref() == Some(config) {
+            return Ok(());
+        }
+
+        // The record after the one holding the latest, which may be a
+        // shorter one from before
+        let (mut r, seq) = match self.latest {
+            Some(l) => (
+                (l.offset / RECORD_SIZE as u32 + 1) % self.records,
+                l.seq.wrapping_add(1),
+            ),
+            None => (0, 0),
+        };
+
+        let mut buf = [0u8; RECORD_SIZE];
+        if r % RECORDS_PER_SECTOR != 0 {
+            // Left over from an interrupted write, start the next sector
+            self.flash.read(r * RECORD_SIZE as u32, &mut buf);
+            if buf.iter().any(|&b| b != 0xff) {
+                r = (r / RECORDS_PER_SECTOR + 1) * RECORDS_PER_SECTOR % self.records;
+            }
+        }
+        let offset = r * RECORD_SIZE as u32;
+        if r % RECORDS_PER_SECTOR == 0 {
+            self.flash.erase_sector(offset);
+        }
+
+        let payload = config.encode();
+        let len = HEADER_LEN + payload.len();
+        buf.fill(0xff);
+        buf[0..4].copy_from_slice(&seq.to_le_bytes());
+        buf[4] = crate::config::SCHEMA_VERSION;
+        buf[5..7].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        buf[HEADER_LEN..len].copy_from_slice(&payload);
+        let crc = crc32(&buf[..len]);
+        buf[len..len + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        for (i, page) in buf.chunks_exact(PAGE_SIZE).enumerate() {
+            let offset = offset + (i * PAGE_SIZE) as u32;
+            self.flash.program_page(offset, page.try_into().unwrap());
+        }
+
+        let mut check = [0u8; RECORD_SIZE];
+        self.flash.read(offset, &mut check);
+        if check != buf {
+            return Err(Error::Verify);
+        }
+        self.latest = Some(Latest { offset, seq });
+        Ok(())
+    }
+}
+
+struct Record<'a> {
+    seq: u32,
+    version: u8,
+    payload: &'a [u8],
+}
+
+/// Parses a record, `None` if it's erased or corrupted
+fn record(buf: &[u8]) -> Option<Record<'_>> {
+    let len = HEADER_LEN + usize::from(u16::from_le_bytes([buf[5], buf[6]]));
+    let seq = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    if seq == u32::MAX || len + CRC_LEN > buf.len() {
+        return None;
+    }
+    let crc = u32::from_le_bytes(buf[len..len + CRC_LEN].try_into().unwrap());
+    (crc32(&buf[..len]) == crc).then(|| Record {
+        seq,
+        version: buf[4],
+        payload: &buf[HEADER_LEN..len],
+    })
+}
+
+/// CRC-32 (IEEE 802.3)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        crc32, ConfigStore, Flash, HEADER_LEN, LEGACY_RECORD_SIZE, PAGE_SIZE, RECORD_SIZE,
+        SECTOR_SIZE,
+    };
+    use crate::config::{Config, SCHEMA_VERSION};
+
+    const SECTORS: u32 = 2;
+    const SIZE: usize = (SECTORS * SECTOR_SIZE) as usize;
+
+    /// NOR flash: programming can only clear bits
+    struct FakeFlash {
+        data: [u8; SIZE],
+        erases: [u32; SECTORS as usize],
+    }
+
+    impl FakeFlash {
+        fn new() -> Self {
+            FakeFlash {
+                data: [0xff; SIZE],
+                erases: [0; SECTORS as usize],
+            }
+        }
+    }
+
+    impl Flash for &mut FakeFlash {
+        fn read(&mut self, offset: u32, buf: &mut [u8]) {
+            let o = offset as usize;
+            buf.copy_from_slice(&self.data[o..o + buf.len()]);
+        }
+
+        fn erase_sector(&mut self, offset: u32) {
+            assert_eq!(offset % SECTOR_SIZE, 0);
+            let o = offset as usize;
+            self.data[o..o + SECTOR_SIZE as usize].fill(0xff);
+            self.erases[o / SECTOR_SIZE as usize] += 1;
+        }
+
+        fn program_page(&mut self, offset: u32, data: &[u8; PAGE_SIZE]) {
+            let o = offset as usize;
+            for (f, d) in self.data[o..o + PAGE_SIZE].iter_mut().zip(data) {
+                *f &= d;
+            }
+        }
+    }
+
+    fn config(brightness: u8) -> Config {
+        Config {
+            led_brightness: brightness,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let mut flash = FakeFlash::new();
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), None);
+        store.save(&config(10)).unwrap();
+        store.save(&config(20)).unwrap();
+        assert_eq!(store.load(), Some(config(20)));
+
+        // Found again after a reboot
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), Some(config(20)));
+    }
+
+    #[test]
+    fn test_wear_levelling() {
+        let mut flash = FakeFlash::new();
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        for i in 0..100 {
+            store.save(&config(i)).unwrap();
+            // Saving the same config again doesn't write
+            store.save(&config(i)).unwrap();
+        }
+        assert_eq!(store.load(), Some(config(99)));
+        // 100 records over 4 records per sector
+        assert_eq!(flash.erases, [13, 12]);
+
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), Some(config(99)));
+    }
+
+    #[test]
+    fn test_corrupted_record() {
+        let mut flash = FakeFlash::new();
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        store.save(&config(10)).unwrap();
+        store.save(&config(20)).unwrap();
+
+        // Interrupted write of the second record
+        flash.data[RECORD_SIZE + 8] ^= 0xff;
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), Some(config(10)));
+
+        // The half-written record is skipped
+        store.save(&config(30)).unwrap();
+        assert_eq!(store.load(), Some(config(30)));
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), Some(config(30)));
+    }
+
+    #[test]
+    fn test_legacy_records() {
+        let mut flash = FakeFlash::new();
+        // Two-page records, the latest one in the second half of a record
+        for (seq, brightness) in [(4u32, 10), (5, 20)] {
+            let payload = config(brightness).encode();
+            let o = seq as usize % 4 * LEGACY_RECORD_SIZE;
+            let len = HEADER_LEN + payload.len();
+            let rec = &mut flash.data[o..o + LEGACY_RECORD_SIZE];
+            rec[0..4].copy_from_slice(&seq.to_le_bytes());
+            rec[4] = SCHEMA_VERSION;
+            rec[5..7].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+            rec[HEADER_LEN..len].copy_from_slice(&payload);
+            let crc = crc32(&rec[..len]);
+            rec[len..len + 4].copy_from_slice(&crc.to_le_bytes());
+        }
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), Some(config(20)));
+
+        // The next record goes after it, without erasing it
+        store.save(&config(30)).unwrap();
+        assert_eq!(flash.erases, [0, 0]);
+        assert_eq!(&flash.data[RECORD_SIZE..RECORD_SIZE + 4], &6u32.to_le_bytes());
+        let mut store = ConfigStore::new(&mut flash, SECTORS);
+        assert_eq!(store.load(), Some(config(30)));
+    }
+}
@@ -0,0 +1,903 @@
+use crate::actions::{Action, LocalAction, LAYER_COUNT};
+use crate::encoder::MAX_SCROLL;
+use crate::led_map::{LedMap, LedSource, LED_COUNT};
+use crate::macros::{Macro, MacroStep, MACRO_COUNT, MAX_STEPS};
+use crate::midi::{MidiTrigger, SwitchMidi};
+use crate::setlist::{Setlist, SetlistStep};
+use crate::ws2812::Rgb;
+use core::fmt::Write;
+
+/// Longest accepted command line
+pub const MAX_LINE: usize = 64;
+
+/// Size of the queue for the output to the host, which holds the help text
+/// with the line ends sent as CR LF
+pub const OUTPUT_LEN: usize = 4096;
+
+pub const HELP: &str = "\
+version                       firmware version and git commit
+stats                         amp link statistics
+midi                          show the footswitch MIDI mapping
+midi <switch> cc|pc <n>|off   set the MIDI message of a switch (1-8)
+midi tempo <0-31>|off         send the tap tempo in BPM as a 14-bit control
+                              change, on the control and the control + 32
+din                           show the DIN MIDI mappings
+din <switch> cc|pc <n>|off    set the DIN MIDI message of a switch (1-8)
+din tempo <0-31>|off          send the tap tempo over DIN MIDI, as above
+din in pc|cc <n> <GA-FC switches>|off
+                              tap the GA-FC switches on a received program
+                              change, or hold them while a control change is
+                              64 or more, e.g. din in cc 20 12
+din status pc on|off          send the amp channel as a program change (0-7)
+din status mode|<effect> cc <n>|off
+                              send effect mode or an effect (boost mod fx
+                              delay reverb) as a control change, 127 when on
+layer [1-3]                   show or select the layer
+action                        show the footswitch actions of the layer
+action <switch> press|long <action>
+                              set the action of a switch (1-8) in the
+                              layer, one of
+                                fs <GA-FC switches, e.g. 15>
+                                seq <GA-FC switches>... (up to 4 steps)
+                                keyboard (switch to keyboard mode)
+                                layer (switch to the next layer)
+                                macro <1-4>
+                                next|prev (setlist step)
+                                tap (tap tempo)
+                                tuner (tuner mode)
+                                off
+macro                         show the macros
+macro <1-4> <step>...|off     set a macro, up to 8 steps of GA-FC switches
+                              and an optional delay after, e.g. 5:200 2
+setlist                       show the setlist
+setlist add <step>...         add amp channels with optional effect
+                              settings to the setlist, e.g. A1 B3+boost-delay
+                              (effects: boost mod fx delay reverb)
+setlist clear                 empty the setlist
+debounce [ms]                 show or set the debounce time (1-50)
+brightness [0-255]            show or set the LED brightness
+ledmap                        show which amp LED status bit lights each LED
+ledmap <1-6> off|<bit>|!<bit> [low] [#rrggbb]
+                              light an LED from a status bit (0-7) being set
+                              or clear, with an active low output or its own
+                              colour, e.g. ledmap 6 !4 for a bank A LED
+ledmap board                  go back to the board's built-in mapping
+tuner [<GA-FC switches>|off]  show or set the GA-FC switches tapped on
+                              entering and leaving tuner mode, e.g. 45
+encoder                       show the encoder's GA-FC switches
+encoder scroll <switches>...|off
+                              set the GA-FC switches tapped one after
+                              another by turning the encoder, e.g. 1 2 3 4
+encoder push <switches>|off   set the GA-FC switches held by pushing it
+save                          save the settings
+bootloader                    reboot to the USB (UF2) bootloader
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    Help,
+    Version,
+    Stats,
+    ShowMidi,
+    /// Sets the MIDI message of a switch, 0-based
+    SetMidi {
+        switch: u8,
+        midi: SwitchMidi,
+    },
+    /// Sets the control for the tap tempo
+    SetMidiTempo(Option<u8>),
+    ShowDin,
+    /// Sets the DIN MIDI message of a switch, 0-based
+    SetDinMidi {
+        switch: u8,
+        midi: SwitchMidi,
+    },
+    /// Sets the control for the tap tempo on DIN MIDI
+    SetDinTempo(Option<u8>),
+    /// Sets the GA-FC switches of a received DIN MIDI message, 0 for none
+    SetDinIn {
+        trigger: MidiTrigger,
+        footswitches: u8,
+    },
+    /// Sets whether the amp channel is sent as a program change
+    SetStatusPrograms(bool),
+    /// Sets the control sent for effect mode (`None`) or an effect (the
+    /// `led_status` effect mode bit)
+    SetStatusControl {
+        effect: Option<u8>,
+        control: Option<u8>,
+    },
+    ShowLayer,
+    /// Selects the layer, 0-based
+    SetLayer(u8),
+    ShowActions,
+    /// Sets the press or long press action of a switch, 0-based
+    SetAction {
+        switch: u8,
+        long_press: bool,
+        action: Action,
+    },
+    ShowMacros,
+    /// Sets a macro, 0-based
+    SetMacro {
+        index: u8,
+        steps: Macro,
+    },
+    ShowSetlist,
+    /// Adds the steps to the end of the setlist
+    AddToSetlist(Setlist),
+    ClearSetlist,
+    ShowDebounce,
+    SetDebounce(u8),
+    ShowBrightness,
+    SetBrightness(u8),
+    ShowLedMapping,
+    /// Sets what lights an LED, 0-based
+    SetLedMap {
+        led: u8,
+        map: LedMap,
+    },
+    /// Goes back to the board's compiled-in mapping
+    ResetLedMapping,
+    ShowTuner,
+    /// Sets the GA-FC switches tapped for tuner mode, 0 for none
+    SetTuner(u8),
+    ShowEncoder,
+    /// Sets the GA-FC switches the encoder steps through, 0 after the last
+    SetEncoderScroll([u8; MAX_SCROLL]),
+    /// Sets the GA-FC switches held by the encoder push switch, 0 for none
+    SetEncoderPush(u8),
+    Save,
+    Bootloader,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ParseError {
+    UnknownCommand,
+    /// Missing, extra or out of range arguments
+    BadArguments,
+}
+
+impl ParseError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ParseError::UnknownCommand => "Unknown command, try 'help'",
+            ParseError::BadArguments => "Bad arguments, try 'help'",
+        }
+    }
+}
+
+/// Parses a command line. Returns `None` for a blank line.
+pub fn parse(line: &str) -> Option<Result<Command, ParseError>> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next()?;
+    let mut args: heapless::Vec<&str, { MAX_STEPS + 1 }> = heapless::Vec::new();
+    for w in words {
+        if args.push(w).is_err() {
+            return Some(Err(ParseError::BadArguments));
+        }
+    }
+
+    let res = match (cmd, &args[..]) {
+        ("help" | "?", []) => Ok(Command::Help),
+        ("version", []) => Ok(Command::Version),
+        ("stats", []) => Ok(Command::Stats),
+        ("midi", []) => Ok(Command::ShowMidi),
+        ("midi", ["tempo", "off"]) => Ok(Command::SetMidiTempo(None)),
+        ("midi", ["tempo", n]) => number(n, 0, 31).map(|n| Command::SetMidiTempo(Some(n))),
+        ("midi", [switch, midi @ ..]) => {
+            parse_switch_midi(switch, midi).map(|(switch, midi)| Command::SetMidi { switch, midi })
+        }
+        ("din", []) => Ok(Command::ShowDin),
+        ("din", ["tempo", "off"]) => Ok(Command::SetDinTempo(None)),
+        ("din", ["tempo", n]) => number(n, 0, 31).map(|n| Command::SetDinTempo(Some(n))),
+        ("din", ["in", kind, n, switches]) => parse_set_din_in(kind, n, switches),
+        ("din", ["status", "pc", "on"]) => Ok(Command::SetStatusPrograms(true)),
+        ("din", ["status", "pc", "off"]) => Ok(Command::SetStatusPrograms(false)),
+        ("din", ["status", what, control @ ..]) => parse_set_status_control(what, control),
+        ("din", [switch, midi @ ..]) => parse_switch_midi(switch, midi)
+            .map(|(switch, midi)| Command::SetDinMidi { switch, midi }),
+        ("layer", []) => Ok(Command::ShowLayer),
+        ("layer", [n]) => number(n, 1, LAYER_COUNT as u8).map(|n| Command::SetLayer(n - 1)),
+        ("action", []) => Ok(Command::ShowActions),
+        ("action", [switch, gesture, action @ ..]) => parse_set_action(switch, gesture, action),
+        ("macro", []) => Ok(Command::ShowMacros),
+        ("macro", [index, steps @ ..]) => parse_set_macro(index, steps),
+        ("setlist", []) => Ok(Command::ShowSetlist),
+        ("setlist", ["clear"]) => Ok(Command::ClearSetlist),
+        ("setlist", ["add", steps @ ..]) if !steps.is_empty() => parse_add_to_setlist(steps),
+        ("debounce", []) => Ok(Command::ShowDebounce),
+        ("debounce", [ms]) => number(ms, 1, 50).map(Command::SetDebounce),
+        ("brightness", []) => Ok(Command::ShowBrightness),
+        ("brightness", [b]) => number(b, 0, 255).map(Command::SetBrightness),
+        ("ledmap", []) => Ok(Command::ShowLedMapping),
+        ("ledmap", ["board"]) => Ok(Command::ResetLedMapping),
+        ("ledmap", [led, map @ ..]) => parse_set_led_map(led, map),
+        ("tuner", []) => Ok(Command::ShowTuner),
+        ("tuner", ["off"]) => Ok(Command::SetTuner(0)),
+        ("tuner", [switches]) => switch_mask(switches).map(Command::SetTuner),
+        ("encoder", []) => Ok(Command::ShowEncoder),
+        ("encoder", ["scroll", "off"]) => Ok(Command::SetEncoderScroll([0; MAX_SCROLL])),
+        ("encoder", ["scroll", steps @ ..]) => parse_set_encoder_scroll(steps),
+        ("encoder", ["push", "off"]) => Ok(Command::SetEncoderPush(0)),
+        ("encoder", ["push", switches]) => switch_mask(switches).map(Command::SetEncoderPush),
+        ("save", []) => Ok(Command::Save),
+        ("bootloader", []) => Ok(Command::Bootloader),
+        (
+            "help" | "?" | "version" | "stats" | "layer" | "action" | "setlist" | "debounce"
+            | "brightness" | "tuner" | "encoder" | "save" | "bootloader",
+            _,
+        ) => Err(ParseError::BadArguments),
+        _ => Err(ParseError::UnknownCommand),
+    };
+    Some(res)
+}
+
+/// A switch, 0-based, and its MIDI message
+fn parse_switch_midi(switch: &str, midi: &[&str]) -> Result<(u8, SwitchMidi), ParseError> {
+    let switch = number(switch, 1, 8)? - 1;
+    let midi = match midi {
+        ["off"] => SwitchMidi::None,
+        ["cc", n] => SwitchMidi::Cc {
+            control: number(n, 0, 127)?,
+        },
+        ["pc", n] => SwitchMidi::Pc {
+            program: number(n, 0, 127)?,
+        },
+        _ => return Err(ParseError::BadArguments),
+    };
+    Ok((switch, midi))
+}
+
+fn parse_set_din_in(kind: &str, n: &str, switches: &str) -> Result<Command, ParseError> {
+    let n = number(n, 0, 127)?;
+    let trigger = match kind {
+        "pc" => MidiTrigger::Program(n),
+        "cc" => MidiTrigger::Cc(n),
+        _ => return Err(ParseError::BadArguments),
+    };
+    let footswitches = match switches {
+        "off" => 0,
+        s => switch_mask(s)?,
+    };
+    Ok(Command::SetDinIn {
+        trigger,
+        footswitches,
+    })
+}
+
+fn parse_set_status_control(what: &str, control: &[&str]) -> Result<Command, ParseError> {
+    let effect = match what {
+        "mode" => None,
+        e => {
+            let bit = EFFECTS.iter().position(|&name| name == e);
+            Some(bit.ok_or(ParseError::BadArguments)? as u8)
+        }
+    };
+    let control = match control {
+        ["off"] => None,
+        ["cc", n] => Some(number(n, 0, 127)?),
+        _ => return Err(ParseError::BadArguments),
+    };
+    Ok(Command::SetStatusControl { effect, control })
+}
+
+/// A received MIDI message in the same form as it's set
+pub fn format_midi_trigger(trigger: MidiTrigger) -> heapless::String<8> {
+    let mut s = heapless::String::new();
+    _ = match trigger {
+        MidiTrigger::Program(p) => write!(s, "pc {}", p),
+        MidiTrigger::Cc(c) => write!(s, "cc {}", c),
+    };
+    s
+}
+
+/// Name of an effect, by its `led_status` effect mode bit
+pub fn effect_name(bit: usize) -> &'static str {
+    EFFECTS[bit]
+}
+
+fn parse_set_encoder_scroll(steps: &[&str]) -> Result<Command, ParseError> {
+    if !(1..=MAX_SCROLL).contains(&steps.len()) {
+        return Err(ParseError::BadArguments);
+    }
+    let mut scroll = [0; MAX_SCROLL];
+    for (s, step) in scroll.iter_mut().zip(steps) {
+        *s = switch_mask(step)?;
+    }
+    Ok(Command::SetEncoderScroll(scroll))
+}
+
+fn parse_set_action(switch: &str, gesture: &str, action: &[&str]) -> Result<Command, ParseError> {
+    let switch = number(switch, 1, 8)? - 1;
+    let long_press = match gesture {
+        "press" => false,
+        "long" => true,
+        _ => return Err(ParseError::BadArguments),
+    };
+    let action = match action {
+        ["off"] => Action::None,
+        ["fs", switches] => Action::Footswitch(switch_mask(switches)?),
+        ["seq", steps @ ..] if (1..=4).contains(&steps.len()) => {
+            let mut seq = [0; 4];
+            for (s, step) in seq.iter_mut().zip(steps) {
+                *s = switch_mask(step)?;
+            }
+            Action::Sequence(seq)
+        }
+        ["keyboard"] => Action::Local(LocalAction::KeyboardMode),
+        ["layer"] => Action::Local(LocalAction::NextLayer),
+        ["next"] => Action::Local(LocalAction::SetlistNext),
+        ["prev"] => Action::Local(LocalAction::SetlistPrev),
+        ["tap"] => Action::Local(LocalAction::TapTempo),
+        ["tuner"] => Action::Local(LocalAction::Tuner),
+        ["macro", n] => Action::Macro(number(n, 1, MACRO_COUNT as u8)? - 1),
+        _ => return Err(ParseError::BadArguments),
+    };
+    Ok(Command::SetAction {
+        switch,
+        long_press,
+        action,
+    })
+}
+
+/// Effect names, for the `led_status` effect mode bits
+const EFFECTS: [&str; 5] = ["boost", "mod", "fx", "delay", "reverb"];
+
+fn parse_add_to_setlist(steps: &[&str]) -> Result<Command, ParseError> {
+    let mut setlist = Setlist::default();
+    for step in steps {
+        setlist.push(parse_setlist_step(step)?);
+    }
+    Ok(Command::AddToSetlist(setlist))
+}
+
+/// A channel and effect settings, e.g. "B3+boost-delay"
+fn parse_setlist_step(s: &str) -> Result<SetlistStep, ParseError> {
+    let bank = match s.get(..1) {
+        Some("A" | "a") => 0,
+        Some("B" | "b") => 4,
+        _ => return Err(ParseError::BadArguments),
+    };
+    let channel = s.get(1..2).ok_or(ParseError::BadArguments)?;
+    let mut step = SetlistStep {
+        channel: bank + number(channel, 1, 4)? - 1,
+        ..Default::default()
+    };
+
+    let mut rest = &s[2..];
+    while let Some(on) = rest.chars().next().map(|c| c == '+') {
+        if !rest.starts_with(['+', '-']) {
+            return Err(ParseError::BadArguments);
+        }
+        let end = rest[1..].find(['+', '-']).map_or(rest.len(), |i| i + 1);
+        let bit = EFFECTS
+            .iter()
+            .position(|&e| e == &rest[1..end])
+            .ok_or(ParseError::BadArguments)?;
+        step.effects |= 1 << bit;
+        if on {
+            step.effects_on |= 1 << bit;
+        }
+        rest = &rest[end..];
+    }
+    Ok(step)
+}
+
+/// A setlist step in the same form as it's set
+pub fn format_setlist_step(step: &SetlistStep) -> heapless::String<40> {
+    let mut s = heapless::String::new();
+    let bank = if step.channel >= 4 { 'B' } else { 'A' };
+    _ = write!(s, "{}{}", bank, step.channel % 4 + 1);
+    for (bit, name) in EFFECTS.iter().enumerate() {
+        if step.effects & (1 << bit) != 0 {
+            _ = s.push(if step.effects_on & (1 << bit) != 0 { '+' } else { '-' });
+            _ = s.push_str(name);
+        }
+    }
+    s
+}
+
+fn parse_set_macro(index: &str, steps: &[&str]) -> Result<Command, ParseError> {
+    let index = number(index, 1, MACRO_COUNT as u8)? - 1;
+    let mut m = Macro::default();
+    match steps {
+        ["off"] => {}
+        [_, ..] => {
+            for (step, s) in m.steps.iter_mut().zip(steps) {
+                let (switches, delay) = s.split_once(':').unwrap_or((s, "0"));
+                *step = MacroStep {
+                    footswitches: switch_mask(switches)?,
+                    delay_ms: delay
+                        .parse()
+                        .ok()
+                        .filter(|&d| d <= MAX_DELAY_MS)
+                        .ok_or(ParseError::BadArguments)?,
+                };
+            }
+        }
+        [] => return Err(ParseError::BadArguments),
+    }
+    Ok(Command::SetMacro { index, steps: m })
+}
+
+fn parse_set_led_map(led: &str, map: &[&str]) -> Result<Command, ParseError> {
+    let led = number(led, 1, LED_COUNT as u8)? - 1;
+    let (source, options) = map.split_first().ok_or(ParseError::BadArguments)?;
+    let mut map = LedMap {
+        source: match (*source, source.strip_prefix('!')) {
+            ("off", _) => LedSource::None,
+            (_, Some(bit)) => LedSource::NotBit(number(bit, 0, 7)?),
+            (bit, None) => LedSource::Bit(number(bit, 0, 7)?),
+        },
+        active_low: false,
+        colour: None,
+    };
+    for option in options {
+        match (*option, option.strip_prefix('#')) {
+            ("low", _) if !map.active_low => map.active_low = true,
+            (_, Some(hex)) if hex.len() == 6 && map.colour.is_none() => {
+                let rgb = u32::from_str_radix(hex, 16).map_err(|_| ParseError::BadArguments)?;
+                map.colour = Some(Rgb::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+            }
+            _ => return Err(ParseError::BadArguments),
+        }
+    }
+    Ok(Command::SetLedMap { led, map })
+}
+
+/// An LED's mapping in the same form as it's set
+pub fn format_led_map(map: &LedMap) -> heapless::String<24> {
+    let mut s = heapless::String::new();
+    _ = match map.source {
+        LedSource::None => write!(s, "off"),
+        LedSource::Bit(b) => write!(s, "{}", b),
+        LedSource::NotBit(b) => write!(s, "!{}", b),
+    };
+    if map.active_low {
+        _ = s.push_str(" low");
+    }
+    if let Some(c) = map.colour {
+        _ = write!(s, " #{:02x}{:02x}{:02x}", c.r, c.g, c.b);
+    }
+    s
+}
+
+/// Longest delay between macro steps
+const MAX_DELAY_MS: u16 = 10_000;
+
+/// GA-FC switch numbers (1-6) written together, e.g. "15" for switches 1
+/// and 5
+fn switch_mask(s: &str) -> Result<u8, ParseError> {
+    let mut mask = 0;
+    for c in s.chars() {
+        match c.to_digit(10) {
+            Some(n @ 1..=6) => mask |= 1 << (n - 1),
+            _ => return Err(ParseError::BadArguments),
+        }
+    }
+    if mask == 0 {
+        return Err(ParseError::BadArguments);
+    }
+    Ok(mask)
+}
+
+/// An action in the same form as it's set
+pub fn format_action(action: Action) -> heapless::String<32> {
+    fn switches(s: &mut heapless::String<32>, mask: u8) {
+        for n in (0..8).filter(|n| mask & (1 << n) != 0) {
+            _ = s.push(char::from(b'1' + n));
+        }
+    }
+
+    let mut s = heapless::String::new();
+    match action {
+        Action::None => _ = s.push_str("off"),
+        Action::Footswitch(m) => {
+            _ = s.push_str("fs ");
+            switches(&mut s, m);
+        }
+        Action::Sequence(steps) => {
+            _ = s.push_str("seq");
+            for m in steps.into_iter().filter(|&m| m != 0) {
+                _ = s.push(' ');
+                switches(&mut s, m);
+            }
+        }
+        Action::Local(LocalAction::KeyboardMode) => _ = s.push_str("keyboard"),
+        Action::Local(LocalAction::NextLayer) => _ = s.push_str("layer"),
+        Action::Local(LocalAction::SetlistNext) => _ = s.push_str("next"),
+        Action::Local(LocalAction::SetlistPrev) => _ = s.push_str("prev"),
+        Action::Local(LocalAction::TapTempo) => _ = s.push_str("tap"),
+        Action::Local(LocalAction::Tuner) => _ = s.push_str("tuner"),
+        Action::Macro(i) => _ = write!(s, "macro {}", i + 1),
+    }
+    s
+}
+
+/// GA-FC switch combinations in the same form as they're set, e.g. "1 2 34"
+pub fn format_switches(masks: &[u8]) -> heapless::String<32> {
+    let mut s = heapless::String::new();
+    for &m in masks.iter().filter(|&&m| m != 0) {
+        if !s.is_empty() {
+            _ = s.push(' ');
+        }
+        for n in (0..8).filter(|n| m & (1 << n) != 0) {
+            _ = s.push(char::from(b'1' + n));
+        }
+    }
+    if s.is_empty() {
+        _ = s.push_str("off");
+    }
+    s
+}
+
+/// Macro steps in the same form as they're set
+pub fn format_macro(m: &Macro) -> heapless::String<128> {
+    let mut s = heapless::String::new();
+    if m.is_empty() {
+        _ = s.push_str("off");
+    }
+    for step in m.steps.iter().filter(|s| s.footswitches != 0) {
+        if !s.is_empty() {
+            _ = s.push(' ');
+        }
+        for n in (0..8).filter(|n| step.footswitches & (1 << n) != 0) {
+            _ = s.push(char::from(b'1' + n));
+        }
+        if step.delay_ms != 0 {
+            _ = write!(s, ":{}", step.delay_ms);
+        }
+    }
+    s
+}
+
+fn number(s: &str, min: u8, max: u8) -> Result<u8, ParseError> {
+    s.parse::<u8>()
+        .ok()
+        .filter(|n| (min..=max).contains(n))
+        .ok_or(ParseError::BadArguments)
+}
+
+/// Collects the bytes typed on a terminal into lines
+pub struct LineBuffer {
+    buf: heapless::Vec<u8, MAX_LINE>,
+    overflow: bool,
+    complete: bool,
+    last_cr: bool,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        LineBuffer {
+            buf: heapless::Vec::new(),
+            overflow: false,
+            complete: false,
+            last_cr: false,
+        }
+    }
+
+    /// Adds a received byte. Returns the line when a line ending is received.
+    /// Lines can end in CR, LF or CR LF. Too long lines are returned as
+    /// `Err`, and backspace removes the previous character.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, ()>> {
+        if self.complete {
+            self.buf.clear();
+            self.overflow = false;
+            self.complete = false;
+        }
+        let after_cr = core::mem::replace(&mut self.last_cr, byte == b'\r');
+
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                self.complete = true;
+                if self.overflow {
+                    Some(Err(()))
+                } else {
+                    // Only ASCII is stored
+                    Some(Ok(core::str::from_utf8(&self.buf).unwrap_or_default()))
+                }
+            }
+            0x08 | 0x7f => {
+                self.buf.pop();
+                None
+            }
+            0x20..=0x7e => {
+                if self.buf.push(byte).is_err() {
+                    self.overflow = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        format_action, format_led_map, format_macro, format_setlist_step, format_switches, parse,
+        Command, LineBuffer, ParseError, HELP, OUTPUT_LEN,
+    };
+    use crate::actions::{Action, LocalAction};
+    use crate::led_map::{LedMap, LedSource};
+    use crate::macros::{Macro, MacroStep};
+    use crate::setlist::SetlistStep;
+    use crate::midi::{MidiTrigger, SwitchMidi};
+    use crate::ws2812::Rgb;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("  "), None);
+        assert_eq!(parse("version"), Some(Ok(Command::Version)));
+        assert_eq!(
+            parse(" brightness  40 "),
+            Some(Ok(Command::SetBrightness(40)))
+        );
+        assert_eq!(
+            parse("midi 3 pc 12"),
+            Some(Ok(Command::SetMidi {
+                switch: 2,
+                midi: SwitchMidi::Pc { program: 12 }
+            }))
+        );
+        assert_eq!(
+            parse("midi 8 off"),
+            Some(Ok(Command::SetMidi {
+                switch: 7,
+                midi: SwitchMidi::None
+            }))
+        );
+        assert_eq!(parse("debounce 0"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("midi 9 cc 1"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("midi 1 cc 128"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("midi tempo 4"), Some(Ok(Command::SetMidiTempo(Some(4)))));
+        assert_eq!(parse("midi tempo off"), Some(Ok(Command::SetMidiTempo(None))));
+        assert_eq!(parse("midi tempo 32"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("layer 3"), Some(Ok(Command::SetLayer(2))));
+        assert_eq!(parse("layer 4"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("save now"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("reboot"), Some(Err(ParseError::UnknownCommand)));
+    }
+
+    #[test]
+    fn test_actions() {
+        let set = |switch, long_press, action| {
+            Some(Ok(Command::SetAction {
+                switch,
+                long_press,
+                action,
+            }))
+        };
+        assert_eq!(
+            parse("action 2 press fs 15"),
+            set(1, false, Action::Footswitch(0x11))
+        );
+        assert_eq!(
+            parse("action 6 long seq 2 1 3 4"),
+            set(5, true, Action::Sequence([0x02, 0x01, 0x04, 0x08]))
+        );
+        assert_eq!(
+            parse("action 1 long keyboard"),
+            set(0, true, Action::Local(LocalAction::KeyboardMode))
+        );
+        assert_eq!(parse("action 1 press fs 9"), Some(Err(ParseError::BadArguments)));
+        // The GA-FC has no switches 7 and 8
+        assert_eq!(parse("action 7 press fs 7"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("action 1 press seq 1 8"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(
+            parse("action 1 press seq 1 2 3 4 5"),
+            Some(Err(ParseError::BadArguments))
+        );
+
+        let actions = [
+            "off", "fs 15", "seq 2 1 34", "keyboard", "layer", "next", "prev", "tap", "tuner",
+            "macro 4",
+        ];
+        for a in actions {
+            let mut line: heapless::String<32> = "action 1 press ".try_into().unwrap();
+            line.push_str(a).unwrap();
+            let Some(Ok(Command::SetAction { action, .. })) = parse(&line) else {
+                panic!("{}", a);
+            };
+            assert_eq!(format_action(action), a);
+        }
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut m = Macro::default();
+        m.steps[0] = MacroStep {
+            footswitches: 0x10,
+            delay_ms: 200,
+        };
+        m.steps[1] = MacroStep {
+            footswitches: 0x03,
+            delay_ms: 0,
+        };
+        assert_eq!(
+            parse("macro 2 5:200 12"),
+            Some(Ok(Command::SetMacro { index: 1, steps: m }))
+        );
+        assert_eq!(format_macro(&m), "5:200 12");
+        assert_eq!(
+            parse("macro 1 off"),
+            Some(Ok(Command::SetMacro {
+                index: 0,
+                steps: Macro::default()
+            }))
+        );
+        assert_eq!(format_macro(&Macro::default()), "off");
+        assert_eq!(parse("macro 5 1"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("macro 1 1:x"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("macro 1 1:20000"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(
+            parse("macro 1 1 2 3 4 5 6 7 8 1"),
+            Some(Err(ParseError::BadArguments))
+        );
+    }
+
+    #[test]
+    fn test_setlist() {
+        let Some(Ok(Command::AddToSetlist(s))) = parse("setlist add a1 B3+boost-delay A4-reverb")
+        else {
+            panic!();
+        };
+        assert_eq!(s.len, 3);
+        assert_eq!(
+            s.steps[1],
+            SetlistStep {
+                channel: 6,
+                effects: 0x09,
+                effects_on: 0x01,
+            }
+        );
+        for (step, text) in s.steps().iter().zip(["A1", "B3+boost-delay", "A4-reverb"]) {
+            assert_eq!(format_setlist_step(step), text);
+        }
+
+        assert_eq!(parse("setlist clear"), Some(Ok(Command::ClearSetlist)));
+        for bad in ["setlist add", "setlist add C1", "setlist add A5", "setlist add A1+chorus"] {
+            assert_eq!(parse(bad), Some(Err(ParseError::BadArguments)), "{}", bad);
+        }
+        assert_eq!(parse("setlist add A1boost"), Some(Err(ParseError::BadArguments)));
+    }
+
+    #[test]
+    fn test_tuner() {
+        assert_eq!(parse("tuner"), Some(Ok(Command::ShowTuner)));
+        assert_eq!(parse("tuner 45"), Some(Ok(Command::SetTuner(0x18))));
+        assert_eq!(parse("tuner off"), Some(Ok(Command::SetTuner(0))));
+        assert_eq!(parse("tuner 7"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("tuner 4 5"), Some(Err(ParseError::BadArguments)));
+    }
+
+    #[test]
+    fn test_encoder() {
+        assert_eq!(parse("encoder"), Some(Ok(Command::ShowEncoder)));
+        assert_eq!(
+            parse("encoder scroll 1 2 34"),
+            Some(Ok(Command::SetEncoderScroll([0x01, 0x02, 0x0c, 0, 0, 0, 0, 0])))
+        );
+        assert_eq!(parse("encoder scroll off"), Some(Ok(Command::SetEncoderScroll([0; 8]))));
+        assert_eq!(parse("encoder push 5"), Some(Ok(Command::SetEncoderPush(0x10))));
+        assert_eq!(parse("encoder push off"), Some(Ok(Command::SetEncoderPush(0))));
+        for bad in ["encoder scroll", "encoder scroll 1 7", "encoder push 4 5", "encoder 1"] {
+            assert_eq!(parse(bad), Some(Err(ParseError::BadArguments)), "{}", bad);
+        }
+        assert_eq!(format_switches(&[0x01, 0x0c]), "1 34");
+        assert_eq!(format_switches(&[0]), "off");
+    }
+
+    #[test]
+    fn test_led_map() {
+        assert_eq!(parse("ledmap"), Some(Ok(Command::ShowLedMapping)));
+        assert_eq!(parse("ledmap board"), Some(Ok(Command::ResetLedMapping)));
+        let Some(Ok(Command::SetLedMap { led, map })) = parse("ledmap 6 !4 low #00ff00") else {
+            panic!();
+        };
+        assert_eq!(led, 5);
+        assert_eq!(
+            map,
+            LedMap {
+                source: LedSource::NotBit(4),
+                active_low: true,
+                colour: Some(Rgb::new(0, 0xff, 0)),
+            }
+        );
+        for m in ["off", "0", "!7 low", "3 #ff8000"] {
+            let mut line: heapless::String<32> = "ledmap 1 ".try_into().unwrap();
+            line.push_str(m).unwrap();
+            let Some(Ok(Command::SetLedMap { map, .. })) = parse(&line) else {
+                panic!("{}", m);
+            };
+            assert_eq!(format_led_map(&map), m);
+        }
+        for bad in ["ledmap 7 0", "ledmap 1 8", "ledmap 1", "ledmap 1 0 high", "ledmap 1 0 #fff"] {
+            assert_eq!(parse(bad), Some(Err(ParseError::BadArguments)), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_din() {
+        assert_eq!(parse("din"), Some(Ok(Command::ShowDin)));
+        assert_eq!(
+            parse("din 2 pc 5"),
+            Some(Ok(Command::SetDinMidi {
+                switch: 1,
+                midi: SwitchMidi::Pc { program: 5 }
+            }))
+        );
+        assert_eq!(parse("din tempo 6"), Some(Ok(Command::SetDinTempo(Some(6)))));
+        assert_eq!(
+            parse("din in cc 20 12"),
+            Some(Ok(Command::SetDinIn {
+                trigger: MidiTrigger::Cc(20),
+                footswitches: 0b11
+            }))
+        );
+        assert_eq!(
+            parse("din in pc 3 off"),
+            Some(Ok(Command::SetDinIn {
+                trigger: MidiTrigger::Program(3),
+                footswitches: 0
+            }))
+        );
+        assert_eq!(parse("din status pc off"), Some(Ok(Command::SetStatusPrograms(false))));
+        assert_eq!(
+            parse("din status delay cc 94"),
+            Some(Ok(Command::SetStatusControl {
+                effect: Some(3),
+                control: Some(94)
+            }))
+        );
+        assert_eq!(
+            parse("din status mode off"),
+            Some(Ok(Command::SetStatusControl {
+                effect: None,
+                control: None
+            }))
+        );
+        assert_eq!(parse("din in nrpn 1 1"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("din in cc 128 1"), Some(Err(ParseError::BadArguments)));
+        assert_eq!(parse("din status chorus cc 1"), Some(Err(ParseError::BadArguments)));
+    }
+
+    #[test]
+    fn test_help_fits_output() {
+        // Each line end is sent as CR LF, and the prompt follows
+        assert!(HELP.len() + HELP.lines().count() + 2 <= OUTPUT_LEN);
+    }
+
+    #[test]
+    fn test_line_buffer() {
+        let mut lb = LineBuffer::new();
+        let mut lines: heapless::Vec<Result<heapless::String<8>, ()>, 3> = heapless::Vec::new();
+        for b in b"verx\x7fsion\r\nstats\n\n" {
+            if let Some(line) = lb.push(*b) {
+                lines.push(line.map(|l| l.try_into().unwrap())).unwrap();
+            }
+        }
+        assert_eq!(
+            &lines[..],
+            &[
+                Ok("version".try_into().unwrap()),
+                Ok("stats".try_into().unwrap()),
+                Ok("".try_into().unwrap())
+            ]
+        );
+
+        for b in [b'a'; 70] {
+            assert_eq!(lb.push(b), None);
+        }
+        assert_eq!(lb.push(b'\r'), Some(Err(())));
+        assert_eq!(lb.push(b'\r'), Some(Ok("")));
+    }
+}
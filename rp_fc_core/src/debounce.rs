@@ -0,0 +1,206 @@
+use pio::Program;
+use pio_proc::pio_asm;
+
+/// The PIO debounce program for the footswitch buttons and the encoder.
+///
+/// The program samples the input pins continuously. When the pins change, it
+/// waits until they have stayed stable for `debounce_cycles` rounds of
+/// `debounce_cycle_instructions` state machine cycles and then pushes the
+/// new pin state to the RX FIFO.
+pub struct DebouncePioProgram {
+    pub program: Program<32>,
+    pub debounce_cycle_instructions: u8,
+    pub debounce_cycles: u8,
+}
+
+impl DebouncePioProgram {
+    /// Clock divisor for the state machine to get the given debounce time
+    pub fn clock_divisor(&self, sys_freq_hz: u32, debounce_time_ms: u32) -> u16 {
+        let total_debounce_instructions =
+            self.debounce_cycle_instructions as u32 * self.debounce_cycles as u32;
+        let target_clk_div = (debounce_time_ms * (sys_freq_hz / 1000)) // instructions during the target time
+            / total_debounce_instructions;
+        target_clk_div.min(u16::MAX as u32) as u16
+    }
+}
+
+/// Assembles the debounce program for reading `pin_count` consecutive pins.
+pub fn program(pin_count: u8) -> DebouncePioProgram {
+    assert!((1..=32).contains(&pin_count));
+
+    let p = pio_asm!(
+        "
+// Cycles per debounce round: in, mov, jmp, jmp, out [31], jmp [31], in
+.define public debounce_cycle_instructions 69
+.define public debounce_cycles 32
+
+start:
+    // Read the input pins (the bit count is patched to the pin count below)
+    in pins, 6
+
+    // Check if the value changed from previous iteration
+    mov y isr
+    jmp x!=y changed
+
+    // If we're in debounce period, jump to the debounce check
+    jmp !osre debounce
+
+    // No change detected, clear isr and start from beginning
+    jmp reset
+
+changed:
+    mov x y       // Store new button states to x
+    mov osr null  // Clear osr counter
+    jmp reset
+
+debounce:
+    out null, 1   [31]     // Shift one bit from the debounce counter
+    jmp !osre reset [31]   // Counter not empty, so still debouncing
+
+emit:
+    // Debouncing done
+    push noblock
+
+reset:
+    // Clear isr
+    in null, 32
+",
+    );
+
+    let mut program = p.program;
+
+    // The first instruction reads the input pins, make it read exactly our pins
+    let read_pins = &mut program.code[0];
+    debug_assert_eq!(*read_pins & 0xffe0, 0x4000, "Expected `in pins, n`");
+    *read_pins = (*read_pins & !0x1f) | (pin_count as u16 & 0x1f);
+
+    DebouncePioProgram {
+        program,
+        debounce_cycle_instructions: p
+            .public_defines
+            .debounce_cycle_instructions
+            .try_into()
+            .unwrap(),
+        debounce_cycles: p.public_defines.debounce_cycles.try_into().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::program;
+    use crate::pio_sim::{Config, StateMachine};
+
+    const RELEASED: u32 = 0x3f;
+
+    fn buttons_sm() -> (StateMachine, u64) {
+        let prog = program(6);
+        let debounce_cycles =
+            prog.debounce_cycle_instructions as u64 * prog.debounce_cycles as u64;
+        let config = Config {
+            in_shift_left: true,
+            ..Default::default()
+        };
+        (StateMachine::new(&prog.program, config).unwrap(), debounce_cycles)
+    }
+
+    /// Runs until the next push, returns the value and the cycles it took
+    fn run_until_push(sm: &mut StateMachine, pins: u32, max_cycles: u64) -> Option<(u32, u64)> {
+        let start = sm.cycles();
+        while sm.cycles() - start < max_cycles {
+            sm.step(pins).unwrap();
+            if let Some(v) = sm.rx_read() {
+                return Some((v, sm.cycles() - start));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_initial_state_pushed() {
+        let (mut sm, debounce_cycles) = buttons_sm();
+        let (v, _) = run_until_push(&mut sm, RELEASED, 2 * debounce_cycles).unwrap();
+        assert_eq!(v, RELEASED);
+        // Nothing more while the pins stay the same
+        assert_eq!(run_until_push(&mut sm, RELEASED, 10 * debounce_cycles), None);
+    }
+
+    #[test]
+    fn test_debounce_cycle_length() {
+        let (mut sm, debounce_cycles) = buttons_sm();
+        run_until_push(&mut sm, RELEASED, 2 * debounce_cycles).unwrap();
+
+        let (v, cycles) = run_until_push(&mut sm, 0x3e, 2 * debounce_cycles).unwrap();
+        assert_eq!(v, 0x3e);
+        // The change is noticed within one idle loop, and the push follows
+        // the full debounce period
+        assert!(cycles >= debounce_cycles, "{} < {}", cycles, debounce_cycles);
+        assert!(cycles <= debounce_cycles + 16, "{} > {}", cycles, debounce_cycles);
+    }
+
+    #[test]
+    fn test_bounce_rejected() {
+        let (mut sm, debounce_cycles) = buttons_sm();
+        run_until_push(&mut sm, RELEASED, 2 * debounce_cycles).unwrap();
+
+        // Contact bounce, shorter than the debounce time
+        for i in 0..20 {
+            let pins = if i % 2 == 0 { 0x3e } else { RELEASED };
+            sm.run(pins, debounce_cycles / 10).unwrap();
+            assert_eq!(sm.rx_read(), None);
+        }
+
+        // Only the settled state is pushed, a full debounce period after the last bounce
+        let (v, cycles) = run_until_push(&mut sm, 0x3e, 2 * debounce_cycles).unwrap();
+        assert_eq!(v, 0x3e);
+        assert!(cycles >= debounce_cycles);
+        assert_eq!(sm.rx_read(), None);
+    }
+
+    #[test]
+    fn test_short_glitch_pushes_unchanged_state() {
+        let (mut sm, debounce_cycles) = buttons_sm();
+        run_until_push(&mut sm, RELEASED, 2 * debounce_cycles).unwrap();
+
+        // A glitch restarts the debounce, and the unchanged state gets pushed again
+        sm.run(0x3e, 200).unwrap();
+        let (v, _) = run_until_push(&mut sm, RELEASED, 2 * debounce_cycles).unwrap();
+        assert_eq!(v, RELEASED);
+    }
+
+    #[test]
+    fn test_fifo_overflow_drops_newest() {
+        let (mut sm, debounce_cycles) = buttons_sm();
+        // Don't read the FIFO: initial state + 4 changes
+        let states = [RELEASED, 0x3e, 0x3c, 0x38, 0x30];
+        for s in states {
+            sm.run(s, 2 * debounce_cycles).unwrap();
+        }
+        assert_eq!(sm.rx_overflows(), 1);
+        for s in &states[..4] {
+            assert_eq!(sm.rx_read(), Some(*s));
+        }
+    }
+
+    #[test]
+    fn test_pin_count_patched() {
+        let prog = program(3);
+        let config = Config {
+            in_shift_left: true,
+            ..Default::default()
+        };
+        let mut sm = StateMachine::new(&prog.program, config).unwrap();
+        let debounce_cycles =
+            prog.debounce_cycle_instructions as u64 * prog.debounce_cycles as u64;
+        let (v, _) = run_until_push(&mut sm, 0xff, 2 * debounce_cycles).unwrap();
+        assert_eq!(v, 0x7);
+    }
+
+    #[test]
+    fn test_clock_divisor() {
+        let prog = program(6);
+        // 5 ms at 125 MHz
+        let div = prog.clock_divisor(125_000_000, 5);
+        let debounce_ns = div as u64 * 8 * prog.debounce_cycles as u64 * prog.debounce_cycle_instructions as u64;
+        assert!((4_900_000..=5_000_000).contains(&debounce_ns));
+    }
+}
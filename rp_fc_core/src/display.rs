@@ -0,0 +1,221 @@
+use crate::amp_state::{AmpState, Bank, Selection};
+use core::fmt::Write;
+use heapless::String;
+
+/// Text size of a 128x64 display with an 8x8 font
+pub const COLUMNS: usize = 16;
+pub const ROWS: usize = 8;
+
+pub type Lines = [[u8; COLUMNS]; ROWS];
+
+/// What the status display shows: the amp's bank and channel, the link
+/// state and a transient message.
+pub struct StatusView {
+    pub amp: Option<AmpState>,
+    pub link_up: bool,
+    message: Option<(String<COLUMNS>, u64)>,
+}
+
+impl Default for StatusView {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusView {
+    pub const fn new() -> Self {
+        StatusView {
+            amp: None,
+            link_up: false,
+            message: None,
+        }
+    }
+
+    /// Shows a message, e.g. "Tuner", until `until_ms`. Cut to the display
+    /// width.
+    pub fn show_message(&mut self, text: &str, until_ms: u64) {
+        let mut msg = String::new();
+        for c in text.chars() {
+            if msg.push(c).is_err() {
+                break;
+            }
+        }
+        self.message = Some((msg, until_ms));
+    }
+
+    pub fn clear_message(&mut self) {
+        self.message = None;
+    }
+
+    /// Display contents at the given time
+    pub fn lines(&self, now_ms: u64) -> Lines {
+        let mut lines = [[b' '; COLUMNS]; ROWS];
+
+        let link = if self.link_up { "LINK OK" } else { "NO LINK" };
+        put(&mut lines[0], format_args!("RP-FC    {}", link));
+
+        match &self.amp {
+            Some(a) if a.effect_mode => {
+                put(&mut lines[2], format_args!("EFFECT MODE"));
+                let mut effects = [b'.'; 9];
+                for (i, name) in b"BMFDR".iter().enumerate() {
+                    if a.effect_on(1 << i) == Some(true) {
+                        effects[2 * i] = *name;
+                    }
+                    if i < 4 {
+                        effects[2 * i + 1] = b' ';
+                    }
+                }
+                lines[4][..effects.len()].copy_from_slice(&effects);
+            }
+            Some(a) => {
+                let bank = match a.bank {
+                    Some(Bank::A) => 'A',
+                    Some(Bank::B) => 'B',
+                    None => '-',
+                };
+                match a.selection {
+                    Some(Selection::Panel) => {
+                        put(&mut lines[2], format_args!("BANK {}   PANEL", bank))
+                    }
+                    Some(Selection::Channel(ch)) => {
+                        put(&mut lines[2], format_args!("BANK {}   CH {}", bank, ch + 1))
+                    }
+                    None => put(&mut lines[2], format_args!("BANK {}", bank)),
+                }
+            }
+            None => {}
+        }
+
+        if let Some((msg, until_ms)) = &self.message {
+            if now_ms < *until_ms {
+                put(&mut lines[6], format_args!("{}", msg));
+            }
+        }
+
+        lines
+    }
+}
+
+/// Writes formatted text to the start of a line, cut to the line length
+fn put(line: &mut [u8; COLUMNS], args: core::fmt::Arguments) {
+    let mut s = String::<32>::new();
+    _ = s.write_fmt(args);
+    for (dst, c) in line.iter_mut().zip(s.bytes()) {
+        *dst = c;
+    }
+}
+
+/// The text on a character display, and what has been drawn of it so far.
+///
+/// Writing a whole screen over I2C takes tens of milliseconds, so the
+/// display is updated a few changed characters at a time instead.
+pub struct TextScreen {
+    target: Lines,
+    shown: Lines,
+}
+
+impl Default for TextScreen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TextScreen {
+    /// A screen that has just been cleared
+    pub const fn new() -> Self {
+        TextScreen {
+            target: [[b' '; COLUMNS]; ROWS],
+            shown: [[b' '; COLUMNS]; ROWS],
+        }
+    }
+
+    pub fn set(&mut self, lines: &Lines) {
+        self.target = *lines;
+    }
+
+    /// Next character that differs from what is shown, as (column, row,
+    /// character). It's marked as shown.
+    pub fn next_change(&mut self) -> Option<(u8, u8, u8)> {
+        for (row, (target, shown)) in self.target.iter().zip(&mut self.shown).enumerate() {
+            for (col, (t, s)) in target.iter().zip(shown.iter_mut()).enumerate() {
+                if t != s {
+                    *s = *t;
+                    return Some((col as u8, row as u8, *t));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StatusView, TextScreen, COLUMNS};
+    use crate::amp_state::{AmpState, AmpTracker};
+    use katana_sysex::led_status;
+
+    fn amp(status: u8) -> Option<AmpState> {
+        let mut t = AmpTracker::new();
+        t.on_led_status(status);
+        t.state().copied()
+    }
+
+    fn text(line: &[u8; COLUMNS]) -> &str {
+        core::str::from_utf8(line).unwrap().trim_end()
+    }
+
+    #[test]
+    fn test_channel_lines() {
+        let mut v = StatusView::new();
+        assert_eq!(text(&v.lines(0)[0]), "RP-FC    NO LINK");
+        assert_eq!(text(&v.lines(0)[2]), "");
+
+        v.link_up = true;
+        v.amp = amp(led_status::CH3 | led_status::BANK);
+        let lines = v.lines(0);
+        assert_eq!(text(&lines[0]), "RP-FC    LINK OK");
+        assert_eq!(text(&lines[2]), "BANK B   CH 3");
+
+        v.amp = amp(0);
+        assert_eq!(text(&v.lines(0)[2]), "BANK A   PANEL");
+    }
+
+    #[test]
+    fn test_effect_lines() {
+        let mut v = StatusView::new();
+        v.amp = amp(led_status::EFFECT_MODE | led_status::BOOST | led_status::DELAY);
+        let lines = v.lines(0);
+        assert_eq!(text(&lines[2]), "EFFECT MODE");
+        assert_eq!(text(&lines[4]), "B . . D .");
+    }
+
+    #[test]
+    fn test_message_expires() {
+        let mut v = StatusView::new();
+        v.show_message("Tap 120 BPM and then some", 1_000);
+        assert_eq!(text(&v.lines(999)[6]), "Tap 120 BPM and");
+        assert_eq!(text(&v.lines(1_000)[6]), "");
+    }
+
+    #[test]
+    fn test_screen_changes() {
+        let mut s = TextScreen::new();
+        assert_eq!(s.next_change(), None);
+
+        let mut v = StatusView::new();
+        v.amp = amp(led_status::CH1);
+        s.set(&v.lines(0));
+        // Only the non-blank characters get drawn
+        let mut count = 0;
+        while s.next_change().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, "RP-FC".len() + "NOLINK".len() + "BANKA".len() + "CH1".len());
+
+        v.amp = amp(led_status::CH2);
+        s.set(&v.lines(0));
+        assert_eq!(s.next_change(), Some((12, 2, b'2')));
+        assert_eq!(s.next_change(), None);
+    }
+}
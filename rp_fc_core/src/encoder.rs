@@ -0,0 +1,423 @@
+use crate::amp_state::AmpState;
+use crate::held::SwitchInput;
+use defmt::Format;
+use katana_sysex::footswitch;
+use pio::{Instruction, InstructionOperands, MovDestination, MovOperation, MovSource, Program};
+use pio_proc::pio_asm;
+
+/// Quadrature transitions between two detents of the encoder
+const TRANSITIONS_PER_DETENT: i32 = 4;
+
+/// Most GA-FC switch combinations the encoder steps through
+pub const MAX_SCROLL: usize = 8;
+
+/// State machine clock for the quadrature program. A sample takes at most
+/// 8 cycles, so up to 125 000 transitions per second (over 30 000 detents
+/// per second) are counted without missing any.
+pub const QUADRATURE_CLOCK_HZ: u32 = 1_000_000;
+
+/// The PIO quadrature decoder for the rotary encoder A and B pins.
+///
+/// The program samples the two pins in a loop and looks up each (previous,
+/// new) state pair in a jump table, so it must be loaded at address 0. X
+/// counts down on every step forward and Y on every step back, and after
+/// each step the low halves of both, X in the upper 16 bits, are pushed to
+/// the RX FIFO. The counts are totals, so a push dropped on a full FIFO is
+/// made up for by the next one. Invalid transitions, with both pins
+/// changed, are ignored.
+///
+/// The state machine needs left shifting ISR, right shifting OSR and the
+/// counters reset with [`counter_reset`] before it's started.
+pub fn quadrature_program() -> Program<32> {
+    let p = pio_asm!(
+        "
+.origin 0
+    // Jump table indexed by (previous AB << 2) | new AB
+    jmp sample          // 00 -> 00
+    jmp y-- changed     // 00 -> 01
+    jmp x-- changed     // 00 -> 10
+    jmp sample          // 00 -> 11
+    jmp x-- changed     // 01 -> 00
+    jmp sample          // 01 -> 01
+    jmp sample          // 01 -> 10
+    jmp y-- changed     // 01 -> 11
+    jmp y-- changed     // 10 -> 00
+    jmp sample          // 10 -> 01
+    jmp sample          // 10 -> 10
+    jmp x-- changed     // 10 -> 11
+    jmp sample          // 11 -> 00
+    jmp x-- changed     // 11 -> 01
+    jmp y-- changed     // 11 -> 10
+.wrap_target
+sample:                 // 11 -> 11
+    out isr, 2          // The previous AB, from the last index
+    in pins, 2
+    mov osr, isr
+    mov pc, isr
+
+changed:
+    in x, 16
+    in y, 16
+    push noblock
+.wrap
+",
+    );
+    p.program
+}
+
+/// Instructions to run on the quadrature state machine before starting it.
+/// The counters start from all ones, so that `jmp x--` and `jmp y--` always
+/// jump.
+pub fn counter_reset() -> [Instruction; 2] {
+    [MovDestination::X, MovDestination::Y].map(|destination| Instruction {
+        operands: InstructionOperands::MOV {
+            destination,
+            op: MovOperation::Invert,
+            source: MovSource::NULL,
+        },
+        delay: 0,
+        side_set: None,
+    })
+}
+
+/// The PIO debounce program for the encoder push switch.
+///
+/// Pushes 0 as soon as the (active low) switch pin goes low and 1 when it
+/// goes high, and ignores the pin for `lockout_cycles` state machine cycles
+/// after each change. The presses and releases always alternate. The state
+/// machine needs autopush with a threshold of 1.
+pub struct SwitchPioProgram {
+    pub program: Program<32>,
+    pub lockout_cycles: u32,
+}
+
+impl SwitchPioProgram {
+    /// Clock divisor for the state machine to get the given lockout time
+    pub fn clock_divisor(&self, sys_freq_hz: u32, lockout_time_ms: u32) -> u16 {
+        let target_clk_div = lockout_time_ms * (sys_freq_hz / 1000) / self.lockout_cycles;
+        target_clk_div.min(u16::MAX as u32) as u16
+    }
+}
+
+pub fn switch_program() -> SwitchPioProgram {
+    let p = pio_asm!(
+        "
+// The lockout loops, 32 rounds of 32 cycles
+.define public lockout_cycles 1024
+
+.wrap_target
+    wait 0 pin 0
+    in null, 1                  // Pressed, autopush 0
+    set x, 31
+pressed:
+    jmp x-- pressed [31]
+
+    wait 1 pin 0
+    in x, 1                     // Released, autopush 1: X is all ones after the loop
+    set x, 31
+released:
+    jmp x-- released [31]
+.wrap
+",
+    );
+
+    SwitchPioProgram {
+        program: p.program,
+        lockout_cycles: p.public_defines.lockout_cycles.try_into().unwrap(),
+    }
+}
+
+/// Turns the step counts pushed by [`quadrature_program`] into detents.
+pub struct Quadrature {
+    forward: u16,
+    back: u16,
+    transitions: i32,
+}
+
+impl Default for Quadrature {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Quadrature {
+    pub const fn new() -> Self {
+        // Matches `counter_reset`
+        Quadrature {
+            forward: u16::MAX,
+            back: u16::MAX,
+            transitions: 0,
+        }
+    }
+
+    /// Takes a word from the RX FIFO and returns the detents turned since
+    /// the previous one, positive forward.
+    pub fn update(&mut self, word: u32) -> i32 {
+        let (forward, back) = ((word >> 16) as u16, word as u16);
+        // The counters count down
+        self.transitions += i32::from(self.forward.wrapping_sub(forward))
+            - i32::from(self.back.wrapping_sub(back));
+        self.forward = forward;
+        self.back = back;
+
+        let detents = self.transitions / TRANSITIONS_PER_DETENT;
+        self.transitions -= detents * TRANSITIONS_PER_DETENT;
+        detents
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum EncoderEvent {
+    Increment,
+    Decrement,
+    Pressed,
+    Released,
+}
+
+/// What the encoder does to the GA-FC switches.
+///
+/// The GA-FC protocol only carries footswitch states, so the encoder can't
+/// set amp parameters directly. Turning it steps through a list of switch
+/// combinations (e.g. the channel switches, to scroll through the
+/// channels), tapping each, and pushing it holds more switches down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct EncoderMapping {
+    scroll: [u8; MAX_SCROLL],
+    scroll_len: u8,
+    /// GA-FC switches held while the encoder is pushed, 0 for none
+    pub push_switches: u8,
+}
+
+impl Default for EncoderMapping {
+    /// Scrolls through channels 1-4 and pushes footswitch 5
+    fn default() -> Self {
+        let channels = [0x01, 0x02, 0x04, 0x08];
+        Self::new(&channels, 0x10).unwrap()
+    }
+}
+
+impl EncoderMapping {
+    /// `None` with more than [`MAX_SCROLL`] switch combinations
+    pub fn new(scroll_switches: &[u8], push_switches: u8) -> Option<Self> {
+        let mut scroll = [0; MAX_SCROLL];
+        scroll.get_mut(..scroll_switches.len())?.copy_from_slice(scroll_switches);
+        Some(EncoderMapping {
+            scroll,
+            scroll_len: scroll_switches.len() as u8,
+            push_switches,
+        })
+    }
+
+    pub fn scroll_switches(&self) -> &[u8] {
+        &self.scroll[..usize::from(self.scroll_len)]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.scroll_switches().iter().all(|&m| m != 0 && m & !footswitch::ALL == 0)
+            && self.push_switches & !footswitch::ALL == 0
+    }
+}
+
+/// Where the encoder is in its list of switch combinations
+#[derive(Default)]
+pub struct EncoderScroll {
+    /// `None` until the first turn or amp status
+    position: Option<usize>,
+}
+
+impl EncoderScroll {
+    pub const fn new() -> Self {
+        EncoderScroll { position: None }
+    }
+
+    /// What an encoder event does to the GA-FC switches, if anything
+    pub fn input(&mut self, mapping: &EncoderMapping, ev: EncoderEvent) -> Option<SwitchInput> {
+        let scroll = mapping.scroll_switches();
+        let len = scroll.len();
+        match ev {
+            EncoderEvent::Increment | EncoderEvent::Decrement if len > 0 => {
+                let position = match (self.position, ev) {
+                    (None, EncoderEvent::Increment) => 0,
+                    (None, _) => len - 1,
+                    (Some(p), EncoderEvent::Increment) => (p + 1) % len,
+                    (Some(p), _) => (p + len - 1) % len,
+                };
+                self.position = Some(position);
+                Some(SwitchInput::Tap(scroll[position]))
+            }
+            EncoderEvent::Pressed if mapping.push_switches != 0 => {
+                Some(SwitchInput::Hold(mapping.push_switches))
+            }
+            EncoderEvent::Released if mapping.push_switches != 0 => {
+                Some(SwitchInput::Release(mapping.push_switches))
+            }
+            _ => None,
+        }
+    }
+
+    /// Moves to the amp's channel if its switch is in the list, so turning
+    /// goes on from the channel selected on the amp or by the footswitches
+    pub fn follow(&mut self, mapping: &EncoderMapping, amp: &AmpState) {
+        let Some(channel) = amp.channel() else {
+            return;
+        };
+        let switch = 1 << (channel % 4);
+        if let Some(p) = mapping.scroll_switches().iter().position(|&m| m == switch) {
+            self.position = Some(p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        counter_reset, quadrature_program, switch_program, EncoderEvent, EncoderMapping,
+        EncoderScroll, Quadrature,
+    };
+    use crate::amp_state::AmpTracker;
+    use crate::held::SwitchInput;
+    use crate::pio_sim::{Config, StateMachine};
+    use katana_sysex::led_status;
+    use pio::SideSet;
+
+    /// Pin states (bit 0 A, bit 1 B) for one detent from the rest position
+    const FORWARD: [u32; 4] = [0b01, 0b00, 0b10, 0b11];
+    const BACK: [u32; 4] = [0b10, 0b00, 0b01, 0b11];
+
+    fn quadrature_sm() -> StateMachine {
+        let config = Config {
+            in_shift_left: true,
+            ..Default::default()
+        };
+        let mut sm = StateMachine::new(&quadrature_program(), config).unwrap();
+        for i in counter_reset() {
+            sm.exec(i.encode(SideSet::default())).unwrap();
+        }
+        // Settle at the rest position
+        sm.run(0b11, 20).unwrap();
+        sm
+    }
+
+    /// Runs the pin states, each for `cycles`, and decodes the pushes
+    fn turn(sm: &mut StateMachine, q: &mut Quadrature, states: &[u32], cycles: u64) -> i32 {
+        let mut detents = 0;
+        for s in states {
+            for _ in 0..cycles {
+                sm.step(*s).unwrap();
+                while let Some(w) = sm.rx_read() {
+                    detents += q.update(w);
+                }
+            }
+        }
+        detents
+    }
+
+    #[test]
+    fn test_detents() {
+        let mut sm = quadrature_sm();
+        let mut q = Quadrature::new();
+        // The first reading after the reset is ignored as an invalid transition
+        assert_eq!(sm.rx_read(), None);
+
+        assert_eq!(turn(&mut sm, &mut q, &FORWARD, 20), 1);
+        assert_eq!(turn(&mut sm, &mut q, &FORWARD.repeat(3), 20), 3);
+        assert_eq!(turn(&mut sm, &mut q, &BACK.repeat(2), 20), -2);
+
+        // Half a detent and back
+        assert_eq!(turn(&mut sm, &mut q, &[0b01, 0b00, 0b01, 0b11], 20), 0);
+        assert_eq!(turn(&mut sm, &mut q, &FORWARD, 20), 1);
+    }
+
+    #[test]
+    fn test_fast_turn() {
+        // One transition every 8 cycles is the maximum rate
+        let mut sm = quadrature_sm();
+        let mut q = Quadrature::new();
+        let detents = turn(&mut sm, &mut q, &FORWARD.repeat(100), 8);
+        // The last push may come after the pins stopped changing
+        assert_eq!(detents + turn(&mut sm, &mut q, &[0b11], 20), 100);
+    }
+
+    #[test]
+    fn test_bounce() {
+        let mut sm = quadrature_sm();
+        let mut q = Quadrature::new();
+        // A bounces on every change, but the steps back cancel out
+        let bouncy: [u32; 12] = [
+            0b01, 0b11, 0b01, 0b00, 0b01, 0b00, 0b10, 0b00, 0b10, 0b11, 0b10, 0b11,
+        ];
+        assert_eq!(turn(&mut sm, &mut q, &bouncy, 10), 1);
+    }
+
+    #[test]
+    fn test_dropped_push() {
+        let mut sm = quadrature_sm();
+        let mut q = Quadrature::new();
+        // The FIFO fills up while nothing reads it
+        for s in FORWARD.repeat(2) {
+            sm.run(s, 20).unwrap();
+        }
+        assert!(sm.rx_overflows() > 0);
+        // The totals catch up with the next push after the stale ones
+        assert_eq!(turn(&mut sm, &mut q, &FORWARD, 20), 3);
+    }
+
+    #[test]
+    fn test_switch_lockout() {
+        let prog = switch_program();
+        let config = Config {
+            in_shift_left: true,
+            autopush: true,
+            push_threshold: 1,
+            ..Default::default()
+        };
+        let mut sm = StateMachine::new(&prog.program, config).unwrap();
+        let lockout = u64::from(prog.lockout_cycles);
+        sm.run(1, 100).unwrap();
+        assert_eq!(sm.rx_read(), None);
+
+        // Pressed right away, and the bounces after that are ignored
+        sm.run(0, 3).unwrap();
+        assert_eq!(sm.rx_read(), Some(0));
+        for i in 0..10 {
+            sm.run(i % 2, lockout / 20).unwrap();
+        }
+        sm.run(0, lockout).unwrap();
+        assert_eq!(sm.rx_read(), None);
+
+        sm.run(1, 3).unwrap();
+        assert_eq!(sm.rx_read(), Some(1));
+        sm.run(0, lockout / 2).unwrap();
+        assert_eq!(sm.rx_read(), None);
+        sm.run(0, lockout).unwrap();
+        assert_eq!(sm.rx_read(), Some(0));
+    }
+
+    #[test]
+    fn test_scroll() {
+        let m = EncoderMapping::default();
+        let mut s = EncoderScroll::new();
+        assert_eq!(s.input(&m, EncoderEvent::Decrement), Some(SwitchInput::Tap(0x08)));
+        assert_eq!(s.input(&m, EncoderEvent::Increment), Some(SwitchInput::Tap(0x01)));
+        assert_eq!(s.input(&m, EncoderEvent::Pressed), Some(SwitchInput::Hold(0x10)));
+        assert_eq!(s.input(&m, EncoderEvent::Released), Some(SwitchInput::Release(0x10)));
+
+        // From the amp's channel 3 in bank B
+        let mut amp = AmpTracker::new();
+        amp.on_led_status(led_status::BANK | led_status::CH3);
+        s.follow(&m, amp.state().unwrap());
+        assert_eq!(s.input(&m, EncoderEvent::Increment), Some(SwitchInput::Tap(0x08)));
+
+        let m = EncoderMapping::new(&[], 0).unwrap();
+        assert_eq!(s.input(&m, EncoderEvent::Increment), None);
+        assert_eq!(s.input(&m, EncoderEvent::Pressed), None);
+        assert_eq!(EncoderMapping::new(&[1; 9], 0), None);
+    }
+
+    #[test]
+    fn test_switch_clock_divisor() {
+        // 5 ms at 125 MHz
+        let div = switch_program().clock_divisor(125_000_000, 5);
+        assert_eq!(div, 610);
+    }
+}
@@ -0,0 +1,111 @@
+use defmt::Format;
+
+/// What an input other than the footswitches does to the GA-FC switches
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum SwitchInput {
+    /// Press and release the switches
+    Tap(u8),
+    /// Hold the switches down until released by the same source
+    Hold(u8),
+    Release(u8),
+}
+
+/// Inputs that can hold GA-FC switches down
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum HoldSource {
+    UsbMidi,
+    DinMidi,
+    Encoder,
+}
+
+const SOURCE_COUNT: usize = 3;
+
+/// The GA-FC switches held down by MIDI control changes and the encoder
+/// push switch.
+///
+/// Every footswitch state sent to the amp, the periodic status message
+/// included, carries these on top of the footswitch and macro state, so a
+/// change from one input doesn't release what another one holds.
+#[derive(Default)]
+pub struct Holds {
+    held: [u8; SOURCE_COUNT],
+}
+
+impl Holds {
+    pub const fn new() -> Self {
+        Holds {
+            held: [0; SOURCE_COUNT],
+        }
+    }
+
+    /// The switches held by all the sources
+    pub fn state(&self) -> u8 {
+        self.held.iter().fold(0, |state, h| state | h)
+    }
+
+    /// Applies an input. Returns the footswitch states to send, given the
+    /// state of the footswitches and macros.
+    pub fn apply(
+        &mut self,
+        source: HoldSource,
+        input: SwitchInput,
+        others: u8,
+    ) -> heapless::Vec<u8, 2> {
+        let held = &mut self.held[source as usize];
+        let tapped = match input {
+            SwitchInput::Tap(m) => m,
+            SwitchInput::Hold(m) => {
+                *held |= m;
+                0
+            }
+            SwitchInput::Release(m) => {
+                *held &= !m;
+                0
+            }
+        };
+        let state = others | self.state();
+        let mut states = heapless::Vec::new();
+        if tapped != 0 {
+            _ = states.push(state | tapped);
+        }
+        _ = states.push(state);
+        states
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HoldSource, Holds, SwitchInput};
+
+    #[test]
+    fn test_holds() {
+        let mut h = Holds::new();
+        assert_eq!(
+            &h.apply(HoldSource::UsbMidi, SwitchInput::Hold(0x01), 0x20)[..],
+            &[0x21]
+        );
+        assert_eq!(
+            &h.apply(HoldSource::DinMidi, SwitchInput::Hold(0x03), 0)[..],
+            &[0x03]
+        );
+        assert_eq!(h.state(), 0x03);
+
+        // A tap, and the status message, keep the holds
+        assert_eq!(
+            &h.apply(HoldSource::DinMidi, SwitchInput::Tap(0x10), 0x20)[..],
+            &[0x33, 0x23]
+        );
+        assert_eq!(0x20 | h.state(), 0x23);
+
+        // Switch 1 stays held by USB MIDI
+        assert_eq!(
+            &h.apply(HoldSource::DinMidi, SwitchInput::Release(0x03), 0)[..],
+            &[0x01]
+        );
+        assert_eq!(
+            &h.apply(HoldSource::UsbMidi, SwitchInput::Release(0x01), 0x20)[..],
+            &[0x20]
+        );
+        assert_eq!(h.state(), 0);
+    }
+}
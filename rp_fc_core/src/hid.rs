@@ -0,0 +1,136 @@
+use defmt::Format;
+
+/// Keyboard usage IDs (USB HID usage tables, page 0x07)
+pub mod keys {
+    pub const SPACE: u8 = 0x2c;
+    pub const HOME: u8 = 0x4a;
+    pub const PAGE_UP: u8 = 0x4b;
+    pub const END: u8 = 0x4d;
+    pub const PAGE_DOWN: u8 = 0x4e;
+    pub const RIGHT: u8 = 0x4f;
+    pub const LEFT: u8 = 0x50;
+    pub const DOWN: u8 = 0x51;
+    pub const UP: u8 = 0x52;
+}
+
+/// Consumer control usage IDs (USB HID usage tables, page 0x0c)
+pub mod media {
+    pub const NEXT_TRACK: u16 = 0xb5;
+    pub const PREV_TRACK: u16 = 0xb6;
+    pub const STOP: u16 = 0xb7;
+    pub const PLAY_PAUSE: u16 = 0xcd;
+    pub const MUTE: u16 = 0xe2;
+    pub const VOLUME_UP: u16 = 0xe9;
+    pub const VOLUME_DOWN: u16 = 0xea;
+}
+
+/// What a switch sends in keyboard mode. The key is held as long as the
+/// switch is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum SwitchKey {
+    None,
+    /// Keyboard key with modifiers (bit 0 left ctrl, 1 left shift, 2 left
+    /// alt, 3 left GUI)
+    Key { modifiers: u8, key: u8 },
+    Media(u16),
+}
+
+/// HID reports for the state of the switches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyReport {
+    pub modifiers: u8,
+    pub keys: [u8; 6],
+    pub media: u16,
+}
+
+/// Footswitch to key mapping for keyboard mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct KeyMapping {
+    pub switches: [SwitchKey; 8],
+}
+
+impl Default for KeyMapping {
+    /// Page turning on 1-2, scrolling on 3-4 and transport on 5-6
+    fn default() -> Self {
+        let key = |key| SwitchKey::Key { modifiers: 0, key };
+        KeyMapping {
+            switches: [
+                key(keys::LEFT),
+                key(keys::RIGHT),
+                key(keys::PAGE_UP),
+                key(keys::PAGE_DOWN),
+                key(keys::SPACE),
+                SwitchKey::Media(media::PLAY_PAUSE),
+                SwitchKey::None,
+                SwitchKey::None,
+            ],
+        }
+    }
+}
+
+impl KeyMapping {
+    /// Reports for the held switches. Up to six keys and one media key are
+    /// reported at a time, the lowest switches first.
+    pub fn report(&self, state: u8) -> KeyReport {
+        let mut report = KeyReport::default();
+        let mut key_count = 0;
+        for (i, sk) in self.switches.iter().enumerate() {
+            if state & (1 << i) == 0 {
+                continue;
+            }
+            match *sk {
+                SwitchKey::None => {}
+                SwitchKey::Key { modifiers, key } => {
+                    if key_count < report.keys.len() {
+                        report.keys[key_count] = key;
+                        report.modifiers |= modifiers;
+                        key_count += 1;
+                    }
+                }
+                SwitchKey::Media(usage) => {
+                    if report.media == 0 {
+                        report.media = usage;
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{keys, media, KeyMapping, KeyReport, SwitchKey};
+
+    #[test]
+    fn test_default_report() {
+        let m = KeyMapping::default();
+        assert_eq!(m.report(0), KeyReport::default());
+        assert_eq!(
+            m.report(0b100010),
+            KeyReport {
+                modifiers: 0,
+                keys: [keys::RIGHT, 0, 0, 0, 0, 0],
+                media: media::PLAY_PAUSE,
+            }
+        );
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mut m = KeyMapping::default();
+        // Shift+space, e.g. play from the start
+        m.switches[4] = SwitchKey::Key {
+            modifiers: 0x02,
+            key: keys::SPACE,
+        };
+        assert_eq!(
+            m.report(0b10001),
+            KeyReport {
+                modifiers: 0x02,
+                keys: [keys::LEFT, keys::SPACE, 0, 0, 0, 0],
+                media: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum LedPattern {
+    Off,
+    On,
+    /// On for the first half of each period
+    Blink { period_ms: u16 },
+    /// Fades smoothly in and out once per period
+    Breathe { period_ms: u16 },
+    /// Flashes at the start of each period, counting from `start_ms`, e.g.
+    /// in time with tap tempo taps
+    Beat { period_ms: u16, start_ms: u32 },
+}
+
+impl LedPattern {
+    /// Whether the brightness changes over time
+    pub fn is_animated(&self) -> bool {
+        matches!(
+            self,
+            LedPattern::Blink { .. } | LedPattern::Breathe { .. } | LedPattern::Beat { .. }
+        )
+    }
+
+    /// Brightness level (0-255) of the pattern at the given time
+    pub fn level(&self, time_ms: u32) -> u8 {
+        match *self {
+            LedPattern::Off => 0,
+            LedPattern::On => 255,
+            LedPattern::Blink { period_ms } => {
+                let period_ms = period_ms.max(1) as u32;
+                if time_ms % period_ms < period_ms / 2 {
+                    255
+                } else {
+                    0
+                }
+            }
+            LedPattern::Breathe { period_ms } => {
+                // Triangle wave, the gamma correction makes it look smooth
+                let period_ms = period_ms.max(2) as u32;
+                let half = period_ms / 2;
+                let phase = time_ms % period_ms;
+                let ramp = if phase < half { phase } else { period_ms - phase };
+                (ramp * 255 / half).min(255) as u8
+            }
+            LedPattern::Beat {
+                period_ms,
+                start_ms,
+            } => {
+                let period_ms = period_ms.max(1) as u32;
+                if time_ms.wrapping_sub(start_ms) % period_ms < period_ms / 4 {
+                    255
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+/// Shows a layer number instead of the amp status: the first `layer + 1`
+/// LEDs blink quickly and the others are off
+pub fn layer_patterns<const N: usize>(layer: usize) -> [LedPattern; N] {
+    let mut patterns = [LedPattern::Off; N];
+    for p in patterns.iter_mut().take(layer + 1) {
+        *p = LedPattern::Blink { period_ms: 200 };
+    }
+    patterns
+}
+
+/// Shows tuner mode instead of the amp status: all the LEDs breathe slowly
+pub fn tuner_patterns<const N: usize>() -> [LedPattern; N] {
+    [LedPattern::Breathe { period_ms: 2000 }; N]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedState {
+    pub pattern: LedPattern,
+    /// Brightness of the LED when fully on (0-255)
+    pub brightness: u8,
+}
+
+impl Default for LedState {
+    fn default() -> Self {
+        LedState {
+            pattern: LedPattern::Off,
+            brightness: 255,
+        }
+    }
+}
+
+/// Patterns and brightness settings of a group of PWM-dimmed LEDs
+pub struct LedBank<const N: usize> {
+    pub leds: [LedState; N],
+    /// Brightness scaling for all the LEDs (0-255), e.g. for dark stages
+    pub global_brightness: u8,
+}
+
+impl<const N: usize> Default for LedBank<N> {
+    fn default() -> Self {
+        LedBank {
+            leds: [LedState::default(); N],
+            global_brightness: 255,
+        }
+    }
+}
+
+impl<const N: usize> LedBank<N> {
+    /// Whether any LED needs periodic updates
+    pub fn is_animated(&self) -> bool {
+        self.leds.iter().any(|l| l.pattern.is_animated())
+    }
+
+    /// Sets the LEDs on or off from a bitmask, bit N for LED N
+    pub fn set_bits(&mut self, bits: u8) {
+        for (i, led) in self.leds.iter_mut().enumerate() {
+            led.pattern = if bits & (1 << i) != 0 {
+                LedPattern::On
+            } else {
+                LedPattern::Off
+            };
+        }
+    }
+
+    /// PWM duty cycles of the LEDs at the given time
+    pub fn duty_cycles(&self, time_ms: u32, max_duty: u16) -> [u16; N] {
+        let mut duties = [0; N];
+        for (duty, led) in duties.iter_mut().zip(&self.leds) {
+            let level = scale(scale(led.pattern.level(time_ms), led.brightness), self.global_brightness);
+            *duty = gamma_duty(level, max_duty);
+        }
+        duties
+    }
+}
+
+fn scale(level: u8, brightness: u8) -> u8 {
+    ((level as u16 * brightness as u16) / 255) as u8
+}
+
+/// Duty cycle for a perceived brightness level, with a gamma of 2
+fn gamma_duty(level: u8, max_duty: u16) -> u16 {
+    let l = level as u32;
+    ((l * l * max_duty as u32) / (255 * 255)) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::{layer_patterns, tuner_patterns, LedBank, LedPattern};
+
+    #[test]
+    fn test_blink() {
+        let p = LedPattern::Blink { period_ms: 500 };
+        assert_eq!(p.level(0), 255);
+        assert_eq!(p.level(249), 255);
+        assert_eq!(p.level(250), 0);
+        assert_eq!(p.level(1000), 255);
+    }
+
+    #[test]
+    fn test_breathe() {
+        let p = LedPattern::Breathe { period_ms: 2000 };
+        assert_eq!(p.level(0), 0);
+        assert_eq!(p.level(500), 127);
+        assert_eq!(p.level(1000), 255);
+        assert_eq!(p.level(1500), 127);
+        assert_eq!(p.level(2000), 0);
+    }
+
+    #[test]
+    fn test_beat() {
+        let p = LedPattern::Beat {
+            period_ms: 500,
+            start_ms: 100,
+        };
+        assert_eq!(p.level(100), 255);
+        assert_eq!(p.level(224), 255);
+        assert_eq!(p.level(225), 0);
+        assert_eq!(p.level(99), 0);
+        assert_eq!(p.level(600), 255);
+        // Timer wrap-around
+        let p = LedPattern::Beat {
+            period_ms: 500,
+            start_ms: u32::MAX - 99,
+        };
+        assert_eq!(p.level(400), 255);
+    }
+
+    #[test]
+    fn test_layer_patterns() {
+        let blink = LedPattern::Blink { period_ms: 200 };
+        let off = LedPattern::Off;
+        assert_eq!(layer_patterns::<4>(0), [blink, off, off, off]);
+        assert_eq!(layer_patterns::<4>(2), [blink, blink, blink, off]);
+        assert!(tuner_patterns::<4>().iter().all(|p| p.is_animated()));
+    }
+
+    #[test]
+    fn test_duty_cycles() {
+        let mut bank = LedBank::<3>::default();
+        bank.set_bits(0b011);
+        bank.leds[1].brightness = 128;
+        assert_eq!(bank.duty_cycles(0, 1000), [1000, 251, 0]);
+        assert!(!bank.is_animated());
+
+        bank.global_brightness = 0;
+        assert_eq!(bank.duty_cycles(0, 1000), [0, 0, 0]);
+
+        bank.leds[2].pattern = LedPattern::Blink { period_ms: 100 };
+        assert!(bank.is_animated());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::ws2812::{Palette, Rgb};
+use defmt::Format;
+
+/// LEDs in the GA-FC layout, one per footswitch
+pub const LED_COUNT: usize = 6;
+
+/// What lights up an LED
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum LedSource {
+    /// Not driven from the amp's LED status
+    None,
+    /// Lit when the status bit is set
+    Bit(u8),
+    /// Lit when the status bit is clear, e.g. a bank A LED from the bank bit
+    NotBit(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct LedMap {
+    pub source: LedSource,
+    /// The output pin is low when the LED is lit (e.g. common anode wiring)
+    pub active_low: bool,
+    /// Colour for an RGB LED. Without one the LED follows the palette.
+    pub colour: Option<Rgb>,
+}
+
+impl LedMap {
+    pub const fn bit(bit: u8) -> Self {
+        LedMap {
+            source: LedSource::Bit(bit),
+            active_low: false,
+            colour: None,
+        }
+    }
+
+    /// Whether the status bit is one of the eight of the status byte
+    pub fn is_valid(&self) -> bool {
+        match self.source {
+            LedSource::None => true,
+            LedSource::Bit(b) | LedSource::NotBit(b) => b < 8,
+        }
+    }
+
+    pub fn is_lit(&self, status: u8) -> bool {
+        match self.source {
+            LedSource::None => false,
+            LedSource::Bit(b) => status & (1 << b) != 0,
+            LedSource::NotBit(b) => status & (1 << b) == 0,
+        }
+    }
+}
+
+/// Maps the bits of the amp's LED status byte to the LED outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct LedMapping<const N: usize> {
+    pub leds: [LedMap; N],
+}
+
+impl<const N: usize> LedMapping<N> {
+    /// Status bit N to LED N, like the GA-FC
+    pub const fn identity() -> Self {
+        let mut leds = [LedMap::bit(0); N];
+        let mut i = 0;
+        while i < N {
+            leds[i] = LedMap::bit(i as u8);
+            i += 1;
+        }
+        LedMapping { leds }
+    }
+
+    /// Lit LEDs for a status byte, bit N for LED N
+    pub fn lit(&self, status: u8) -> u8 {
+        self.leds
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.is_lit(status))
+            .fold(0, |acc, (i, _)| acc | (1 << i))
+    }
+
+    /// Colours of RGB LEDs for a status byte.
+    ///
+    /// An LED without its own colour shows the palette colour of its status
+    /// bit. An LED with a colour shows it when lit, and the palette's `off`
+    /// colour otherwise.
+    pub fn colours(&self, status: u8, palette: &Palette) -> [Rgb; N] {
+        let palette_colours = palette.colours(status);
+        let mut colours = [palette.off; N];
+        for (colour, led) in colours.iter_mut().zip(&self.leds) {
+            *colour = match (led.colour, led.source) {
+                (Some(c), _) if led.is_lit(status) => c,
+                (Some(_), _) | (None, LedSource::None) => palette.off,
+                (None, LedSource::Bit(b) | LedSource::NotBit(b)) => {
+                    palette_colours.get(b as usize).copied().unwrap_or(palette.off)
+                }
+            };
+        }
+        colours
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LedMap, LedMapping, LedSource};
+    use crate::ws2812::{Palette, Rgb};
+    use katana_sysex::led_status;
+
+    #[test]
+    fn test_identity() {
+        let m = LedMapping::<6>::identity();
+        assert_eq!(m.lit(0b10_1001), 0b10_1001);
+        assert_eq!(m.leds[5], LedMap::bit(5));
+    }
+
+    #[test]
+    fn test_reordered_with_bank_a_led() {
+        let mut m = LedMapping::<6>::identity();
+        m.leds.swap(0, 3);
+        m.leds[5] = LedMap {
+            source: LedSource::NotBit(4),
+            active_low: true,
+            colour: None,
+        };
+        assert_eq!(m.lit(led_status::CH1), 0b10_1000);
+        assert_eq!(m.lit(led_status::CH4 | led_status::BANK), 0b01_0001);
+    }
+
+    #[test]
+    fn test_colours() {
+        let palette = Palette::default();
+        let green = Rgb::new(0, 255, 0);
+        let mut m = LedMapping::<3>::identity();
+        m.leds[1].colour = Some(green);
+        m.leds[2] = LedMap {
+            source: LedSource::None,
+            active_low: false,
+            colour: None,
+        };
+
+        let c = m.colours(led_status::CH1 | led_status::BANK, &palette);
+        assert_eq!(c, [palette.bank_b, Rgb::OFF, Rgb::OFF]);
+        let c = m.colours(led_status::CH2, &palette);
+        assert_eq!(c, [Rgb::OFF, green, Rgb::OFF]);
+    }
+}
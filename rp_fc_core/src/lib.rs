@@ -0,0 +1,31 @@
+//! Hardware-independent parts of the RP-FC firmware.
+//!
+//! Everything here builds for the host too, so the logic can be unit tested
+//! with `cargo test -p rp_fc_core --target <host triple>`.
+#![no_std]
+
+pub mod actions;
+pub mod amp_state;
+pub mod button_event;
+pub mod config;
+pub mod config_store;
+pub mod console;
+pub mod debounce;
+pub mod display;
+pub mod encoder;
+pub mod held;
+pub mod hid;
+pub mod led;
+pub mod led_map;
+pub mod macros;
+pub mod midi;
+#[cfg(test)]
+mod pio_sim;
+pub mod self_test;
+pub mod setlist;
+pub mod seven_segment;
+pub mod soft_debounce;
+pub mod tap_tempo;
+pub mod tm1637;
+pub mod tuner;
+pub mod ws2812;
@@ -0,0 +1,155 @@
+use defmt::Format;
+
+pub const MACRO_COUNT: usize = 4;
+pub const MAX_STEPS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct MacroStep {
+    /// GA-FC footswitches tapped in this step, 0 for an unused step
+    pub footswitches: u8,
+    /// Wait after the step before the next one
+    pub delay_ms: u16,
+}
+
+/// Footswitch taps sent to the amp one after another
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct Macro {
+    pub steps: [MacroStep; MAX_STEPS],
+}
+
+impl Macro {
+    pub fn is_empty(&self) -> bool {
+        self.steps.iter().all(|s| s.footswitches == 0)
+    }
+}
+
+/// Plays macros, one GA-FC footswitch state at a time.
+///
+/// Each tap is a press and a release message. The next message is only
+/// released when the caller says the amp link is ready, i.e. the previous
+/// message has been echoed and replied to, and the step's delay has passed.
+#[derive(Default)]
+pub struct MacroPlayer {
+    /// Footswitch states to send, with the delay after each
+    queue: heapless::Deque<(u8, u16), { 2 * MAX_STEPS }>,
+    next_at_ms: u64,
+    /// A press has been sent and its release is still queued
+    pressed: bool,
+    /// The last state sent
+    sent: u8,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        MacroPlayer {
+            queue: heapless::Deque::new(),
+            next_at_ms: 0,
+            pressed: false,
+            sent: 0,
+        }
+    }
+
+    /// Starts a macro, replacing a running one. `held` are the GA-FC
+    /// footswitches held down meanwhile.
+    pub fn start(&mut self, m: &Macro, held: u8) {
+        self.abort();
+        for step in m.steps.iter().filter(|s| s.footswitches != 0) {
+            _ = self.queue.push_back((held | step.footswitches, 0));
+            _ = self.queue.push_back((held, step.delay_ms));
+        }
+    }
+
+    /// Stops the macro. A pressed footswitch is still released.
+    pub fn abort(&mut self) {
+        if self.pressed {
+            while self.queue.len() > 1 {
+                self.queue.pop_back();
+            }
+            if let Some((_, delay_ms)) = self.queue.front_mut() {
+                *delay_ms = 0;
+            }
+        } else {
+            self.queue.clear();
+        }
+        self.next_at_ms = 0;
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// GA-FC footswitches held down by the macro, to be included in the
+    /// other messages sent meanwhile
+    pub fn state(&self) -> u8 {
+        if self.pressed {
+            self.sent
+        } else {
+            0
+        }
+    }
+
+    /// The next footswitch state to send, if it's time for it
+    pub fn poll(&mut self, now_ms: u64, link_ready: bool) -> Option<u8> {
+        if !link_ready || now_ms < self.next_at_ms {
+            return None;
+        }
+        let (state, delay_ms) = self.queue.pop_front()?;
+        self.pressed = !self.pressed;
+        self.sent = state;
+        self.next_at_ms = now_ms + u64::from(delay_ms);
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Macro, MacroPlayer, MacroStep};
+
+    fn test_macro() -> Macro {
+        let mut m = Macro::default();
+        m.steps[0] = MacroStep {
+            footswitches: 0x10,
+            delay_ms: 200,
+        };
+        m.steps[2] = MacroStep {
+            footswitches: 0x02,
+            delay_ms: 0,
+        };
+        m
+    }
+
+    #[test]
+    fn test_play() {
+        let mut p = MacroPlayer::new();
+        p.start(&test_macro(), 0x20);
+
+        assert_eq!(p.poll(0, false), None);
+        assert_eq!(p.poll(0, true), Some(0x30));
+        assert_eq!(p.state(), 0x30);
+        assert_eq!(p.poll(10, true), Some(0x20));
+        assert_eq!(p.state(), 0);
+        // Step delay
+        assert_eq!(p.poll(100, true), None);
+        assert_eq!(p.poll(210, true), Some(0x22));
+        assert_eq!(p.poll(220, true), Some(0x20));
+        assert!(!p.is_running());
+        assert_eq!(p.poll(1000, true), None);
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut p = MacroPlayer::new();
+        p.start(&test_macro(), 0);
+        assert_eq!(p.poll(0, true), Some(0x10));
+        // The pressed switch is released, the rest dropped
+        p.abort();
+        assert_eq!(p.poll(10, true), Some(0));
+        assert!(!p.is_running());
+
+        p.start(&test_macro(), 0);
+        assert_eq!(p.poll(20, true), Some(0x10));
+        assert_eq!(p.poll(30, true), Some(0));
+        p.abort();
+        assert!(!p.is_running());
+    }
+}
@@ -0,0 +1,621 @@
+use crate::amp_state::AmpState;
+use crate::button_event::ButtonEvent;
+use crate::held::SwitchInput;
+use defmt::Format;
+
+/// The MIDI messages the foot controller sends and understands. Channels
+/// are 0-15 (shown as 1-16 on most gear).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum MidiMessage {
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+}
+
+impl MidiMessage {
+    /// The message as bytes on the wire
+    pub fn to_bytes(self) -> heapless::Vec<u8, 3> {
+        let bytes: &[u8] = match self {
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => &[0xb0 | (channel & 0x0f), control & 0x7f, value & 0x7f],
+            MidiMessage::ProgramChange { channel, program } => {
+                &[0xc0 | (channel & 0x0f), program & 0x7f]
+            }
+        };
+        heapless::Vec::from_slice(bytes).unwrap()
+    }
+
+    /// The message as a USB MIDI event packet on virtual cable 0
+    pub fn to_usb_packet(self) -> [u8; 4] {
+        let bytes = self.to_bytes();
+        // The code index number is the status nibble for channel messages
+        let mut packet = [bytes[0] >> 4, 0, 0, 0];
+        packet[1..1 + bytes.len()].copy_from_slice(&bytes);
+        packet
+    }
+
+    /// Parses a USB MIDI event packet. Messages other than control and
+    /// program changes are ignored.
+    pub fn from_usb_packet(packet: [u8; 4]) -> Option<Self> {
+        let channel = packet[1] & 0x0f;
+        match (packet[0] & 0x0f, packet[1] >> 4) {
+            (0x0b, 0x0b) => Some(MidiMessage::ControlChange {
+                channel,
+                control: packet[2] & 0x7f,
+                value: packet[3] & 0x7f,
+            }),
+            (0x0c, 0x0c) => Some(MidiMessage::ProgramChange {
+                channel,
+                program: packet[2] & 0x7f,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        match *self {
+            MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. } => channel,
+        }
+    }
+}
+
+/// Parser for a MIDI byte stream, e.g. from a DIN MIDI port. Handles running
+/// status and skips real-time and system messages. Only control and program
+/// changes are returned.
+#[derive(Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: [u8; 2],
+    len: usize,
+}
+
+impl MidiParser {
+    pub const fn new() -> Self {
+        MidiParser {
+            status: None,
+            data: [0; 2],
+            len: 0,
+        }
+    }
+
+    /// Feeds a received byte, returns a message when one is complete
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        match byte {
+            // Real-time messages can appear anywhere, even inside other messages
+            0xf8.. => return None,
+            // System messages (and sysex data) cancel the running status
+            0xf0.. => {
+                self.status = None;
+                self.len = 0;
+                return None;
+            }
+            0x80.. => {
+                self.status = Some(byte);
+                self.len = 0;
+                return None;
+            }
+            _ => {}
+        }
+
+        let status = self.status?;
+        self.data[self.len] = byte;
+        self.len += 1;
+        let data_len = match status >> 4 {
+            0xc | 0xd => 1,
+            _ => 2,
+        };
+        if self.len < data_len {
+            return None;
+        }
+        self.len = 0;
+
+        let channel = status & 0x0f;
+        match status >> 4 {
+            0xb => Some(MidiMessage::ControlChange {
+                channel,
+                control: self.data[0],
+                value: self.data[1],
+            }),
+            0xc => Some(MidiMessage::ProgramChange {
+                channel,
+                program: self.data[0],
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// What a switch sends over MIDI
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum SwitchMidi {
+    None,
+    /// Control change with value 127 on press and 0 on release
+    Cc { control: u8 },
+    /// Program change on press
+    Pc { program: u8 },
+}
+
+/// MIDI messages sent for the switches
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct MidiOutMapping {
+    pub channel: u8,
+    /// Switch N is button state bit N
+    pub switches: [SwitchMidi; 8],
+    /// Control (0-31) for the tap tempo in BPM, sent as a 14-bit control
+    /// change: the upper bits on this control and the lower on control + 32
+    pub tempo_control: Option<u8>,
+}
+
+impl Default for MidiOutMapping {
+    /// CC 80-87 (general purpose controllers) on channel 1
+    fn default() -> Self {
+        let mut switches = [SwitchMidi::None; 8];
+        for (i, s) in switches.iter_mut().enumerate() {
+            *s = SwitchMidi::Cc {
+                control: 80 + i as u8,
+            };
+        }
+        MidiOutMapping {
+            channel: 0,
+            switches,
+            tempo_control: None,
+        }
+    }
+}
+
+impl MidiOutMapping {
+    /// Messages to send for a button event
+    pub fn messages(&self, ev: &ButtonEvent) -> heapless::Vec<MidiMessage, 8> {
+        let mut messages = heapless::Vec::new();
+        for (i, s) in self.switches.iter().enumerate() {
+            let bit = 1 << i;
+            let pressed = ev.pressed() & bit != 0;
+            let released = ev.released() & bit != 0;
+            let msg = match *s {
+                SwitchMidi::Cc { control } if pressed || released => {
+                    Some(MidiMessage::ControlChange {
+                        channel: self.channel,
+                        control,
+                        value: if pressed { 127 } else { 0 },
+                    })
+                }
+                SwitchMidi::Pc { program } if pressed => Some(MidiMessage::ProgramChange {
+                    channel: self.channel,
+                    program,
+                }),
+                _ => None,
+            };
+            if let Some(msg) = msg {
+                _ = messages.push(msg);
+            }
+        }
+        messages
+    }
+
+    /// Messages to send for a new tap tempo
+    pub fn tempo_messages(&self, bpm: u16) -> heapless::Vec<MidiMessage, 2> {
+        let Some(control) = self.tempo_control else {
+            return heapless::Vec::new();
+        };
+        [(control, bpm >> 7), (control + 32, bpm)]
+            .into_iter()
+            .map(|(control, value)| MidiMessage::ControlChange {
+                channel: self.channel,
+                control,
+                value: (value & 0x7f) as u8,
+            })
+            .collect()
+    }
+}
+
+/// A received MIDI message that presses a footswitch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum MidiTrigger {
+    /// Taps the footswitches: press and release
+    Program(u8),
+    /// Holds the footswitches down while the value is 64 or more
+    Cc(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct MidiInRule {
+    pub trigger: MidiTrigger,
+    /// Footswitch bits, like in the button state
+    pub footswitches: u8,
+}
+
+/// Most rules in a [`MidiInMapping`]
+pub const MAX_IN_RULES: usize = 16;
+/// Fills the unused rules, so that mappings with the same rules are equal
+const NO_RULE: MidiInRule = MidiInRule {
+    trigger: MidiTrigger::Program(0),
+    footswitches: 0,
+};
+
+/// Maps received MIDI messages to GA-FC footswitch presses
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct MidiInMapping {
+    /// Only messages on this channel are used, or on all channels if None
+    pub channel: Option<u8>,
+    pub rules: [MidiInRule; MAX_IN_RULES],
+    pub len: u8,
+}
+
+impl Default for MidiInMapping {
+    /// Program changes 0-5 tap footswitches 1-6, and CC 80-85 (what the
+    /// footswitches send by default) hold them down
+    fn default() -> Self {
+        let mut m = MidiInMapping::new();
+        for i in 0..6 {
+            m.set(MidiTrigger::Program(i), 1 << i);
+            m.set(MidiTrigger::Cc(80 + i), 1 << i);
+        }
+        m
+    }
+}
+
+impl MidiInMapping {
+    /// No rules, on all channels
+    pub const fn new() -> Self {
+        MidiInMapping {
+            channel: None,
+            rules: [NO_RULE; MAX_IN_RULES],
+            len: 0,
+        }
+    }
+
+    pub fn rules(&self) -> &[MidiInRule] {
+        &self.rules[..usize::from(self.len)]
+    }
+
+    /// Sets the footswitches of a trigger, replacing its rule if it has one.
+    /// No footswitches removes the rule. Returns `false` if the rules are
+    /// full.
+    pub fn set(&mut self, trigger: MidiTrigger, footswitches: u8) -> bool {
+        let len = usize::from(self.len);
+        let i = self.rules().iter().position(|r| r.trigger == trigger).unwrap_or(len);
+        if footswitches == 0 {
+            if i < len {
+                self.rules.copy_within(i + 1..len, i);
+                self.rules[len - 1] = NO_RULE;
+                self.len -= 1;
+            }
+            return true;
+        }
+        let Some(rule) = self.rules.get_mut(i) else {
+            return false;
+        };
+        *rule = MidiInRule {
+            trigger,
+            footswitches,
+        };
+        if i == len {
+            self.len += 1;
+        }
+        true
+    }
+
+    /// Returns what a MIDI message does to the GA-FC switches, if anything.
+    /// The switches held by control changes are tracked by [`Holds`](crate::held::Holds).
+    pub fn input(&self, msg: MidiMessage) -> Option<SwitchInput> {
+        if self.channel.is_some_and(|c| c != msg.channel()) {
+            return None;
+        }
+
+        self.rules()
+            .iter()
+            .find_map(|rule| match (rule.trigger, msg) {
+                (MidiTrigger::Program(p), MidiMessage::ProgramChange { program, .. })
+                    if p == program =>
+                {
+                    Some(SwitchInput::Tap(rule.footswitches))
+                }
+                (MidiTrigger::Cc(c), MidiMessage::ControlChange { control, value, .. })
+                    if c == control =>
+                {
+                    if value >= 64 {
+                        Some(SwitchInput::Hold(rule.footswitches))
+                    } else {
+                        Some(SwitchInput::Release(rule.footswitches))
+                    }
+                }
+                _ => None,
+            })
+    }
+}
+
+/// MIDI messages sent when the amp's state changes, to keep other gear in
+/// sync with the amp
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct StatusMidiMapping {
+    pub channel: u8,
+    /// Send program change 0-3 when the amp switches to channel 1-4 of bank
+    /// A, and 4-7 for bank B
+    pub channel_programs: bool,
+    /// Control change for effect mode, 127 in effect mode and 0 otherwise
+    pub effect_mode_control: Option<u8>,
+    /// Control change for each effect (boost, mod, fx, delay, reverb), 127
+    /// when on and 0 when off
+    pub effect_controls: [Option<u8>; 5],
+}
+
+impl Default for StatusMidiMapping {
+    fn default() -> Self {
+        StatusMidiMapping {
+            channel: 0,
+            channel_programs: true,
+            effect_mode_control: None,
+            effect_controls: [None; 5],
+        }
+    }
+}
+
+impl StatusMidiMapping {
+    /// Messages to send for a new amp state. Everything known is sent for
+    /// the first state.
+    pub fn messages(
+        &self,
+        prev: Option<&AmpState>,
+        state: &AmpState,
+    ) -> heapless::Vec<MidiMessage, 7> {
+        let mut messages = heapless::Vec::new();
+        let cc = |control, on| MidiMessage::ControlChange {
+            channel: self.channel,
+            control,
+            value: if on { 127 } else { 0 },
+        };
+
+        if let Some(program) = state.channel() {
+            if self.channel_programs && prev.and_then(AmpState::channel) != Some(program) {
+                _ = messages.push(MidiMessage::ProgramChange {
+                    channel: self.channel,
+                    program,
+                });
+            }
+        }
+
+        if let Some(control) = self.effect_mode_control {
+            if prev.map(|p| p.effect_mode) != Some(state.effect_mode) {
+                _ = messages.push(cc(control, state.effect_mode));
+            }
+        }
+
+        for (i, control) in self.effect_controls.iter().enumerate() {
+            let (Some(control), Some(on)) = (*control, state.effect_on(1 << i)) else {
+                continue;
+            };
+            if prev.and_then(|p| p.effect_on(1 << i)) != Some(on) {
+                _ = messages.push(cc(control, on));
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        MidiInMapping, MidiMessage, MidiOutMapping, MidiParser, MidiTrigger, StatusMidiMapping,
+        SwitchMidi, MAX_IN_RULES,
+    };
+    use katana_sysex::led_status;
+    use crate::amp_state::AmpTracker;
+    use crate::button_event::ButtonEvent;
+    use crate::held::SwitchInput;
+
+    #[test]
+    fn test_encoding() {
+        let cc = MidiMessage::ControlChange {
+            channel: 2,
+            control: 80,
+            value: 127,
+        };
+        assert_eq!(&cc.to_bytes()[..], &[0xb2, 80, 127]);
+        assert_eq!(cc.to_usb_packet(), [0x0b, 0xb2, 80, 127]);
+
+        let pc = MidiMessage::ProgramChange {
+            channel: 15,
+            program: 5,
+        };
+        assert_eq!(&pc.to_bytes()[..], &[0xcf, 5]);
+        assert_eq!(pc.to_usb_packet(), [0x0c, 0xcf, 5, 0]);
+    }
+
+    #[test]
+    fn test_usb_packet_parsing() {
+        assert_eq!(
+            MidiMessage::from_usb_packet([0x0b, 0xb1, 7, 100]),
+            Some(MidiMessage::ControlChange {
+                channel: 1,
+                control: 7,
+                value: 100
+            })
+        );
+        assert_eq!(
+            MidiMessage::from_usb_packet([0x1c, 0xc0, 3, 0]),
+            Some(MidiMessage::ProgramChange {
+                channel: 0,
+                program: 3
+            })
+        );
+        // Note on
+        assert_eq!(MidiMessage::from_usb_packet([0x09, 0x90, 60, 100]), None);
+    }
+
+    #[test]
+    fn test_parser() {
+        let mut p = MidiParser::new();
+        let mut parse = |bytes: &[u8]| {
+            let mut out = heapless::Vec::<MidiMessage, 4>::new();
+            for b in bytes {
+                if let Some(m) = p.push(*b) {
+                    out.push(m).unwrap();
+                }
+            }
+            out
+        };
+
+        let cc = |value| MidiMessage::ControlChange {
+            channel: 1,
+            control: 7,
+            value,
+        };
+        // Running status, with a clock tick in the middle
+        assert_eq!(&parse(&[0xb1, 7, 10, 7, 0xf8, 20])[..], &[cc(10), cc(20)]);
+        // Note on is skipped, sysex cancels the running status
+        assert_eq!(&parse(&[0x90, 60, 100, 0xf0, 1, 2, 0xf7, 3])[..], &[]);
+        assert_eq!(
+            &parse(&[0xc5, 9])[..],
+            &[MidiMessage::ProgramChange {
+                channel: 5,
+                program: 9
+            }]
+        );
+    }
+
+    #[test]
+    fn test_status_mapping() {
+        let mut m = StatusMidiMapping {
+            effect_mode_control: Some(90),
+            ..Default::default()
+        };
+        m.effect_controls[3] = Some(94);
+        let pc = |program| MidiMessage::ProgramChange { channel: 0, program };
+        let cc = |control, value| MidiMessage::ControlChange {
+            channel: 0,
+            control,
+            value,
+        };
+
+        let mut t = AmpTracker::new();
+        let mut prev = None;
+        let mut frame = |status| {
+            t.on_led_status(status);
+            let state = *t.state().unwrap();
+            let messages = m.messages(prev.as_ref(), &state);
+            prev = Some(state);
+            messages
+        };
+        assert_eq!(&frame(led_status::CH2)[..], &[pc(1), cc(90, 0)]);
+        assert_eq!(&frame(led_status::CH2)[..], &[]);
+        assert_eq!(&frame(led_status::CH2 | led_status::BANK)[..], &[pc(5)]);
+        let effects = led_status::EFFECT_MODE | led_status::BOOST | led_status::DELAY;
+        assert_eq!(&frame(effects)[..], &[cc(90, 127), cc(94, 127)]);
+        assert_eq!(&frame(led_status::EFFECT_MODE)[..], &[cc(94, 0)]);
+    }
+
+    #[test]
+    fn test_in_mapping() {
+        let mut m = MidiInMapping::default();
+        let pc = MidiMessage::ProgramChange {
+            channel: 3,
+            program: 2,
+        };
+        assert_eq!(m.input(pc), Some(SwitchInput::Tap(0b100)));
+
+        let cc = |value| MidiMessage::ControlChange {
+            channel: 3,
+            control: 81,
+            value,
+        };
+        assert_eq!(m.input(cc(127)), Some(SwitchInput::Hold(0b10)));
+        assert_eq!(m.input(cc(0)), Some(SwitchInput::Release(0b10)));
+
+        // Unmapped, and on another channel
+        let other = MidiMessage::ProgramChange {
+            channel: 3,
+            program: 100,
+        };
+        assert_eq!(m.input(other), None);
+        m.channel = Some(0);
+        assert_eq!(m.input(pc), None);
+    }
+
+    #[test]
+    fn test_in_mapping_rules() {
+        let mut m = MidiInMapping::default();
+        assert_eq!(m.len, 12);
+        let pc = MidiMessage::ProgramChange {
+            channel: 0,
+            program: 2,
+        };
+
+        // Replaced, removed, and added back at the end
+        assert!(m.set(MidiTrigger::Program(2), 0b11));
+        assert_eq!(m.input(pc), Some(SwitchInput::Tap(0b11)));
+        assert!(m.set(MidiTrigger::Program(2), 0));
+        assert_eq!(m.len, 11);
+        assert_eq!(m.input(pc), None);
+        assert_eq!(m.rules()[4].trigger, MidiTrigger::Cc(82));
+        assert!(m.set(MidiTrigger::Program(2), 0b100));
+        assert_eq!(m.rules()[11].trigger, MidiTrigger::Program(2));
+
+        for i in 0..4 {
+            assert!(m.set(MidiTrigger::Cc(i), 1));
+        }
+        assert_eq!(usize::from(m.len), MAX_IN_RULES);
+        assert!(!m.set(MidiTrigger::Cc(4), 1));
+        // Removing an unmapped trigger is fine
+        assert!(m.set(MidiTrigger::Cc(4), 0));
+    }
+
+    #[test]
+    fn test_mapping() {
+        let mut m = MidiOutMapping::default();
+        m.switches[1] = SwitchMidi::Pc { program: 3 };
+        m.switches[2] = SwitchMidi::None;
+
+        let press = ButtonEvent {
+            time_us: 0,
+            state: 0b0111,
+            prev_state: 0,
+        };
+        assert_eq!(
+            &m.messages(&press)[..],
+            &[
+                MidiMessage::ControlChange {
+                    channel: 0,
+                    control: 80,
+                    value: 127
+                },
+                MidiMessage::ProgramChange {
+                    channel: 0,
+                    program: 3
+                },
+            ]
+        );
+
+        let release = ButtonEvent {
+            time_us: 0,
+            state: 0,
+            prev_state: 0b0111,
+        };
+        assert_eq!(
+            &m.messages(&release)[..],
+            &[MidiMessage::ControlChange {
+                channel: 0,
+                control: 80,
+                value: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tempo() {
+        let mut m = MidiOutMapping::default();
+        assert!(m.tempo_messages(120).is_empty());
+
+        m.channel = 2;
+        m.tempo_control = Some(12);
+        let cc = |control, value| MidiMessage::ControlChange {
+            channel: 2,
+            control,
+            value,
+        };
+        assert_eq!(&m.tempo_messages(200)[..], &[cc(12, 1), cc(44, 72)]);
+        assert_eq!(&m.tempo_messages(90)[..], &[cc(12, 0), cc(44, 90)]);
+    }
+}
@@ -0,0 +1,514 @@
+//! Cycle-accurate software model of a single RP2040 PIO state machine.
+//!
+//! Runs assembled PIO programs on the host, one state machine clock cycle at
+//! a time, with the input pins supplied by the caller for every cycle. Only
+//! the parts of the PIO needed for input programs and side-set outputs are
+//! modelled: `out`/`set`/`mov` to pins are not supported, and neither are
+//! `irq`, `wait irq` and `mov`/`out` to `exec`. Running one of those returns
+//! [`Unsupported`], so a test fails instead of simulating the wrong thing.
+
+use heapless::Deque;
+use pio::Program;
+
+const FIFO_DEPTH: usize = 4;
+
+/// A program or instruction the model doesn't handle
+#[derive(Debug, PartialEq)]
+pub struct Unsupported(pub &'static str);
+
+/// State machine configuration, the parts of `PIOBuilder` that affect
+/// program execution.
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub in_shift_left: bool,
+    pub out_shift_left: bool,
+    pub autopush: bool,
+    pub push_threshold: u8,
+    pub autopull: bool,
+    pub pull_threshold: u8,
+    /// Input pin for `jmp pin`, relative to the input pin base
+    pub jmp_pin: u8,
+}
+
+impl Default for Config {
+    /// Defaults after a state machine reset
+    fn default() -> Self {
+        Config {
+            in_shift_left: false,
+            out_shift_left: false,
+            autopush: false,
+            push_threshold: 32,
+            autopull: false,
+            pull_threshold: 32,
+            jmp_pin: 0,
+        }
+    }
+}
+
+pub struct StateMachine {
+    code: [u16; 32],
+    wrap_source: u8,
+    wrap_target: u8,
+    side_set_bits: u8,
+    side_set_optional: bool,
+    config: Config,
+
+    pc: u8,
+    x: u32,
+    y: u32,
+    isr: u32,
+    isr_count: u8,
+    osr: u32,
+    osr_count: u8,
+    delay: u8,
+    rx_fifo: Deque<u32, FIFO_DEPTH>,
+    tx_fifo: Deque<u32, FIFO_DEPTH>,
+    rx_overflows: u32,
+    side_set_pins: u32,
+    cycles: u64,
+}
+
+enum Exec {
+    Next,
+    Jump(u8),
+    Stall,
+}
+
+impl StateMachine {
+    /// Loads a program to instruction memory address 0 and resets the state
+    /// machine to the start of it, like `PIOBuilder::build` does.
+    pub fn new<const N: usize>(program: &Program<N>, config: Config) -> Result<Self, Unsupported> {
+        if program.code.len() > 32 {
+            return Err(Unsupported("program too long"));
+        }
+        if program.origin.unwrap_or(0) != 0 {
+            return Err(Unsupported("program origin"));
+        }
+        let mut code = [0; 32];
+        code[..program.code.len()].copy_from_slice(&program.code);
+
+        Ok(StateMachine {
+            code,
+            wrap_source: program.wrap.source,
+            wrap_target: program.wrap.target,
+            side_set_bits: program.side_set.bits(),
+            side_set_optional: program.side_set.optional(),
+            config,
+            pc: 0,
+            x: 0,
+            y: 0,
+            isr: 0,
+            isr_count: 0,
+            osr: 0,
+            // The output shift register starts empty
+            osr_count: 32,
+            delay: 0,
+            rx_fifo: Deque::new(),
+            tx_fifo: Deque::new(),
+            rx_overflows: 0,
+            side_set_pins: 0,
+            cycles: 0,
+        })
+    }
+
+    /// Number of state machine clock cycles run so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn pc(&self) -> u8 {
+        self.pc
+    }
+
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    /// Reads a word from the RX FIFO, like `Rx::read`
+    pub fn rx_read(&mut self) -> Option<u32> {
+        self.rx_fifo.pop_front()
+    }
+
+    /// Writes a word to the TX FIFO, returns false if the FIFO is full
+    pub fn tx_write(&mut self, value: u32) -> bool {
+        self.tx_fifo.push_back(value).is_ok()
+    }
+
+    /// Number of `push noblock` (or autopush) values dropped due to a full RX FIFO
+    pub fn rx_overflows(&self) -> u32 {
+        self.rx_overflows
+    }
+
+    /// Current levels of the side-set pins, bit 0 is the side-set pin base
+    pub fn side_set_pins(&self) -> u32 {
+        self.side_set_pins
+    }
+
+    /// Runs one clock cycle with the given input pin levels. Bit 0 of `pins`
+    /// is the input pin base.
+    pub fn step(&mut self, pins: u32) -> Result<(), Unsupported> {
+        self.cycles += 1;
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return Ok(());
+        }
+
+        let instr = self.code[self.pc as usize];
+        self.side_set(instr);
+        match self.execute(instr, pins)? {
+            Exec::Stall => {}
+            res => {
+                let delay_bits = 5 - self.side_set_bits;
+                self.delay = ((instr >> 8) & ((1 << delay_bits) - 1)) as u8;
+                self.pc = match res {
+                    Exec::Jump(addr) => addr,
+                    _ if self.pc == self.wrap_source => self.wrap_target,
+                    _ => (self.pc + 1) % 32,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes an instruction right away, like `exec_instruction` on a
+    /// stopped state machine. Jumps don't move the program counter.
+    pub fn exec(&mut self, instr: u16) -> Result<(), Unsupported> {
+        self.execute(instr, 0).map(|_| ())
+    }
+
+    /// Runs `cycles` clock cycles with constant input pin levels
+    pub fn run(&mut self, pins: u32, cycles: u64) -> Result<(), Unsupported> {
+        for _ in 0..cycles {
+            self.step(pins)?;
+        }
+        Ok(())
+    }
+
+    /// Side-set takes effect when the instruction issues, even if it stalls
+    fn side_set(&mut self, instr: u16) {
+        if self.side_set_bits == 0 {
+            return;
+        }
+        let field = ((instr >> (13 - self.side_set_bits)) & mask(self.side_set_bits) as u16) as u32;
+        if !self.side_set_optional {
+            self.side_set_pins = field;
+        } else if field & (1 << (self.side_set_bits - 1)) != 0 {
+            self.side_set_pins = field & mask(self.side_set_bits - 1);
+        }
+    }
+
+    fn execute(&mut self, instr: u16, pins: u32) -> Result<Exec, Unsupported> {
+        let arg1 = ((instr >> 5) & 0b111) as u8;
+        let arg2 = (instr & 0b1_1111) as u8;
+
+        let exec = match instr >> 13 {
+            // JMP
+            0b000 => {
+                let cond = match arg1 {
+                    0b000 => true,
+                    0b001 => self.x == 0,
+                    0b010 => {
+                        let taken = self.x != 0;
+                        self.x = self.x.wrapping_sub(1);
+                        taken
+                    }
+                    0b011 => self.y == 0,
+                    0b100 => {
+                        let taken = self.y != 0;
+                        self.y = self.y.wrapping_sub(1);
+                        taken
+                    }
+                    0b101 => self.x != self.y,
+                    0b110 => pins & (1 << self.config.jmp_pin) != 0,
+                    _ => self.osr_count < self.config.pull_threshold,
+                };
+                if cond {
+                    Exec::Jump(arg2)
+                } else {
+                    Exec::Next
+                }
+            }
+            // WAIT
+            0b001 => {
+                let polarity = arg1 & 0b100 != 0;
+                let level = match arg1 & 0b11 {
+                    // GPIO numbering is treated as relative to the input pin base
+                    0b00 | 0b01 => pins & (1 << arg2) != 0,
+                    _ => return Err(Unsupported("wait irq")),
+                };
+                if level == polarity {
+                    Exec::Next
+                } else {
+                    Exec::Stall
+                }
+            }
+            // IN
+            0b010 => {
+                let data = match arg1 {
+                    0b000 => pins,
+                    0b001 => self.x,
+                    0b010 => self.y,
+                    0b011 => 0,
+                    0b110 => self.isr,
+                    0b111 => self.osr,
+                    _ => return Err(Unsupported("reserved IN source")),
+                };
+                // Autopush stall: the ISR is still full from a previous IN
+                if self.config.autopush
+                    && self.isr_count >= self.config.push_threshold
+                    && !self.push()
+                {
+                    return Ok(Exec::Stall);
+                }
+                self.shift_in(data, bit_count(arg2));
+                if self.config.autopush && self.isr_count >= self.config.push_threshold {
+                    self.push();
+                }
+                Exec::Next
+            }
+            // OUT
+            0b011 => {
+                if self.config.autopull
+                    && self.osr_count >= self.config.pull_threshold
+                    && !self.pull()
+                {
+                    return Ok(Exec::Stall);
+                }
+                let data = self.shift_out(bit_count(arg2));
+                match arg1 {
+                    0b001 => self.x = data,
+                    0b010 => self.y = data,
+                    0b011 => {}
+                    0b101 => return Ok(Exec::Jump(data as u8 & 0b1_1111)),
+                    0b110 => {
+                        self.isr = data;
+                        self.isr_count = bit_count(arg2);
+                    }
+                    _ => return Err(Unsupported("OUT destination")),
+                }
+                Exec::Next
+            }
+            // PUSH / PULL
+            0b100 => {
+                let is_pull = instr & 0x80 != 0;
+                let if_full_empty = instr & 0x40 != 0;
+                let block = instr & 0x20 != 0;
+                if is_pull {
+                    // `pull ifempty` does nothing until the threshold is reached
+                    if (if_full_empty && self.osr_count < self.config.pull_threshold) || self.pull() {
+                        Exec::Next
+                    } else if block {
+                        Exec::Stall
+                    } else {
+                        // Non-blocking pull from an empty FIFO copies X
+                        self.osr = self.x;
+                        self.osr_count = 0;
+                        Exec::Next
+                    }
+                } else if (if_full_empty && self.isr_count < self.config.push_threshold) || self.push() {
+                    Exec::Next
+                } else if block {
+                    Exec::Stall
+                } else {
+                    // Non-blocking push to a full FIFO drops the data
+                    self.rx_overflows += 1;
+                    self.isr = 0;
+                    self.isr_count = 0;
+                    Exec::Next
+                }
+            }
+            // MOV
+            0b101 => {
+                let src = match instr & 0b111 {
+                    0b000 => pins,
+                    0b001 => self.x,
+                    0b010 => self.y,
+                    0b011 => 0,
+                    0b110 => self.isr,
+                    0b111 => self.osr,
+                    _ => return Err(Unsupported("MOV source")),
+                };
+                let value = match (instr >> 3) & 0b11 {
+                    0b00 => src,
+                    0b01 => !src,
+                    0b10 => src.reverse_bits(),
+                    _ => return Err(Unsupported("reserved MOV operation")),
+                };
+                match arg1 {
+                    0b001 => self.x = value,
+                    0b010 => self.y = value,
+                    0b101 => return Ok(Exec::Jump(value as u8 & 0b1_1111)),
+                    0b110 => {
+                        self.isr = value;
+                        self.isr_count = 0;
+                    }
+                    0b111 => {
+                        self.osr = value;
+                        self.osr_count = 0;
+                    }
+                    _ => return Err(Unsupported("MOV destination")),
+                }
+                Exec::Next
+            }
+            // IRQ
+            0b110 => return Err(Unsupported("irq")),
+            // SET
+            _ => {
+                match arg1 {
+                    0b001 => self.x = arg2 as u32,
+                    0b010 => self.y = arg2 as u32,
+                    _ => return Err(Unsupported("SET destination")),
+                }
+                Exec::Next
+            }
+        };
+        Ok(exec)
+    }
+
+    fn shift_in(&mut self, data: u32, count: u8) {
+        let data = data & mask(count);
+        self.isr = if count == 32 {
+            data
+        } else if self.config.in_shift_left {
+            (self.isr << count) | data
+        } else {
+            (self.isr >> count) | (data << (32 - count))
+        };
+        self.isr_count = (self.isr_count + count).min(32);
+    }
+
+    fn shift_out(&mut self, count: u8) -> u32 {
+        let data;
+        if count == 32 {
+            data = self.osr;
+            self.osr = 0;
+        } else if self.config.out_shift_left {
+            data = self.osr >> (32 - count);
+            self.osr <<= count;
+        } else {
+            data = self.osr & mask(count);
+            self.osr >>= count;
+        }
+        self.osr_count = (self.osr_count + count).min(32);
+        data
+    }
+
+    fn push(&mut self) -> bool {
+        if self.rx_fifo.push_back(self.isr).is_err() {
+            return false;
+        }
+        self.isr = 0;
+        self.isr_count = 0;
+        true
+    }
+
+    fn pull(&mut self) -> bool {
+        match self.tx_fifo.pop_front() {
+            Some(v) => {
+                self.osr = v;
+                self.osr_count = 0;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Bit count field, where 0 means 32
+fn bit_count(field: u8) -> u8 {
+    if field == 0 {
+        32
+    } else {
+        field
+    }
+}
+
+fn mask(count: u8) -> u32 {
+    if count == 32 {
+        u32::MAX
+    } else {
+        (1 << count) - 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, StateMachine, Unsupported};
+    use pio_proc::pio_asm;
+
+    #[test]
+    fn test_loop_and_push() {
+        let p = pio_asm!(
+            "
+    set x, 3
+loop:
+    in x, 4
+    jmp x-- loop
+    push block
+"
+        );
+        let mut sm = StateMachine::new(&p.program, Config::default()).unwrap();
+        sm.run(0, 10).unwrap();
+        // Shifted right: the first value ends up in the low bits
+        assert_eq!(sm.rx_read(), Some(0x0123 << 16));
+        assert_eq!(sm.rx_read(), None);
+    }
+
+    #[test]
+    fn test_delay_and_wrap() {
+        let p = pio_asm!(
+            "
+    set y, 0 [3]
+.wrap_target
+    mov x, pins
+.wrap
+"
+        );
+        let mut sm = StateMachine::new(&p.program, Config::default()).unwrap();
+        sm.run(0b101, 4).unwrap();
+        assert_eq!(sm.pc(), 1);
+        assert_eq!(sm.x(), 0);
+        sm.step(0b101).unwrap();
+        assert_eq!(sm.x(), 0b101);
+        // Wrapped back to the same instruction
+        assert_eq!(sm.pc(), 1);
+        assert_eq!(sm.cycles(), 5);
+    }
+
+    #[test]
+    fn test_side_set() {
+        let p = pio_asm!(
+            "
+.side_set 1 opt
+    nop side 1 [1]
+    nop
+    nop side 0
+"
+        );
+        let mut sm = StateMachine::new(&p.program, Config::default()).unwrap();
+        sm.step(0).unwrap();
+        assert_eq!(sm.side_set_pins(), 1);
+        sm.run(0, 2).unwrap();
+        // No side-set on the second instruction, the pin keeps its value
+        assert_eq!(sm.side_set_pins(), 1);
+        sm.step(0).unwrap();
+        assert_eq!(sm.side_set_pins(), 0);
+    }
+
+    #[test]
+    fn test_push_noblock_overflow() {
+        let p = pio_asm!("push noblock");
+        let mut sm = StateMachine::new(&p.program, Config::default()).unwrap();
+        sm.run(0, 6).unwrap();
+        assert_eq!(sm.rx_overflows(), 2);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let p = pio_asm!("nop", "irq 0");
+        let mut sm = StateMachine::new(&p.program, Config::default()).unwrap();
+        sm.step(0).unwrap();
+        assert_eq!(sm.step(0), Err(Unsupported("irq")));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::button_event::ButtonEvent;
+
+/// Keeps track of which switches have worked during the wiring diagnostics.
+pub struct SwitchCheck {
+    expected: u8,
+    seen: u8,
+}
+
+impl SwitchCheck {
+    /// `expected` has a bit set for each switch that should be tested
+    pub const fn new(expected: u8) -> Self {
+        SwitchCheck { expected, seen: 0 }
+    }
+
+    /// Returns the expected switches pressed for the first time in the event
+    pub fn update(&mut self, ev: &ButtonEvent) -> u8 {
+        let new = ev.pressed() & self.expected & !self.seen;
+        self.seen |= new;
+        new
+    }
+
+    /// Switches that haven't been pressed yet
+    pub fn missing(&self) -> u8 {
+        self.expected & !self.seen
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SwitchCheck;
+    use crate::button_event::ButtonEvent;
+
+    fn ev(prev_state: u8, state: u8) -> ButtonEvent {
+        ButtonEvent {
+            time_us: 0,
+            state,
+            prev_state,
+        }
+    }
+
+    #[test]
+    fn test_switch_check() {
+        let mut c = SwitchCheck::new(0b111);
+        assert_eq!(c.update(&ev(0b000, 0b011)), 0b011);
+        assert_eq!(c.update(&ev(0b011, 0b000)), 0);
+        // Already seen, or not tested
+        assert_eq!(c.update(&ev(0b000, 0b1001)), 0);
+        assert_eq!(c.missing(), 0b100);
+        assert!(!c.is_complete());
+
+        assert_eq!(c.update(&ev(0b000, 0b100)), 0b100);
+        assert!(c.is_complete());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::amp_state::{AmpState, Bank, Selection};
+use defmt::Format;
+use katana_sysex::footswitch;
+
+/// Most steps in the setlist
+pub const MAX_SETLIST: usize = 16;
+/// Wait after a tap before trusting the LED status, as the amp reports the
+/// change in a later status reply
+pub const SETTLE_MS: u64 = 200;
+/// Tap again if no new LED status has arrived by then
+pub const RETRY_MS: u64 = 1000;
+/// Taps per step before giving up
+pub const MAX_TAPS: u8 = 12;
+
+/// An amp channel, with some effects switched on or off
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct SetlistStep {
+    /// Bank A channels 1-4 are 0-3, bank B 4-7
+    pub channel: u8,
+    /// Effects to switch, `led_status` effect mode bits
+    pub effects: u8,
+    /// Which of `effects` are on
+    pub effects_on: u8,
+}
+
+/// Amp channels to step through, e.g. one per song
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct Setlist {
+    pub steps: [SetlistStep; MAX_SETLIST],
+    pub len: u8,
+}
+
+impl Setlist {
+    pub fn steps(&self) -> &[SetlistStep] {
+        &self.steps[..usize::from(self.len)]
+    }
+
+    /// Adds a step, `false` if the setlist is full
+    pub fn push(&mut self, step: SetlistStep) -> bool {
+        let Some(s) = self.steps.get_mut(usize::from(self.len)) else {
+            return false;
+        };
+        *s = step;
+        self.len += 1;
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Progress {
+    /// Tap these GA-FC switches
+    Tap(u8),
+    /// The amp is on the step
+    Done,
+    /// The amp didn't get to the step in `MAX_TAPS` taps
+    Failed,
+}
+
+/// Takes the amp to setlist steps with GA-FC switch taps.
+///
+/// The LEDs show the channel in channel mode and the effects in effect
+/// mode. The channel is selected first, as that loads its effect settings,
+/// then the effects are set in effect mode, and then the amp is returned to
+/// channel mode. Each tap is decided from the amp state of the latest LED
+/// status, so a tap that didn't register is simply made again.
+#[derive(Default)]
+pub struct SetlistRunner {
+    /// Step of the setlist selected last
+    position: Option<u8>,
+    target: Option<SetlistStep>,
+    /// The channel of the target has been seen selected
+    channel_done: bool,
+    /// The effects of the target have been seen set
+    effects_done: bool,
+    taps: u8,
+    tapped_at_ms: Option<u64>,
+    /// The amp state of the latest LED status and when it was received
+    status: Option<(AmpState, u64)>,
+}
+
+impl SetlistRunner {
+    pub const fn new() -> Self {
+        SetlistRunner {
+            position: None,
+            target: None,
+            channel_done: false,
+            effects_done: false,
+            taps: 0,
+            tapped_at_ms: None,
+            status: None,
+        }
+    }
+
+    /// Step of the setlist selected last, 0-based
+    pub fn position(&self) -> Option<u8> {
+        self.position
+    }
+
+    /// Goes to the next step, the first one at first. Stays on the last step.
+    pub fn next(&mut self, setlist: &Setlist) {
+        let last = setlist.len.checked_sub(1);
+        let position = match (self.position, last) {
+            (_, None) => return,
+            (None, _) => 0,
+            (Some(p), Some(last)) => (p + 1).min(last),
+        };
+        self.select(setlist, position);
+    }
+
+    /// Goes to the previous step. Stays on the first step.
+    pub fn prev(&mut self, setlist: &Setlist) {
+        if setlist.len == 0 {
+            return;
+        }
+        let position = self.position.map_or(0, |p| p.saturating_sub(1));
+        self.select(setlist, position.min(setlist.len - 1));
+    }
+
+    fn select(&mut self, setlist: &Setlist, position: u8) {
+        self.position = Some(position);
+        self.target = setlist.steps().get(usize::from(position)).copied();
+        self.channel_done = false;
+        self.effects_done = false;
+        self.taps = 0;
+        self.tapped_at_ms = None;
+    }
+
+    /// Stops going to the step
+    pub fn abort(&mut self) {
+        self.target = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.target.is_some()
+    }
+
+    /// Updates the amp state after receiving an LED status
+    pub fn on_status(&mut self, amp: AmpState, now_ms: u64) {
+        self.status = Some((amp, now_ms));
+    }
+
+    pub fn poll(&mut self, now_ms: u64) -> Option<Progress> {
+        let target = self.target?;
+        let (amp, status_ms) = self.status?;
+        if let Some(tapped) = self.tapped_at_ms {
+            if status_ms < tapped + SETTLE_MS && now_ms < tapped + RETRY_MS {
+                return None;
+            }
+        }
+
+        let Some(tap) = self.next_tap(&target, &amp) else {
+            self.target = None;
+            return Some(Progress::Done);
+        };
+        if self.taps >= MAX_TAPS {
+            self.target = None;
+            return Some(Progress::Failed);
+        }
+        self.taps += 1;
+        self.tapped_at_ms = Some(now_ms);
+        Some(Progress::Tap(tap))
+    }
+
+    /// GA-FC switch to tap next, `None` when the amp is on the step
+    fn next_tap(&mut self, target: &SetlistStep, amp: &AmpState) -> Option<u8> {
+        if amp.effect_mode {
+            if self.channel_done && target.effects != 0 && !self.effects_done {
+                let effects = amp.effects.unwrap_or(0);
+                let wrong = (effects ^ target.effects_on) & target.effects;
+                if wrong != 0 {
+                    return Some(1 << wrong.trailing_zeros());
+                }
+                self.effects_done = true;
+            }
+            return Some(footswitch::EFFECT_MODE);
+        }
+
+        let bank = if target.channel >= 4 { Bank::B } else { Bank::A };
+        if amp.bank != Some(bank) {
+            return Some(footswitch::BANK);
+        }
+        let channel = target.channel % 4;
+        if amp.selection != Some(Selection::Channel(channel)) {
+            return Some(1 << channel);
+        }
+        self.channel_done = true;
+        if target.effects != 0 && !self.effects_done {
+            return Some(footswitch::EFFECT_MODE);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Progress, Setlist, SetlistRunner, SetlistStep, MAX_TAPS, RETRY_MS, SETTLE_MS};
+    use crate::amp_state::{AmpState, AmpTracker};
+    use katana_sysex::led_status::*;
+
+    /// Amp state of an LED status
+    fn state(status: u8) -> AmpState {
+        let mut t = AmpTracker::new();
+        t.on_led_status(status);
+        *t.state().unwrap()
+    }
+
+    fn setlist() -> Setlist {
+        let mut s = Setlist::default();
+        s.push(SetlistStep {
+            channel: 0,
+            effects: 0,
+            effects_on: 0,
+        });
+        // B2 with the boost on and the delay off
+        s.push(SetlistStep {
+            channel: 5,
+            effects: BOOST | DELAY,
+            effects_on: BOOST,
+        });
+        s
+    }
+
+    /// Amp state behind the LEDs
+    #[derive(Clone, Copy)]
+    struct Amp {
+        effect_mode: bool,
+        bank_b: bool,
+        /// Channel switch bit
+        channel: u8,
+        effects: u8,
+    }
+
+    impl Amp {
+        fn status(&self) -> u8 {
+            if self.effect_mode {
+                self.effects | EFFECT_MODE
+            } else if self.bank_b {
+                self.channel | BANK
+            } else {
+                self.channel
+            }
+        }
+
+        fn tap(&mut self, m: u8) {
+            match m {
+                0x20 => self.effect_mode = !self.effect_mode,
+                _ if self.effect_mode => self.effects ^= m,
+                0x10 => self.bank_b = !self.bank_b,
+                // Each channel has its own effects
+                ch => {
+                    self.channel = ch;
+                    self.effects = 0;
+                }
+            }
+        }
+    }
+
+    fn amp(channel: u8, effects: u8) -> Amp {
+        Amp {
+            effect_mode: false,
+            bank_b: false,
+            channel,
+            effects,
+        }
+    }
+
+    /// Taps until done, with the amp following the taps if `follow` is set
+    fn run(r: &mut SetlistRunner, amp: &mut Amp, follow: bool) -> Progress {
+        let mut now = 0;
+        loop {
+            r.on_status(state(amp.status()), now);
+            match r.poll(now) {
+                Some(Progress::Tap(m)) if follow => amp.tap(m),
+                Some(Progress::Tap(_)) | None => {}
+                Some(p) => return p,
+            }
+            now += SETTLE_MS;
+        }
+    }
+
+    #[test]
+    fn test_steps() {
+        let s = setlist();
+        let mut r = SetlistRunner::new();
+        r.prev(&s);
+        assert_eq!(r.position(), Some(0));
+        let mut a = Amp {
+            bank_b: true,
+            ..amp(CH3, 0)
+        };
+        assert_eq!(run(&mut r, &mut a, true), Progress::Done);
+        assert_eq!(a.status(), CH1);
+
+        r.next(&s);
+        assert_eq!(r.position(), Some(1));
+        // Starting in effect mode
+        a.effect_mode = true;
+        assert_eq!(run(&mut r, &mut a, true), Progress::Done);
+        assert_eq!(a.status(), CH2 | BANK);
+        assert_eq!(a.effects, BOOST);
+        // Stays on the last step
+        r.next(&s);
+        assert_eq!(r.position(), Some(1));
+    }
+
+    #[test]
+    fn test_tap_order() {
+        let s = setlist();
+        let mut r = SetlistRunner::new();
+        r.next(&s);
+        r.next(&s);
+        let mut a = amp(CH1, 0);
+        let mut taps: heapless::Vec<u8, 8> = heapless::Vec::new();
+        for now in 0..8 {
+            r.on_status(state(a.status()), now * SETTLE_MS);
+            match r.poll(now * SETTLE_MS) {
+                Some(Progress::Tap(m)) => {
+                    taps.push(m).unwrap();
+                    a.tap(m);
+                    // The new channel comes with the delay on
+                    if m == 0x02 {
+                        a.effects = DELAY;
+                    }
+                }
+                Some(Progress::Done) => break,
+                p => panic!("{:?}", p),
+            }
+        }
+        // Bank, channel 2, effect mode, boost, delay, channel mode
+        assert_eq!(&taps[..], &[0x10, 0x02, 0x20, 0x01, 0x08, 0x20]);
+    }
+
+    #[test]
+    fn test_retry() {
+        let s = setlist();
+        let mut r = SetlistRunner::new();
+        r.next(&s);
+        r.on_status(state(CH2), 0);
+        assert_eq!(r.poll(0), Some(Progress::Tap(0x01)));
+        // Waits for a newer status
+        assert_eq!(r.poll(RETRY_MS - 1), None);
+        r.on_status(state(CH2), 100);
+        assert_eq!(r.poll(150), None);
+        // The tap was missed, tap again
+        assert_eq!(r.poll(RETRY_MS), Some(Progress::Tap(0x01)));
+        r.on_status(state(CH1), RETRY_MS + SETTLE_MS);
+        assert_eq!(r.poll(RETRY_MS + SETTLE_MS), Some(Progress::Done));
+
+        // An amp that never follows
+        r.next(&s);
+        assert_eq!(run(&mut r, &mut amp(CH1, 0), false), Progress::Failed);
+        assert_eq!(r.taps, MAX_TAPS);
+    }
+}
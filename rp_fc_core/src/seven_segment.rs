@@ -0,0 +1,74 @@
+use crate::amp_state::{AmpState, Bank, Selection};
+
+// Segment bits: a-g in bits 0-6, decimal point in bit 7
+const DIGITS: [u8; 10] = [0x3f, 0x06, 0x5b, 0x4f, 0x66, 0x6d, 0x7d, 0x07, 0x7f, 0x6f];
+const LETTER_A: u8 = 0x77;
+const LETTER_B: u8 = 0x7c;
+const LETTER_E: u8 = 0x79;
+const LETTER_F: u8 = 0x71;
+const LETTER_P: u8 = 0x73;
+const DASH: u8 = 0x40;
+
+/// Segments for a decimal digit
+pub fn digit(n: u8) -> u8 {
+    DIGITS[(n % 10) as usize]
+}
+
+/// Segments of a two-digit display for the amp's state: the bank and the
+/// channel ("A1" - "b4"), "AP" / "bP" for the panel setting, "EF" in effect
+/// mode and "--" before the first status has been received.
+pub fn status_digits(state: Option<&AmpState>) -> [u8; 2] {
+    let Some(state) = state else {
+        return [DASH, DASH];
+    };
+    if state.effect_mode {
+        return [LETTER_E, LETTER_F];
+    }
+
+    let bank = match state.bank {
+        Some(Bank::A) => LETTER_A,
+        Some(Bank::B) => LETTER_B,
+        None => DASH,
+    };
+    match state.selection {
+        Some(Selection::Panel) => [bank, LETTER_P],
+        Some(Selection::Channel(ch)) => [bank, digit(ch + 1)],
+        None => [bank, DASH],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{digit, status_digits, DASH, LETTER_A, LETTER_B, LETTER_E, LETTER_F, LETTER_P};
+    use crate::amp_state::AmpTracker;
+    use katana_sysex::led_status;
+
+    #[test]
+    fn test_status_digits() {
+        let digits = |frames: &[u8]| {
+            let mut t = AmpTracker::new();
+            for &f in frames {
+                t.on_led_status(f);
+            }
+            status_digits(t.state())
+        };
+        assert_eq!(digits(&[]), [DASH, DASH]);
+        assert_eq!(digits(&[led_status::CH1]), [LETTER_A, digit(1)]);
+        assert_eq!(
+            digits(&[led_status::CH4 | led_status::BANK]),
+            [LETTER_B, digit(4)]
+        );
+        assert_eq!(digits(&[led_status::BANK]), [LETTER_B, LETTER_P]);
+        assert_eq!(
+            digits(&[led_status::EFFECT_MODE | led_status::BOOST]),
+            [LETTER_E, LETTER_F]
+        );
+    }
+
+    #[test]
+    fn test_digits() {
+        // "8" lights all the segments, "1" only b and c
+        assert_eq!(digit(8), 0x7f);
+        assert_eq!(digit(1), 0b0000_0110);
+    }
+}
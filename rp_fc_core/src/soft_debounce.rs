@@ -0,0 +1,155 @@
+use embedded_hal::digital::InputPin;
+
+/// Debouncer for active-low buttons that doesn't need a PIO block.
+///
+/// Works like the PIO debounce program: every edge on the pins restarts the
+/// debounce period, and the pins are read once they have been quiet for the
+/// whole period. The caller calls [`SoftDebouncer::on_edge`] from the GPIO
+/// interrupt and [`SoftDebouncer::poll`] when the returned deadline has
+/// passed. Times are in microseconds.
+pub struct SoftDebouncer<P, const N: usize> {
+    pins: [P; N],
+    debounce_time_us: u64,
+    deadline: Option<u64>,
+    state: u8,
+}
+
+impl<P: InputPin, const N: usize> SoftDebouncer<P, N> {
+    pub fn new(pins: [P; N], debounce_time_us: u64) -> Self {
+        assert!(N <= 8, "Button state is an u8");
+        let mut debouncer = SoftDebouncer {
+            pins,
+            debounce_time_us,
+            deadline: None,
+            state: 0,
+        };
+        debouncer.state = debouncer.read();
+        debouncer
+    }
+
+    /// Debounced button state, bit N is set when button N is pressed
+    pub fn state(&self) -> u8 {
+        self.state
+    }
+
+    /// Time when `poll` should be called, if a debounce period is running
+    pub fn deadline(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    pub fn pins_mut(&mut self) -> &mut [P; N] {
+        &mut self.pins
+    }
+
+    /// Starts or restarts the debounce period after an edge on any of the
+    /// pins. Returns the new deadline.
+    pub fn on_edge(&mut self, now_us: u64) -> u64 {
+        let deadline = now_us + self.debounce_time_us;
+        self.deadline = Some(deadline);
+        deadline
+    }
+
+    /// Reads the pins if the debounce period has ended. Returns the new
+    /// state if it changed.
+    pub fn poll(&mut self, now_us: u64) -> Option<u8> {
+        match self.deadline {
+            Some(deadline) if now_us >= deadline => {
+                self.deadline = None;
+                let state = self.read();
+                if state != self.state {
+                    self.state = state;
+                    Some(state)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        self.pins
+            .iter_mut()
+            .enumerate()
+            .fold(0, |acc, (i, p)| {
+                if p.is_low().unwrap_or(false) {
+                    acc | (1 << i)
+                } else {
+                    acc
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SoftDebouncer;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+    use embedded_hal::digital::{ErrorType, InputPin};
+
+    struct FakePin<'a>(&'a Cell<bool>);
+
+    impl ErrorType for FakePin<'_> {
+        type Error = Infallible;
+    }
+
+    impl InputPin for FakePin<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0.get())
+        }
+    }
+
+    const DEBOUNCE_US: u64 = 5_000;
+
+    #[test]
+    fn test_initial_state() {
+        let levels = [Cell::new(true), Cell::new(false)];
+        let d = SoftDebouncer::new([FakePin(&levels[0]), FakePin(&levels[1])], DEBOUNCE_US);
+        assert_eq!(d.state(), 0b10);
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn test_press_after_debounce_time() {
+        let levels = [Cell::new(true), Cell::new(true)];
+        let mut d = SoftDebouncer::new([FakePin(&levels[0]), FakePin(&levels[1])], DEBOUNCE_US);
+
+        levels[1].set(false);
+        assert_eq!(d.on_edge(1_000), 6_000);
+        assert_eq!(d.poll(5_999), None);
+        assert_eq!(d.poll(6_000), Some(0b10));
+        assert_eq!(d.deadline(), None);
+        assert_eq!(d.poll(20_000), None);
+    }
+
+    #[test]
+    fn test_bounce_restarts_period() {
+        let level = Cell::new(true);
+        let mut d = SoftDebouncer::new([FakePin(&level)], DEBOUNCE_US);
+
+        for (t, l) in [(0, false), (300, true), (700, false), (1_500, true), (2_000, false)] {
+            level.set(l);
+            d.on_edge(t);
+        }
+        assert_eq!(d.poll(5_000), None);
+        assert_eq!(d.poll(7_000), Some(0b1));
+    }
+
+    #[test]
+    fn test_glitch_ignored() {
+        let level = Cell::new(true);
+        let mut d = SoftDebouncer::new([FakePin(&level)], DEBOUNCE_US);
+
+        level.set(false);
+        d.on_edge(0);
+        level.set(true);
+        d.on_edge(100);
+        assert_eq!(d.poll(5_100), None);
+        assert_eq!(d.state(), 0);
+    }
+}
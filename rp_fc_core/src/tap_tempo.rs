@@ -0,0 +1,107 @@
+/// A longer pause between taps starts a new tempo (30 BPM)
+pub const MAX_INTERVAL_US: u64 = 2_000_000;
+/// Closer taps are ignored as a double tap (300 BPM)
+pub const MIN_INTERVAL_US: u64 = 200_000;
+/// Intervals averaged for the tempo
+const AVERAGED: usize = 4;
+/// An interval this many percent off the average is a tempo change, and
+/// the average starts over from it
+const CHANGE_PERCENT: u64 = 25;
+
+/// Measures a tempo from switch taps.
+///
+/// The tap times are the button event timestamps, taken when the switch
+/// changed state, so the main loop's delays don't affect the tempo.
+#[derive(Default)]
+pub struct TapTempo {
+    last_tap_us: Option<u64>,
+    /// The latest intervals
+    intervals: heapless::Deque<u64, AVERAGED>,
+}
+
+impl TapTempo {
+    pub const fn new() -> Self {
+        TapTempo {
+            last_tap_us: None,
+            intervals: heapless::Deque::new(),
+        }
+    }
+
+    /// Adds a tap, and returns the new tempo interval once there are two
+    /// taps close enough. `None` for an ignored double tap too.
+    pub fn tap(&mut self, time_us: u64) -> Option<u64> {
+        let interval = self.last_tap_us.map(|t| time_us.saturating_sub(t));
+        match interval {
+            Some(i) if i < MIN_INTERVAL_US => return None,
+            Some(i) if i <= MAX_INTERVAL_US => {
+                if let Some(avg) = self.interval_us() {
+                    if avg.abs_diff(i) * 100 > avg * CHANGE_PERCENT {
+                        self.intervals.clear();
+                    }
+                }
+                if self.intervals.is_full() {
+                    self.intervals.pop_front();
+                }
+                _ = self.intervals.push_back(i);
+            }
+            _ => self.intervals.clear(),
+        }
+        self.last_tap_us = Some(time_us);
+        self.interval_us()
+    }
+
+    /// The average tap interval, if there's a tempo
+    pub fn interval_us(&self) -> Option<u64> {
+        let n = self.intervals.len() as u64;
+        (n > 0).then(|| self.intervals.iter().sum::<u64>() / n)
+    }
+
+    /// Time of the latest tap, the tempo's beats are counted from it
+    pub fn last_tap_us(&self) -> Option<u64> {
+        self.last_tap_us
+    }
+
+    /// The tempo in beats per minute
+    pub fn bpm(&self) -> Option<u16> {
+        self.interval_us().map(|i| ((60_000_000 + i / 2) / i) as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TapTempo, MAX_INTERVAL_US};
+
+    #[test]
+    fn test_average() {
+        let mut t = TapTempo::new();
+        assert_eq!(t.tap(1_000_000), None);
+        assert_eq!(t.tap(1_500_000), Some(500_000));
+        assert_eq!(t.bpm(), Some(120));
+        assert_eq!(t.tap(2_010_000), Some(505_000));
+        assert_eq!(t.tap(2_500_000), Some(500_000));
+        // Only the latest four intervals count
+        t.tap(3_020_000);
+        assert_eq!(t.tap(3_520_000), Some(505_000));
+        assert_eq!(t.last_tap_us(), Some(3_520_000));
+    }
+
+    #[test]
+    fn test_tempo_change() {
+        let mut t = TapTempo::new();
+        for i in 0..4 {
+            t.tap(i * 500_000);
+        }
+        // A bounce or a double tap is ignored
+        assert_eq!(t.tap(1_550_000), None);
+        assert_eq!(t.interval_us(), Some(500_000));
+        // A much slower tap starts a new average
+        assert_eq!(t.tap(2_500_000), Some(1_000_000));
+        assert_eq!(t.bpm(), Some(60));
+
+        // And a long pause starts over
+        let last = 2_500_000 + MAX_INTERVAL_US + 1;
+        assert_eq!(t.tap(last), None);
+        assert_eq!(t.bpm(), None);
+        assert_eq!(t.tap(last + 400_000), Some(400_000));
+    }
+}
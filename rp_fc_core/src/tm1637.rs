@@ -0,0 +1,243 @@
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+
+const CMD_DATA_AUTO_INCREMENT: u8 = 0x40;
+const CMD_ADDRESS: u8 = 0xc0;
+const CMD_DISPLAY_ON: u8 = 0x88;
+
+/// Half of a clock period, for about 100 kHz
+const HALF_BIT_US: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The chip didn't acknowledge a byte
+    Nack,
+    Pin,
+}
+
+/// Bit-banged driver for TM1637 LED display drivers.
+///
+/// The protocol is I2C-like but without addresses, and bytes are sent least
+/// significant bit first. Both lines need pull-ups (most modules have them);
+/// DIO must work like an open-drain pin, so that the chip can pull it low to
+/// acknowledge.
+pub struct Tm1637<CLK, DIO, D> {
+    clk: CLK,
+    dio: DIO,
+    delay: D,
+    brightness: u8,
+}
+
+impl<CLK: OutputPin, DIO: OutputPin + InputPin, D: DelayNs> Tm1637<CLK, DIO, D> {
+    pub fn new(clk: CLK, dio: DIO, delay: D) -> Self {
+        Tm1637 {
+            clk,
+            dio,
+            delay,
+            brightness: 7,
+        }
+    }
+
+    /// Sets the brightness (0-7) used from the next write on
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness.min(7);
+    }
+
+    /// Writes the segments of the digits, starting from the leftmost one,
+    /// and turns the display on
+    pub fn write(&mut self, segments: &[u8]) -> Result<(), Error> {
+        self.transfer([CMD_DATA_AUTO_INCREMENT])?;
+        self.transfer(core::iter::once(CMD_ADDRESS).chain(segments.iter().copied()))?;
+        self.transfer([CMD_DISPLAY_ON | self.brightness])
+    }
+
+    /// Sends bytes between a start and a stop condition. The stop is sent
+    /// even if a byte isn't acknowledged, to leave the bus idle.
+    fn transfer(&mut self, bytes: impl IntoIterator<Item = u8>) -> Result<(), Error> {
+        self.start()?;
+        let res = bytes.into_iter().try_for_each(|b| self.write_byte(b));
+        self.stop()?;
+        res
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        self.dio.set_high().map_err(|_| Error::Pin)?;
+        self.clk.set_high().map_err(|_| Error::Pin)?;
+        self.wait();
+        self.dio.set_low().map_err(|_| Error::Pin)?;
+        self.wait();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        self.clk.set_low().map_err(|_| Error::Pin)?;
+        self.dio.set_low().map_err(|_| Error::Pin)?;
+        self.wait();
+        self.clk.set_high().map_err(|_| Error::Pin)?;
+        self.wait();
+        self.dio.set_high().map_err(|_| Error::Pin)?;
+        self.wait();
+        Ok(())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
+        for i in 0..8 {
+            self.clk.set_low().map_err(|_| Error::Pin)?;
+            if byte & (1 << i) != 0 {
+                self.dio.set_high().map_err(|_| Error::Pin)?;
+            } else {
+                self.dio.set_low().map_err(|_| Error::Pin)?;
+            }
+            self.wait();
+            self.clk.set_high().map_err(|_| Error::Pin)?;
+            self.wait();
+        }
+
+        // Release DIO for the acknowledge bit
+        self.clk.set_low().map_err(|_| Error::Pin)?;
+        self.dio.set_high().map_err(|_| Error::Pin)?;
+        self.wait();
+        self.clk.set_high().map_err(|_| Error::Pin)?;
+        self.wait();
+        let ack = self.dio.is_low().map_err(|_| Error::Pin)?;
+        self.clk.set_low().map_err(|_| Error::Pin)?;
+
+        if ack {
+            Ok(())
+        } else {
+            Err(Error::Nack)
+        }
+    }
+
+    fn wait(&mut self) {
+        self.delay.delay_us(HALF_BIT_US);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Tm1637};
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use embedded_hal::delay::DelayNs;
+    use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+    use heapless::Vec;
+
+    /// Decodes the bus traffic like a TM1637 would
+    #[derive(Default)]
+    struct Bus {
+        clk: bool,
+        dio: bool,
+        ack: bool,
+        bits: u16,
+        bit_count: u8,
+        /// Bytes of each transfer between a start and a stop
+        transfers: Vec<Vec<u8, 8>, 4>,
+    }
+
+    impl Bus {
+        fn set_clk(&mut self, level: bool) {
+            if level && !self.clk {
+                // Data bits and the acknowledge bit
+                if self.bit_count < 8 {
+                    self.bits |= (self.dio as u16) << self.bit_count;
+                }
+                self.bit_count += 1;
+                if self.bit_count == 9 {
+                    let t = self.transfers.last_mut().unwrap();
+                    t.push(self.bits as u8).unwrap();
+                    self.bits = 0;
+                    self.bit_count = 0;
+                }
+            }
+            self.clk = level;
+        }
+
+        fn set_dio(&mut self, level: bool) {
+            if self.clk && self.dio && !level {
+                // Start condition
+                self.transfers.push(Vec::new()).unwrap();
+                self.bits = 0;
+                self.bit_count = 0;
+            }
+            self.dio = level;
+        }
+    }
+
+    struct Clk<'a>(&'a RefCell<Bus>);
+    struct Dio<'a>(&'a RefCell<Bus>);
+    struct NoDelay;
+
+    impl ErrorType for Clk<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Clk<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().set_clk(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().set_clk(true);
+            Ok(())
+        }
+    }
+
+    impl ErrorType for Dio<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Dio<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().set_dio(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().set_dio(true);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Dio<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            self.is_low().map(|l| !l)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            let bus = self.0.borrow();
+            Ok(!bus.dio || bus.ack)
+        }
+    }
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    #[test]
+    fn test_write() {
+        let bus = RefCell::new(Bus {
+            ack: true,
+            ..Default::default()
+        });
+        let mut tm = Tm1637::new(Clk(&bus), Dio(&bus), NoDelay);
+        tm.set_brightness(3);
+        tm.write(&[0x77, 0x06]).unwrap();
+
+        let bus = bus.borrow();
+        assert_eq!(bus.transfers.len(), 3);
+        assert_eq!(&bus.transfers[0][..], &[0x40]);
+        assert_eq!(&bus.transfers[1][..], &[0xc0, 0x77, 0x06]);
+        assert_eq!(&bus.transfers[2][..], &[0x8b]);
+        // Stopped, with both lines released
+        assert!(bus.clk && bus.dio);
+    }
+
+    #[test]
+    fn test_nack() {
+        let bus = RefCell::new(Bus::default());
+        let mut tm = Tm1637::new(Clk(&bus), Dio(&bus), NoDelay);
+        assert_eq!(tm.write(&[0]), Err(Error::Nack));
+    }
+}
@@ -0,0 +1,50 @@
+/// Tuner mode, toggled by the tuner action.
+///
+/// No GA-FC message for the Katana's tuner is known, so entering and
+/// leaving the mode taps a GA-FC switch combination from the settings, if
+/// one is set. The amp doesn't report its tuner state either, so the mode
+/// doesn't follow the tuner being turned off on the amp itself.
+#[derive(Default)]
+pub struct Tuner {
+    on: bool,
+}
+
+impl Tuner {
+    pub const fn new() -> Self {
+        Tuner { on: false }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// Turns the mode on or off. Returns the footswitch states to send: a
+    /// tap of `footswitches` on top of the held `buttons`, or nothing
+    /// without a combination.
+    pub fn toggle(&mut self, footswitches: u8, buttons: u8) -> heapless::Vec<u8, 2> {
+        self.on = !self.on;
+        let mut states = heapless::Vec::new();
+        if footswitches != 0 {
+            _ = states.push(buttons | footswitches);
+            _ = states.push(buttons);
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Tuner;
+
+    #[test]
+    fn test_toggle() {
+        let mut t = Tuner::new();
+        assert!(t.toggle(0, 0x01).is_empty());
+        assert!(t.is_on());
+        assert!(t.toggle(0, 0).is_empty());
+        assert!(!t.is_on());
+
+        assert_eq!(&t.toggle(0x30, 0x01)[..], &[0x31, 0x01]);
+        assert!(t.is_on());
+    }
+}
@@ -0,0 +1,248 @@
+use defmt::Format;
+use katana_sysex::led_status;
+use pio::Program;
+use pio_proc::pio_asm;
+
+pub const BIT_RATE_HZ: u32 = 800_000;
+
+/// The PIO program for driving a chain of WS2812 LEDs, from the Pico SDK
+/// examples.
+///
+/// Each bit takes `cycles_per_bit` state machine cycles, with the data line
+/// high for 2 cycles for a 0 bit and for 7 cycles for a 1 bit. The state
+/// machine needs the data pin as the side-set pin, autopull with a threshold
+/// of 24 and left shifting OSR, so that each TX FIFO word is one LED (see
+/// [`Rgb::grb_word`]). The line stays low while the FIFO is empty, which
+/// latches the colours once it has been low for over 50 µs.
+pub struct Ws2812PioProgram {
+    pub program: Program<32>,
+    pub cycles_per_bit: u8,
+}
+
+impl Ws2812PioProgram {
+    /// Clock divisor for the state machine to get the given bit rate, as
+    /// the integer part and 1/256 fractions
+    pub fn clock_divisor(&self, sys_freq_hz: u32, bit_rate_hz: u32) -> (u16, u8) {
+        let div_256 = (sys_freq_hz as u64 * 256) / (bit_rate_hz as u64 * self.cycles_per_bit as u64);
+        ((div_256 >> 8).min(u16::MAX as u64) as u16, div_256 as u8)
+    }
+}
+
+pub fn program() -> Ws2812PioProgram {
+    let p = pio_asm!(
+        "
+.side_set 1
+.define public T1 2
+.define public T2 5
+.define public T3 3
+
+.wrap_target
+bitloop:
+    out x, 1       side 0 [T3 - 1] // Stalls here with the line low when out of data
+    jmp !x do_zero side 1 [T1 - 1]
+do_one:
+    jmp bitloop    side 1 [T2 - 1] // Continue driving high for a long pulse
+do_zero:
+    nop            side 0 [T2 - 1] // Or drive low for a short pulse
+.wrap
+",
+    );
+
+    let defines = p.public_defines;
+    Ws2812PioProgram {
+        program: p.program,
+        cycles_per_bit: (defines.T1 + defines.T2 + defines.T3).try_into().unwrap(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    /// The colour scaled by a brightness (0-255)
+    pub fn dimmed(self, brightness: u8) -> Rgb {
+        let scale = |c: u8| ((c as u16 * brightness as u16) / 255) as u8;
+        Rgb::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+
+    /// TX FIFO word for the WS2812 program: green, red and blue, most
+    /// significant bit first, in the upper 24 bits
+    pub fn grb_word(self) -> u32 {
+        (self.g as u32) << 24 | (self.r as u32) << 16 | (self.b as u32) << 8
+    }
+}
+
+/// Colours for showing the amp's LED status on RGB LEDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    /// Selected channel and the bank LED in bank A
+    pub bank_a: Rgb,
+    /// Selected channel and the bank LED in bank B
+    pub bank_b: Rgb,
+    /// Enabled effects in effect mode: boost, mod, fx, delay and reverb
+    pub effects: [Rgb; 5],
+    /// The effect mode LED
+    pub effect_mode: Rgb,
+    /// Unlit LEDs, e.g. a dim glow to show where the switches are in the dark
+    pub off: Rgb,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            bank_a: Rgb::new(0, 255, 0),
+            bank_b: Rgb::new(255, 0, 0),
+            effects: [
+                Rgb::new(255, 0, 0),
+                Rgb::new(0, 64, 255),
+                Rgb::new(160, 0, 255),
+                Rgb::new(0, 255, 0),
+                Rgb::new(0, 255, 160),
+            ],
+            effect_mode: Rgb::new(255, 96, 0),
+            off: Rgb::OFF,
+        }
+    }
+}
+
+impl Palette {
+    /// Colours of the six switch LEDs for an LED status byte from the amp.
+    ///
+    /// In channel mode the channel LEDs and the bank LED are lit in the bank
+    /// colour; in effect mode the first five LEDs show the effects in their
+    /// own colours.
+    pub fn colours(&self, status: u8) -> [Rgb; 6] {
+        let lit = |bit: u8, colour: Rgb| if status & bit != 0 { colour } else { self.off };
+
+        let mut colours = [self.off; 6];
+        if status & led_status::EFFECT_MODE != 0 {
+            for (i, colour) in self.effects.iter().enumerate() {
+                colours[i] = lit(1 << i, *colour);
+            }
+            colours[5] = self.effect_mode;
+        } else {
+            let bank = if status & led_status::BANK != 0 {
+                self.bank_b
+            } else {
+                self.bank_a
+            };
+            for (i, colour) in colours[..4].iter_mut().enumerate() {
+                *colour = lit(1 << i, bank);
+            }
+            colours[4] = bank;
+        }
+        colours
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{program, Palette, Rgb, BIT_RATE_HZ};
+    use crate::pio_sim::{Config, StateMachine};
+    use katana_sysex::led_status;
+
+    fn ws2812_sm() -> StateMachine {
+        let config = Config {
+            out_shift_left: true,
+            autopull: true,
+            pull_threshold: 24,
+            ..Default::default()
+        };
+        StateMachine::new(&program().program, config).unwrap()
+    }
+
+    /// Runs the state machine and returns the lengths of the high pulses
+    fn high_pulses(sm: &mut StateMachine, cycles: u64) -> heapless::Vec<u64, 64> {
+        let mut pulses = heapless::Vec::new();
+        let mut high_cycles = 0;
+        for _ in 0..cycles {
+            sm.step(0).unwrap();
+            if sm.side_set_pins() & 1 != 0 {
+                high_cycles += 1;
+            } else if high_cycles > 0 {
+                pulses.push(high_cycles).unwrap();
+                high_cycles = 0;
+            }
+        }
+        pulses
+    }
+
+    #[test]
+    fn test_bit_timing() {
+        let mut sm = ws2812_sm();
+        let colour = Rgb::new(0x0f, 0x80, 0x01);
+        assert!(sm.tx_write(colour.grb_word()));
+
+        let pulses = high_pulses(&mut sm, 300);
+        assert_eq!(pulses.len(), 24);
+        let bits = pulses.iter().fold(0u32, |acc, p| match p {
+            2 => acc << 1,
+            7 => acc << 1 | 1,
+            _ => panic!("Unexpected pulse length {}", p),
+        });
+        assert_eq!(bits, 0x800f01);
+    }
+
+    #[test]
+    fn test_bit_period_and_idle_low() {
+        let mut sm = ws2812_sm();
+        sm.run(0, 20).unwrap();
+        assert_eq!(sm.side_set_pins(), 0);
+
+        assert!(sm.tx_write(Rgb::OFF.grb_word()));
+        let start = sm.cycles();
+        let pulses = high_pulses(&mut sm, 1000);
+        assert_eq!(pulses.len(), 24);
+        // Stalled waiting for more data, with the line low
+        assert_eq!(sm.side_set_pins(), 0);
+        assert_eq!(sm.pc(), 0);
+
+        let cycles_per_bit = program().cycles_per_bit as u64;
+        assert_eq!(cycles_per_bit, 10);
+        assert!(sm.cycles() - start >= 24 * cycles_per_bit);
+    }
+
+    #[test]
+    fn test_clock_divisor() {
+        // 125 MHz / 8 MHz = 15.625
+        assert_eq!(program().clock_divisor(125_000_000, BIT_RATE_HZ), (15, 160));
+    }
+
+    #[test]
+    fn test_dimmed() {
+        assert_eq!(Rgb::new(255, 128, 0).dimmed(128), Rgb::new(128, 64, 0));
+    }
+
+    #[test]
+    fn test_channel_mode_colours() {
+        let p = Palette::default();
+        let c = p.colours(led_status::CH2);
+        assert_eq!(c, [Rgb::OFF, p.bank_a, Rgb::OFF, Rgb::OFF, p.bank_a, Rgb::OFF]);
+
+        let c = p.colours(led_status::CH4 | led_status::BANK);
+        assert_eq!(c, [Rgb::OFF, Rgb::OFF, Rgb::OFF, p.bank_b, p.bank_b, Rgb::OFF]);
+    }
+
+    #[test]
+    fn test_effect_mode_colours() {
+        let p = Palette {
+            off: Rgb::new(1, 1, 1),
+            ..Default::default()
+        };
+        let c = p.colours(led_status::EFFECT_MODE | led_status::BOOST | led_status::REVERB);
+        assert_eq!(
+            c,
+            [p.effects[0], p.off, p.off, p.off, p.effects[4], p.effect_mode]
+        );
+    }
+}